@@ -1,58 +1,57 @@
 use super::catalog;
 use super::config_entry;
 use super::health;
-use lazy_static::lazy_static;
+use crate::api::{hostname, AuditMeta};
+use crate::duration::GoDuration;
 use serde_derive::{Deserialize, Serialize};
 use serde_json::Value;
 use std::collections::HashMap;
+use std::time::Duration;
 
-/// ServiceKind is the kind of service being registered.
-type ServiceKind = String;
-
-lazy_static! {
-    /// SERVICE_KIND_TYPICAL is a typical, classic Consul service. This is
-    /// represented by the absence of a value. This was chosen for ease of
-    /// backwards compatibility: existing services in the catalog would
-    /// default to the typical service.
-    pub static ref SERVICE_KIND_TYPICAL: ServiceKind = {
-        String::new()
-    };
-    /// SERVICE_KIND_CONNECT_PROXY is a proxy for the Connect feature. This
-    /// service proxies another service within Consul and speaks the connect
-    /// protocol.
-    pub static ref SERVICE_KIND_CONNECT_PROXY: ServiceKind = {
-        String::from("connect-proxy")
-    };
-
-    /// SERVICE_KIND_MESH_GATEWAY is a Mesh Gateway for the Connect feature. This
-    /// service will proxy connections based off the SNI header set by other
-    /// connect proxies
-    pub static ref SERVICE_KIND_MESH_GATEWAY: ServiceKind = {
-        String::from("mesh-gateway")
-    };
-
-    /// SERVICE_KIND_TERMINATING_GATEWAY is a Terminating Gateway for the Connect
-    /// feature. This service will proxy connections to services outside the mesh.
-    pub static ref SERVICE_KIND_TERMINATING_GATEWAY: ServiceKind = {
-        String::from("terminating-gateway")
-    };
+string_enum! {
+    /// ServiceKind is the kind of service being registered.
+    ServiceKind, default = Typical {
+        /// SERVICE_KIND_TYPICAL is a typical, classic Consul service. This is
+        /// represented by the absence of a value. This was chosen for ease of
+        /// backwards compatibility: existing services in the catalog would
+        /// default to the typical service.
+        Typical => "",
+        /// SERVICE_KIND_CONNECT_PROXY is a proxy for the Connect feature. This
+        /// service proxies another service within Consul and speaks the connect
+        /// protocol.
+        ConnectProxy => "connect-proxy",
+        /// SERVICE_KIND_MESH_GATEWAY is a Mesh Gateway for the Connect feature. This
+        /// service will proxy connections based off the SNI header set by other
+        /// connect proxies
+        MeshGateway => "mesh-gateway",
+        /// SERVICE_KIND_TERMINATING_GATEWAY is a Terminating Gateway for the Connect
+        /// feature. This service will proxy connections to services outside the mesh.
+        TerminatingGateway => "terminating-gateway",
+        /// SERVICE_KIND_INGRESS_GATEWAY is an Ingress Gateway for the Connect feature.
+        /// This service allows external traffic into the Connect mesh.
+        IngressGateway => "ingress-gateway",
+    }
 }
 
-/// UpstreamDestType is the type of upstream discovery mechanism.
-type UpstreamDestType = String;
+pub const SERVICE_KIND_TYPICAL: ServiceKind = ServiceKind::Typical;
+pub const SERVICE_KIND_CONNECT_PROXY: ServiceKind = ServiceKind::ConnectProxy;
+pub const SERVICE_KIND_MESH_GATEWAY: ServiceKind = ServiceKind::MeshGateway;
+pub const SERVICE_KIND_TERMINATING_GATEWAY: ServiceKind = ServiceKind::TerminatingGateway;
+pub const SERVICE_KIND_INGRESS_GATEWAY: ServiceKind = ServiceKind::IngressGateway;
 
-lazy_static! {
-    /// UpstreamDestTypeService discovers instances via healthy service lookup.
-    pub static ref UPSTREAM_DEST_TYPE_SERVICE: UpstreamDestType = {
-        String::from("service")
-    };
-
-    /// UpstreamDestTypePreparedQuery discovers instances via prepared query execution.
-    pub static ref UPSTREAM_DEST_TYPE_PREPARED_QUERY: UpstreamDestType = {
-        String::from("prepared_query")
-    };
+string_enum! {
+    /// UpstreamDestType is the type of upstream discovery mechanism.
+    UpstreamDestType, default = Service {
+        /// UpstreamDestTypeService discovers instances via healthy service lookup.
+        Service => "service",
+        /// UpstreamDestTypePreparedQuery discovers instances via prepared query execution.
+        PreparedQuery => "prepared_query",
+    }
 }
 
+pub const UPSTREAM_DEST_TYPE_SERVICE: UpstreamDestType = UpstreamDestType::Service;
+pub const UPSTREAM_DEST_TYPE_PREPARED_QUERY: UpstreamDestType = UpstreamDestType::PreparedQuery;
+
 /// AgentCheck represents a check known to the api
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
 #[allow(non_snake_case)]
@@ -67,7 +66,7 @@ pub struct AgentCheck {
     pub ServiceName: Option<String>,
     pub Type: Option<String>,
     pub Definition: Option<health::HealthCheckDefinition>,
-    pub Namespace: String,
+    pub Namespace: Option<String>,
 }
 
 /// Filter
@@ -166,6 +165,11 @@ pub struct AgentServiceRegistration {
     // pub Namespace: Option<String>,
 }
 
+/// TAGGED_ADDRESS_LAN is the tag of the LAN address of a service.
+pub const TAGGED_ADDRESS_LAN: &str = "lan";
+/// TAGGED_ADDRESS_WAN is the tag of the WAN address of a service.
+pub const TAGGED_ADDRESS_WAN: &str = "wan";
+
 /// ServiceRegisterOpts is used to pass extra options to the service register.
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
 #[allow(non_snake_case)]
@@ -175,6 +179,78 @@ pub struct ServiceRegisterOpts {
     ///having to manually deregister checks.
     #[serde(rename = "replace-existing-checks")]
     pub ReplaceExistingChecks: bool,
+
+    /// Audit is recorded by the audit log along with the registration.
+    #[serde(skip)]
+    pub Audit: Option<AuditMeta>,
+
+    /// VerifyBeforeRegister checks that the service is reachable before it is
+    /// registered: an HTTP GET of its HTTP check, or else a TCP connect to its
+    /// TCP check or Address:Port. An unreachable service fails with
+    /// ConsulError::Unreachable.
+    #[serde(skip)]
+    pub VerifyBeforeRegister: bool,
+
+    /// DefaultID sets a missing ID to AgentServiceRegistration::default_id with
+    /// the local hostname, instead of letting Consul default it to the Name,
+    /// on which every instance would overwrite the previous one.
+    #[serde(skip)]
+    pub DefaultID: bool,
+
+    /// VerifyRetryWindow keeps retrying the reachability check for up to this
+    /// long, for a service that is still starting up.
+    #[serde(skip)]
+    pub VerifyRetryWindow: Option<Duration>,
+
+    /// RejectNameConflict fails the registration with ConsulError::Conflict
+    /// when the agent already has a service of the same Name under a
+    /// different ID, for singleton services. Re-registering the same ID is
+    /// an update and is allowed.
+    #[serde(skip)]
+    pub RejectNameConflict: bool,
+}
+
+impl AgentServiceRegistration {
+    /// default_id returns `{Name}-{hostname}-{Port}` (or `{Name}-{hostname}`
+    /// without a Port), which stays the same across restarts while telling
+    /// apart the instances on different hosts or ports.
+    pub fn default_id(&self, hostname: &str) -> Option<String> {
+        let name = self.Name.as_ref()?;
+        match self.Port {
+            Some(port) => Some(format!("{}-{}-{}", name, hostname, port)),
+            None => Some(format!("{}-{}", name, hostname)),
+        }
+    }
+
+    /// effective_id returns the ID the agent registers the service under with
+    /// `opts`: its ID, else its default_id with DefaultID, else its Name.
+    pub fn effective_id(&self, opts: &ServiceRegisterOpts) -> Option<String> {
+        self.ID
+            .clone()
+            .or_else(|| {
+                if opts.DefaultID {
+                    self.default_id(&hostname())
+                } else {
+                    None
+                }
+            })
+            .or_else(|| self.Name.clone())
+    }
+
+    /// tagged_address sets the address the service is reachable on under
+    /// `tag`, e.g. TAGGED_ADDRESS_LAN or TAGGED_ADDRESS_WAN.
+    pub fn tagged_address(&mut self, tag: &str, address: &str, port: usize) -> &mut Self {
+        self.TaggedAddresses
+            .get_or_insert_with(HashMap::new)
+            .insert(
+                tag.to_string(),
+                catalog::ServiceAddress {
+                    Address: Some(address.to_string()),
+                    Port: Some(port),
+                },
+            );
+        self
+    }
 }
 
 /// Upstream is the response structure for a proxy upstream configuration.
@@ -191,6 +267,66 @@ pub struct Upstream {
     pub MeshGateway: Option<config_entry::MeshGatewayConfig>,
     pub CentrallyConfigured: Option<bool>,
 }
+
+/// ConnectProxyConfig is the response structure for agent-local proxy
+/// configuration.
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[allow(non_snake_case)]
+pub struct ConnectProxyConfig {
+    pub ProxyServiceID: Option<String>,
+    pub TargetServiceID: Option<String>,
+    pub TargetServiceName: Option<String>,
+    pub ContentHash: Option<String>,
+    pub Config: Option<HashMap<String, Value>>,
+    pub Upstreams: Option<Vec<Upstream>>,
+}
+
+/// UpstreamBuilder builds an Upstream that discovers its destination as a
+/// service, with an empty proxy config.
+#[derive(Debug, Clone)]
+pub struct UpstreamBuilder {
+    upstream: Upstream,
+}
+
+impl UpstreamBuilder {
+    pub fn new() -> Self {
+        let upstream = Upstream {
+            DestinationType: Some(UPSTREAM_DEST_TYPE_SERVICE),
+            ..Upstream::default()
+        };
+        UpstreamBuilder { upstream }
+    }
+
+    pub fn destination_name(mut self, destination_name: &str) -> Self {
+        self.upstream.DestinationName = Some(destination_name.to_string());
+        self
+    }
+
+    pub fn datacenter(mut self, datacenter: &str) -> Self {
+        self.upstream.Datacenter = Some(datacenter.to_string());
+        self
+    }
+
+    pub fn local_bind_port(mut self, local_bind_port: usize) -> Self {
+        self.upstream.LocalBindPort = Some(local_bind_port);
+        self
+    }
+
+    pub fn mesh_gateway_mode(mut self, mode: config_entry::MeshGatewayMode) -> Self {
+        self.upstream.MeshGateway = Some(config_entry::MeshGatewayConfig { Mode: Some(mode) });
+        self
+    }
+
+    pub fn build(self) -> Upstream {
+        self.upstream
+    }
+}
+
+impl Default for UpstreamBuilder {
+    fn default() -> Self {
+        UpstreamBuilder::new()
+    }
+}
 //
 type AgentServiceChecks = Vec<AgentServiceCheck>;
 
@@ -204,14 +340,17 @@ pub struct AgentServiceCheck {
     pub DockerContainerID: Option<String>,
     /// Only supported for Docker.
     pub Shell: Option<String>,
-    pub Interval: Option<String>,
-    pub Timeout: Option<String>,
-    pub TTL: Option<String>,
+    pub Interval: Option<GoDuration>,
+    pub Timeout: Option<GoDuration>,
+    pub TTL: Option<GoDuration>,
     pub HTTP: Option<String>,
-    pub Header: Option<HashMap<String, String>>,
+    /// Header is sent with the HTTP check. A header may have several values.
+    pub Header: Option<HashMap<String, Vec<String>>>,
     pub Method: Option<String>,
     pub Body: Option<String>,
     pub TCP: Option<String>,
+    /// Status is the initial status of the check, `critical` if unset. A TTL
+    /// check registered as `passing` isn't unhealthy until its first update.
     pub Status: Option<String>,
     pub Notes: Option<String>,
     pub TLSServerName: Option<String>,
@@ -229,9 +368,127 @@ pub struct AgentServiceCheck {
     /// a check is in the critical state for more than this configured value,
     /// then its associated service (and all of its associated checks) will
     /// automatically be deregistered.
-    pub DeregisterCriticalServiceAfter: Option<String>,
+    pub DeregisterCriticalServiceAfter: Option<GoDuration>,
+}
+
+impl AgentServiceCheck {
+    /// ttl returns a TTL check that turns critical unless it is updated
+    /// within `ttl`.
+    pub fn ttl(ttl: Duration) -> Self {
+        AgentServiceCheck {
+            TTL: Some(GoDuration(ttl)),
+            ..AgentServiceCheck::default()
+        }
+    }
+
+    /// deregister_critical_service_after deregisters the service once the
+    /// check has been critical for longer than `after`.
+    pub fn deregister_critical_service_after(mut self, after: Duration) -> Self {
+        self.DeregisterCriticalServiceAfter = Some(GoDuration(after));
+        self
+    }
+
+    /// header adds `value` to the HTTP check header `name`, keeping any
+    /// value already set.
+    pub fn header(mut self, name: &str, value: &str) -> Self {
+        self.Header
+            .get_or_insert_with(HashMap::new)
+            .entry(name.to_string())
+            .or_default()
+            .push(value.to_string());
+        self
+    }
+
+    /// initial_status sets the status the check is registered with, e.g.
+    /// `passing` for a TTL check that shouldn't start critical.
+    pub fn initial_status(mut self, status: &str) -> Self {
+        self.Status = Some(status.to_string());
+        self
+    }
+}
+
+/// AgentConfig is the effective configuration of the local agent, as
+/// resolved from its config files and defaults.
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[allow(non_snake_case)]
+pub struct AgentConfig {
+    pub Datacenter: Option<String>,
+    pub PrimaryDatacenter: Option<String>,
+    pub NodeName: Option<String>,
+    pub NodeID: Option<String>,
+    /// Server is set when the agent runs in server mode.
+    pub Server: Option<bool>,
+    pub Version: Option<String>,
+    pub Revision: Option<String>,
+    #[serde(default)]
+    pub Ports: AgentPorts,
+}
+
+/// AgentPorts are the ports the agent listens on. A port is negative when
+/// the listener is disabled.
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[allow(non_snake_case)]
+pub struct AgentPorts {
+    #[serde(rename = "HTTPPort")]
+    pub HTTP: Option<i64>,
+    #[serde(rename = "HTTPSPort")]
+    pub HTTPS: Option<i64>,
+    #[serde(rename = "DNSPort")]
+    pub DNS: Option<i64>,
+    #[serde(rename = "GRPCPort")]
+    pub GRPC: Option<i64>,
+    #[serde(rename = "SerfPortLAN")]
+    pub SerfLAN: Option<i64>,
+    #[serde(rename = "SerfPortWAN")]
+    pub SerfWAN: Option<i64>,
+    #[serde(rename = "ServerPort")]
+    pub Server: Option<i64>,
 }
 
+/// AgentSelf is the part of `/v1/agent/self` read into an AgentConfig.
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[allow(non_snake_case)]
+pub struct AgentSelf {
+    pub Config: Option<AgentConfig>,
+    pub DebugConfig: Option<AgentPorts>,
+}
+
+impl AgentSelf {
+    /// agent_config returns the Config with the Ports of the DebugConfig.
+    pub fn agent_config(self) -> AgentConfig {
+        let mut config = self.Config.unwrap_or_default();
+        if let Some(ports) = self.DebugConfig {
+            config.Ports = ports;
+        }
+        config
+    }
+}
+
+/// AgentMember represents a cluster member known to the agent
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[allow(non_snake_case)]
+pub struct AgentMember {
+    pub Name: Option<String>,
+    pub Addr: Option<String>,
+    pub Port: Option<u16>,
+    pub Tags: Option<HashMap<String, String>>,
+    /// Status of the Member which corresponds to the serf member status:
+    /// one of the MEMBER_STATUS_* constants.
+    pub Status: Option<i64>,
+    pub ProtocolMin: Option<u8>,
+    pub ProtocolMax: Option<u8>,
+    pub ProtocolCur: Option<u8>,
+    pub DelegateMin: Option<u8>,
+    pub DelegateMax: Option<u8>,
+    pub DelegateCur: Option<u8>,
+}
+
+pub const MEMBER_STATUS_NONE: i64 = 0;
+pub const MEMBER_STATUS_ALIVE: i64 = 1;
+pub const MEMBER_STATUS_LEAVING: i64 = 2;
+pub const MEMBER_STATUS_LEFT: i64 = 3;
+pub const MEMBER_STATUS_FAILED: i64 = 4;
+
 /// Metrics info is used to store different types of metric values from the agent.
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
 #[allow(non_snake_case)]
@@ -275,3 +532,156 @@ pub struct SampledValue {
     pub Stddev: Option<f64>,
     pub Labels: Option<HashMap<String, String>>,
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::agent::{
+        AgentServiceCheck, AgentServiceRegistration, ServiceKind, UpstreamBuilder,
+        UpstreamDestType, TAGGED_ADDRESS_LAN, TAGGED_ADDRESS_WAN,
+    };
+    use crate::config_entry::MeshGatewayMode;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_deregister_critical_service_after() {
+        use std::time::Duration;
+        let check = AgentServiceCheck::ttl(Duration::from_secs(15))
+            .deregister_critical_service_after(Duration::from_secs(90));
+        let json = serde_json::to_value(&check).unwrap();
+        assert_eq!(json["DeregisterCriticalServiceAfter"], "1m30s");
+        let err = serde_json::from_str::<AgentServiceCheck>(
+            r#"{"TTL":"15s","DeregisterCriticalServiceAfter":"90"}"#,
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("invalid duration"));
+    }
+
+    #[test]
+    fn test_ttl_check_initial_status() {
+        let mut service = AgentServiceRegistration::default();
+        service.Check = Some(
+            AgentServiceCheck::ttl(std::time::Duration::from_secs(15)).initial_status("passing"),
+        );
+        let json = serde_json::to_value(&service).unwrap();
+        assert_eq!(json["Check"]["TTL"], "15s");
+        assert_eq!(json["Check"]["Status"], "passing");
+    }
+
+    #[test]
+    fn test_http_check_header_serde() {
+        let mut header = HashMap::new();
+        header.insert(
+            String::from("Accept"),
+            vec![String::from("application/json"), String::from("text/plain")],
+        );
+        let check = AgentServiceCheck {
+            HTTP: Some(String::from("https://10.0.0.1:8443/health")),
+            Method: Some(String::from("POST")),
+            Body: Some(String::from("{\"probe\":true}")),
+            Header: Some(header),
+            ..AgentServiceCheck::default()
+        };
+        let json = serde_json::to_value(&check).unwrap();
+        assert_eq!(
+            json["Header"],
+            serde_json::json!({"Accept": ["application/json", "text/plain"]})
+        );
+    }
+
+    #[test]
+    fn test_http_check_header_round_trip() {
+        let documented = r#"{
+            "CheckID": "api",
+            "Name": "HTTP API on port 5000",
+            "HTTP": "https://example.com",
+            "TLSSkipVerify": true,
+            "Method": "POST",
+            "Header": {"Content-Type": ["application/json"]},
+            "Body": "{\"method\":\"health\"}",
+            "Interval": "10s",
+            "Timeout": "5s"
+        }"#;
+        let check: AgentServiceCheck = serde_json::from_str(documented).unwrap();
+        let mut json = serde_json::to_value(&check).unwrap();
+        json.as_object_mut()
+            .unwrap()
+            .retain(|_, value| !value.is_null());
+        let documented: serde_json::Value = serde_json::from_str(documented).unwrap();
+        assert_eq!(json, documented);
+
+        let built = AgentServiceCheck::default()
+            .header("Content-Type", "application/json")
+            .header("Accept", "application/json")
+            .header("Accept", "text/plain");
+        let header = built.Header.unwrap();
+        assert_eq!(header["Content-Type"], vec!["application/json"]);
+        assert_eq!(header["Accept"], vec!["application/json", "text/plain"]);
+    }
+
+    #[test]
+    fn test_tagged_addresses_serde() {
+        let mut service = AgentServiceRegistration::default();
+        service
+            .tagged_address(TAGGED_ADDRESS_LAN, "10.0.0.1", 8080)
+            .tagged_address(TAGGED_ADDRESS_WAN, "198.51.100.1", 443);
+        let json = serde_json::to_value(&service).unwrap();
+        assert_eq!(
+            json["TaggedAddresses"],
+            serde_json::json!({
+                "lan": {"Address": "10.0.0.1", "Port": 8080},
+                "wan": {"Address": "198.51.100.1", "Port": 443},
+            })
+        );
+    }
+
+    #[test]
+    fn test_service_kind_serde() {
+        let kinds = vec![
+            (ServiceKind::Typical, "\"\""),
+            (ServiceKind::ConnectProxy, "\"connect-proxy\""),
+            (ServiceKind::MeshGateway, "\"mesh-gateway\""),
+            (ServiceKind::TerminatingGateway, "\"terminating-gateway\""),
+            (ServiceKind::IngressGateway, "\"ingress-gateway\""),
+            (
+                ServiceKind::Unknown(String::from("api-gateway")),
+                "\"api-gateway\"",
+            ),
+        ];
+        for (kind, json) in kinds {
+            assert_eq!(serde_json::to_string(&kind).unwrap(), json);
+            assert_eq!(serde_json::from_str::<ServiceKind>(json).unwrap(), kind);
+        }
+    }
+
+    #[test]
+    fn test_upstream_dest_type_serde() {
+        let types = vec![
+            (UpstreamDestType::Service, "\"service\""),
+            (UpstreamDestType::PreparedQuery, "\"prepared_query\""),
+        ];
+        for (dest_type, json) in types {
+            assert_eq!(serde_json::to_string(&dest_type).unwrap(), json);
+            assert_eq!(
+                serde_json::from_str::<UpstreamDestType>(json).unwrap(),
+                dest_type
+            );
+        }
+    }
+
+    #[test]
+    fn test_upstream_builder() {
+        let upstream = UpstreamBuilder::new()
+            .destination_name("db")
+            .datacenter("dc2")
+            .local_bind_port(9191)
+            .mesh_gateway_mode(MeshGatewayMode::Local)
+            .build();
+        assert_eq!(upstream.DestinationType, Some(UpstreamDestType::Service));
+        assert_eq!(upstream.DestinationName.as_deref(), Some("db"));
+        assert_eq!(upstream.LocalBindPort, Some(9191));
+        assert!(upstream.Config.is_empty());
+        let value = serde_json::to_value(&upstream).unwrap();
+        assert_eq!(value["DestinationType"], "service");
+        assert_eq!(value["MeshGateway"]["Mode"], "local");
+    }
+}
@@ -1,13 +1,46 @@
-use super::agent::{AgentServiceRegistration, ServiceRegisterOpts};
-use super::health::{ServiceAddress, ServiceEntry};
-use super::watch::WatchService;
+use super::agent::{
+    AgentCheck, AgentConfig, AgentMember, AgentSelf, AgentService, AgentServiceRegistration,
+    ConnectProxyConfig, ServiceRegisterOpts,
+};
+use super::catalog::{CatalogRegistration, CatalogService, UiService};
+use super::circuit_breaker::{CircuitBreaker, CircuitBreakerConfig, CircuitState};
+use super::config_entry::{
+    ExportedServices, ProxyDefaults, ServiceDefaults, EXPORTED_SERVICES,
+    EXPORTED_SERVICES_DEFAULT_PARTITION, PROXY_CONFIG_GLOBAL, PROXY_DEFAULTS, SERVICE_DEFAULTS,
+};
+use super::connect::CAConfig;
+use super::duration::{parse_go_duration, GoDuration};
+use super::error::ConsulError;
+use super::health::{
+    CatalogServiceChecksInfo, HealthCheck, HealthChecks, HealthStatus, InstanceHealth,
+    ResolvedAddress, ServiceAddress, ServiceEntry, SrvRecord,
+};
+use super::kv::KVPair;
+use super::operator::{KeyringRequest, KeyringResponse, Usage};
+use super::peering::{
+    Peering, PeeringEstablishRequest, PeeringGenerateTokenRequest, PeeringGenerateTokenResponse,
+};
+use super::prepared_query::PreparedQueryExecuteResponse;
+use super::session::{SessionCreateResponse, SessionEntry};
+use super::watch::{WatchService, WatchStatus};
 use async_std::fs::read_to_string;
-use async_std::sync::{Arc, RwLock};
+use async_std::io::prelude::{BufReadExt, ReadExt};
+use async_std::stream::{Stream, StreamExt};
+use async_std::sync::{Arc, Mutex, RwLock};
+use async_std::{task, task_local};
+use http_client::isahc::IsahcClient;
+use isahc::config::{
+    CaCertificate, ClientCertificate, Configurable, PrivateKey, SslOption, VersionNegotiation,
+};
 use lazy_static::lazy_static;
 use rand::Rng;
 use serde_derive::{Deserialize, Serialize};
 use serde_yaml;
+use std::cell::RefCell;
 use std::collections::{HashMap, LinkedList};
+use std::convert::TryInto;
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::time;
 use surf;
 use surf::http::Method;
@@ -25,6 +58,167 @@ lazy_static! {
         let hash_map = RwLock::new(hash_map);
         Arc::new(hash_map)
     };
+    /// CIRCUIT_BREAKERS holds the circuit breaker of each resolved service.
+    pub static ref CIRCUIT_BREAKERS: Arc<RwLock<HashMap<String, CircuitBreaker>>> = {
+        Arc::new(RwLock::new(HashMap::new()))
+    };
+    /// STREAMING_UNSUPPORTED is set once the agent turned out not to serve
+    /// blocking queries from its streaming backend.
+    static ref STREAMING_UNSUPPORTED: AtomicBool = AtomicBool::new(false);
+    /// STARTUP_JITTER_UNTIL is when the startup jitter ends, picked by the
+    /// first caller.
+    static ref STARTUP_JITTER_UNTIL: Mutex<Option<time::Instant>> = Mutex::new(None);
+    /// BACKGROUND_TASKS holds the tasks spawned by this crate, stopped by shutdown.
+    /// It is shared by every client of the process.
+    static ref BACKGROUND_TASKS: Arc<Mutex<Vec<task::JoinHandle<()>>>> = {
+        Arc::new(Mutex::new(vec![]))
+    };
+    /// WATCH_POLLS holds when each watched service was last polled successfully.
+    static ref WATCH_POLLS: Arc<RwLock<HashMap<String, time::SystemTime>>> = {
+        Arc::new(RwLock::new(HashMap::new()))
+    };
+    /// GUARDED_SERVICES holds the IDs of the services deregistered by shutdown.
+    /// It is shared by every client of the process.
+    static ref GUARDED_SERVICES: Arc<Mutex<Vec<String>>> = {
+        Arc::new(Mutex::new(vec![]))
+    };
+    /// HTTP_CLIENTS holds the surf client shared by the requests of each
+    /// client configuration, so that connections (and HTTP/2 streams) are
+    /// reused instead of reopened.
+    static ref HTTP_CLIENTS: Arc<RwLock<HashMap<ClientKey, surf::Client>>> = {
+        Arc::new(RwLock::new(HashMap::new()))
+    };
+}
+
+/// ClientKey keys HTTP_CLIENTS on the `address`, `http2` and `tls_config` of a config.
+type ClientKey = (Option<String>, bool, Option<TLSConfig>);
+
+task_local! {
+    /// REQUEST_ID is the correlation id sent with the requests of the current task.
+    static REQUEST_ID: RefCell<Option<String>> = RefCell::new(None);
+}
+
+/// with_request_id runs `fut` with `request_id` attached to every request it
+/// makes, under the `request_id_header` name from the Config.
+pub async fn with_request_id<F: Future>(request_id: &str, fut: F) -> F::Output {
+    scoped(&REQUEST_ID, Some(request_id.to_string()), fut).await
+}
+
+/// scoped returns `fut` with the task-local `key` set to `value` while `fut`
+/// is polled. The previous value is restored after each poll, also when `fut`
+/// panics, so the value doesn't outlive a dropped `fut` and the futures joined
+/// in one task each see their own. Outside an async-std task `fut` runs
+/// without it.
+fn scoped<T: Send + Unpin, F: Future>(
+    key: &'static task::LocalKey<RefCell<T>>,
+    value: T,
+    fut: F,
+) -> Scoped<T, F> {
+    Scoped {
+        key,
+        value: Some(value),
+        fut: Box::pin(fut),
+    }
+}
+
+/// Scoped is the future returned by scoped.
+struct Scoped<T: Send + 'static, F> {
+    key: &'static task::LocalKey<RefCell<T>>,
+    value: Option<T>,
+    fut: std::pin::Pin<Box<F>>,
+}
+
+impl<T: Send + Unpin, F: Future> Future for Scoped<T, F> {
+    type Output = F::Output;
+
+    fn poll(self: std::pin::Pin<&mut Self>, cx: &mut task::Context<'_>) -> task::Poll<F::Output> {
+        let this = self.get_mut();
+        let mut guard = ScopeGuard {
+            key: this.key,
+            value: &mut this.value,
+        };
+        guard.swap();
+        this.fut.as_mut().poll(cx)
+    }
+}
+
+/// ScopeGuard swaps the value of a Scoped with its task-local, and swaps them
+/// back when dropped.
+struct ScopeGuard<'a, T: Send + 'static> {
+    key: &'static task::LocalKey<RefCell<T>>,
+    value: &'a mut Option<T>,
+}
+
+impl<'a, T: Send> ScopeGuard<'a, T> {
+    fn swap(&mut self) {
+        let value = &mut *self.value;
+        let _ = self.key.try_with(|current| {
+            if let Some(inner) = value.take() {
+                *value = Some(current.replace(inner));
+            }
+        });
+    }
+}
+
+impl<'a, T: Send> Drop for ScopeGuard<'a, T> {
+    fn drop(&mut self) {
+        self.swap();
+    }
+}
+
+task_local! {
+    /// EXTRA_HEADERS are the headers sent with the requests of the current task.
+    static EXTRA_HEADERS: RefCell<HashMap<String, String>> = RefCell::new(HashMap::new());
+}
+
+/// with_headers runs `fut` with `headers` added to every request it makes,
+/// overriding the Config's extra_headers of the same name.
+pub async fn with_headers<F: Future>(headers: HashMap<String, String>, fut: F) -> F::Output {
+    scoped(&EXTRA_HEADERS, headers, fut).await
+}
+
+task_local! {
+    /// DEADLINE is the deadline of the requests of the current task.
+    static DEADLINE: RefCell<Option<time::Instant>> = RefCell::new(None);
+}
+
+/// with_deadline runs `fut` with every request it makes bounded by
+/// `deadline`, like a Go context deadline shared by a chain of calls. A
+/// request still running at the deadline, or made after it, fails with
+/// ConsulError::Timeout, and the wait of a blocking query is shortened so it
+/// returns before the deadline. A nested deadline can only be earlier. The
+/// tasks spawned by fan-outs such as heartbeat_all inherit it.
+pub async fn with_deadline<F: Future>(deadline: time::Instant, fut: F) -> F::Output {
+    let earliest = DEADLINE
+        .try_with(|current| {
+            current
+                .borrow()
+                .map_or(deadline, |current| current.min(deadline))
+        })
+        .unwrap_or(deadline);
+    scoped(&DEADLINE, Some(earliest), fut).await
+}
+
+/// spawn_scoped spawns `fut` with the request id, headers and deadline of the
+/// current task, which task::spawn doesn't carry over, so the requests of a
+/// concurrent fan-out are bound like those of the caller.
+fn spawn_scoped<F>(fut: F) -> task::JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    let request_id = REQUEST_ID
+        .try_with(|request_id| request_id.borrow().clone())
+        .unwrap_or_default();
+    let headers = EXTRA_HEADERS
+        .try_with(|headers| headers.borrow().clone())
+        .unwrap_or_default();
+    let deadline = DEADLINE
+        .try_with(|deadline| *deadline.borrow())
+        .unwrap_or_default();
+    let fut = scoped(&DEADLINE, deadline, fut);
+    let fut = scoped(&EXTRA_HEADERS, headers, fut);
+    task::spawn(scoped(&REQUEST_ID, request_id, fut))
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -37,7 +231,6 @@ impl Default for ConsulConfig {
     fn default() -> Self {
         let mut config = Config::default();
         config.address = Some(String::from("http://127.0.0.1:8500"));
-        config.datacenter = Some(String::from("dc1"));
         ConsulConfig {
             config: Some(config),
             watch_services: None,
@@ -69,41 +262,229 @@ impl ConsulConfig {
             .address
             .as_ref()
             .expect("consul config address is empty");
-        let url = format!("{}{}", address, path);
-        let uri = surf::Url::parse(&url)?;
+        let mut uri = surf::Url::parse(&join_url(address, path))?;
+        {
+            let mut query = uri.query_pairs_mut();
+            if let Some(datacenter) = config.datacenter.as_ref() {
+                query.append_pair("dc", datacenter);
+            }
+            if let Some(namespace) = config.namespace.as_ref() {
+                query.append_pair("ns", namespace);
+            }
+        }
+        if uri.query() == Some("") {
+            uri.set_query(None);
+        }
         let mut req = surf::Request::new(method, uri);
-        req.set_header("Connection", "close");
-        let mut body: HashMap<String, String> = HashMap::new();
+        if let Some(token) = config.token.as_ref() {
+            req.set_header("X-Consul-Token", token.as_str());
+        }
+        if let Some(extra_headers) = config.extra_headers.as_ref() {
+            for (name, value) in extra_headers.iter() {
+                req.set_header(name.as_str(), value.as_str());
+            }
+        }
+        let _ = EXTRA_HEADERS.try_with(|extra| {
+            for (name, value) in extra.borrow().iter() {
+                req.set_header(name.as_str(), value.as_str());
+            }
+        });
+        if let Some(header) = config.request_id_header.as_ref() {
+            let request_id = REQUEST_ID.try_with(|id| id.borrow().clone());
+            if let Ok(Some(request_id)) = request_id {
+                req.set_header(header.as_str(), request_id.as_str());
+            }
+        }
+        Ok(req)
+    }
 
-        if config.datacenter.is_some() {
-            body.insert(
-                String::from("dc"),
-                String::from(config.datacenter.as_ref().unwrap()),
-            );
-        };
-        if config.namespace.is_some() {
-            body.insert(
-                String::from("ns"),
-                String::from(config.namespace.as_ref().unwrap()),
-            );
-        };
+    /// client returns the surf client shared by the configs with the same
+    /// address, `http2` and `tls_config`, building it on first use.
+    pub async fn client(&self) -> surf::Result<surf::Client> {
+        let key = self.client_key();
+        {
+            let clients = HTTP_CLIENTS.read().await;
+            if let Some(client) = clients.get(&key) {
+                return Ok(client.clone());
+            }
+        }
+        let mut clients = HTTP_CLIENTS.write().await;
+        if let Some(client) = clients.get(&key) {
+            return Ok(client.clone());
+        }
+        let client = self.build_client()?;
+        clients.insert(key, client.clone());
+        Ok(client)
+    }
 
-        if config.wait_time.is_some() {
-            let wait = config.wait_time.as_ref().unwrap().to_string();
-            body.insert(String::from("wait"), wait);
+    /// client_key returns the settings the client of this config is built
+    /// from.
+    fn client_key(&self) -> ClientKey {
+        let config = self.config.as_ref();
+        (
+            config.and_then(|config| config.address.clone()),
+            config.and_then(|config| config.http2).unwrap_or(false),
+            self.tls_config().cloned(),
+        )
+    }
+
+    /// build_client creates a surf client backed by the curl (isahc) client.
+    /// When `http2` is enabled, HTTP/2 is offered via ALPN on TLS connections
+    /// and the client falls back to HTTP/1.1 if the server doesn't negotiate it.
+    /// Otherwise the client is pinned to HTTP/1.1. The `cert_file`, `key_file`,
+    /// `ca_file` and `insecure_skip_verify` of the `tls_config` are applied.
+    fn build_client(&self) -> surf::Result<surf::Client> {
+        let http2 = self
+            .config
+            .as_ref()
+            .and_then(|config| config.http2)
+            .unwrap_or(false);
+        let version = if http2 {
+            VersionNegotiation::latest_compatible()
         } else {
-            body.insert(String::from("wait"), String::from("5s"));
+            VersionNegotiation::http11()
+        };
+        let mut builder = isahc::HttpClient::builder().version_negotiation(version);
+        if let Some(tls) = self.tls_config() {
+            if let (Some(cert), Some(key)) = (tls.cert_file.as_ref(), tls.key_file.as_ref()) {
+                builder = builder.ssl_client_certificate(ClientCertificate::pem_file(
+                    cert,
+                    PrivateKey::pem_file(key, None),
+                ));
+            }
+            if let Some(ca) = tls.ca_file.as_ref() {
+                builder = builder.ssl_ca_certificate(CaCertificate::file(ca));
+            }
+            if tls.insecure_skip_verify == Some(true) {
+                builder = builder.ssl_options(
+                    SslOption::DANGER_ACCEPT_INVALID_CERTS | SslOption::DANGER_ACCEPT_INVALID_HOSTS,
+                );
+            }
         }
+        let client = builder.build()?;
+        let client: surf::Client = surf::Config::new()
+            .set_http_client(IsahcClient::from_client(client))
+            .try_into()?;
+        Ok(client)
+    }
 
-        if config.token.is_some() {
-            body.insert(
-                "X-Consul-Token".to_string(),
-                String::from(config.token.as_ref().unwrap()),
-            );
+    fn tls_config(&self) -> Option<&TLSConfig> {
+        self.config
+            .as_ref()
+            .and_then(|config| config.tls_config.as_ref())
+    }
+
+    /// reload_tls re-reads the certificate, key and CA files of the
+    /// `tls_config` and swaps in a new client using them for this config.
+    /// The new client is first checked with a request to
+    /// `/v1/status/leader`. Requests in flight finish on the previous
+    /// client. If a file can't be read or the check fails, the current client
+    /// is kept and the error returned.
+    pub async fn reload_tls(&self) -> surf::Result<()> {
+        if let Some(tls) = self.tls_config() {
+            let files = vec![&tls.cert_file, &tls.key_file, &tls.ca_file];
+            for file in files.into_iter().flatten() {
+                read_to_string(file).await.map_err(|err| {
+                    Error::from_str(StatusCode::BadRequest, format!("{}: {}", file, err))
+                })?;
+            }
+        }
+        let client = self.build_client()?;
+        let req = self.new_request(Method::Get, "/v1/status/leader").await?;
+        client.send(req).await?;
+        HTTP_CLIENTS.write().await.insert(self.client_key(), client);
+        Ok(())
+    }
+
+    /// send sends the request with the shared client. Non-2xx responses are
+    /// turned into an error carrying a ConsulError parsed from the body.
+    /// A rate-limited (429) GET is retried up to `rate_limit_retries` times,
+    /// after the delay from its `Retry-After` header. A delay longer than
+    /// RECONNECT_MAX_BACKOFF isn't waited out: the request fails with
+    /// ConsulError::RateLimited straight away. With `max_response_size`
+    /// set, the body is buffered and a larger one fails with
+    /// ConsulError::ResponseTooLarge.
+    pub async fn send(&self, mut req: surf::Request) -> surf::Result<surf::Response> {
+        let deadline = DEADLINE.try_with(|deadline| *deadline.borrow());
+        let remaining = match deadline {
+            Ok(Some(deadline)) => deadline.saturating_duration_since(time::Instant::now()),
+            // boxed to keep the futures of the callers small.
+            _ => return Box::pin(self.send_limited(req)).await,
         };
+        let timeout = || Error::new(StatusCode::RequestTimeout, ConsulError::Timeout);
+        if remaining.is_zero() {
+            return Err(timeout());
+        }
+        shorten_wait(&mut req, remaining);
+        async_std::future::timeout(remaining, Box::pin(self.send_limited(req)))
+            .await
+            .unwrap_or_else(|_| Err(timeout()))
+    }
 
-        req.body_json(&body)?;
-        Ok(req)
+    /// send_limited is send without a deadline.
+    async fn send_limited(&self, req: surf::Request) -> surf::Result<surf::Response> {
+        let res = self.send_stream(req).await?;
+        match self
+            .config
+            .as_ref()
+            .and_then(|config| config.max_response_size)
+        {
+            Some(limit) => limit_body(res, limit).await,
+            None => Ok(res),
+        }
+    }
+
+    /// send_stream is send without the `max_response_size` limit, for the
+    /// responses read incrementally.
+    async fn send_stream(&self, req: surf::Request) -> surf::Result<surf::Response> {
+        let client = self.client().await?;
+        let mut retries = 0;
+        if req.method() == Method::Get {
+            retries = self
+                .config
+                .as_ref()
+                .and_then(|config| config.rate_limit_retries)
+                .unwrap_or(DEFAULT_RATE_LIMIT_RETRIES);
+        }
+        let mut req = Some(req);
+        loop {
+            // a clone drops the body, which only the retried GETs can afford.
+            let attempt = if retries > 0 {
+                req.clone().unwrap()
+            } else {
+                req.take().unwrap()
+            };
+            let mut res = client.send(attempt).await?;
+            let status = res.status();
+            if status.is_success() {
+                return Ok(res);
+            }
+            if status == StatusCode::TooManyRequests {
+                let retry_after = res
+                    .header("Retry-After")
+                    .and_then(|value| value.as_str().trim().parse::<u64>().ok());
+                let delay = retry_after
+                    .map(time::Duration::from_secs)
+                    .unwrap_or(RECONNECT_MIN_BACKOFF);
+                if retries == 0 || delay > RECONNECT_MAX_BACKOFF {
+                    return Err(Error::new(status, ConsulError::RateLimited { retry_after }));
+                }
+                retries -= 1;
+                log::warn!("consul rate limited, retrying in {:?}", delay);
+                task::sleep(delay).await;
+                continue;
+            }
+            let limit = self
+                .config
+                .as_ref()
+                .and_then(|config| config.max_response_size)
+                .unwrap_or(MAX_ERROR_BODY_SIZE);
+            let body = error_body(&mut res, limit).await;
+            return Err(Error::new(
+                status,
+                ConsulError::from_response(status, &body),
+            ));
+        }
     }
 
     /// service_register is used to register a new service with
@@ -147,21 +528,166 @@ impl ConsulConfig {
         opts: &ServiceRegisterOpts,
     ) -> surf::Result<StatusCode> {
         if self.config.is_some() {
+            self.startup_jitter().await;
+            if opts.VerifyBeforeRegister {
+                let window = opts.VerifyRetryWindow.unwrap_or_default();
+                self.verify_reachable(service, window).await?;
+            }
             let mut req = self
                 .new_request(Method::Put, "/v1/agent/service/register")
                 .await?;
-            if opts.ReplaceExistingChecks == true {
-                req.set_query(&opts)?;
+            if opts.ReplaceExistingChecks {
+                let mut query: HashMap<&str, String> = HashMap::new();
+                query.insert("replace-existing-checks", String::from("true"));
+                merge_query(&mut req, &query);
             };
+            if let Some(audit) = opts.Audit.as_ref() {
+                set_audit_meta(&mut req, audit);
+            }
+            let mut service = service.clone();
+            if opts.DefaultID && service.ID.is_none() {
+                service.ID = service.default_id(&hostname());
+            }
+            if opts.RejectNameConflict {
+                self.check_name_conflict(&service).await?;
+            }
             req.body_json(&service)?;
-            let client = surf::Client::new();
-            let res = client.send(req).await?;
+            let res = self.send(req).await?;
             Ok(res.status())
         } else {
             Err(Error::from_str(StatusCode::BadRequest, "client init err"))
         }
     }
 
+    /// check_name_conflict fails with ConsulError::Conflict if the agent has
+    /// a service of the same name under another ID. Consul defaults a missing
+    /// ID to the name.
+    async fn check_name_conflict(&self, service: &AgentServiceRegistration) -> surf::Result<()> {
+        let name = service.Name.clone().unwrap_or_default();
+        let id = service.ID.clone().unwrap_or_else(|| name.clone());
+        for (_, existing) in self.agent_services().await? {
+            let existing_id = existing.ID.unwrap_or_default();
+            if existing.Service.as_deref() == Some(name.as_str()) && existing_id != id {
+                return Err(Error::new(
+                    StatusCode::Conflict,
+                    ConsulError::Conflict(existing_id),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// verify_reachable checks the service the way VerifyBeforeRegister
+    /// describes, retrying for up to `window`.
+    async fn verify_reachable(
+        &self,
+        service: &AgentServiceRegistration,
+        window: time::Duration,
+    ) -> surf::Result<()> {
+        let check = service.Check.as_ref();
+        let http = check.and_then(|check| check.HTTP.clone());
+        let target = match (http.as_ref(), check.and_then(|check| check.TCP.clone())) {
+            (Some(url), _) => url.to_string(),
+            (None, Some(tcp)) => tcp,
+            (None, None) => match service.Port {
+                Some(port) => format!(
+                    "{}:{}",
+                    service.Address.as_deref().unwrap_or("127.0.0.1"),
+                    port
+                ),
+                None => return Ok(()),
+            },
+        };
+        let deadline = time::Instant::now() + window;
+        loop {
+            let reachable = match http.as_ref() {
+                Some(url) => {
+                    let client = self.client().await?;
+                    succeeds_within(VERIFY_TIMEOUT, client.get(url)).await
+                }
+                None => {
+                    succeeds_within(VERIFY_TIMEOUT, async_std::net::TcpStream::connect(&target))
+                        .await
+                }
+            };
+            if reachable {
+                return Ok(());
+            }
+            if time::Instant::now() >= deadline {
+                return Err(Error::new(
+                    StatusCode::ServiceUnavailable,
+                    ConsulError::Unreachable(target),
+                ));
+            }
+            task::sleep(RECONNECT_MIN_BACKOFF).await;
+        }
+    }
+
+    /// service_register_bulk registers the services concurrently and returns
+    /// the status of each registration, keyed by service ID (or name), so one
+    /// failure doesn't abort the others.
+    pub async fn service_register_bulk(
+        &self,
+        services: &[AgentServiceRegistration],
+    ) -> surf::Result<Vec<(String, StatusCode)>> {
+        self.service_register_bulk_opts(services, &ServiceRegisterOpts::default(), false)
+            .await
+    }
+
+    /// service_register_bulk_opts is service_register_bulk with options. With
+    /// `rollback` set, the successful registrations are deregistered again if
+    /// any registration fails. A failed deregistration doesn't stop the
+    /// rollback; the failures are returned in one error.
+    pub async fn service_register_bulk_opts(
+        &self,
+        services: &[AgentServiceRegistration],
+        opts: &ServiceRegisterOpts,
+        rollback: bool,
+    ) -> surf::Result<Vec<(String, StatusCode)>> {
+        let mut handles = vec![];
+        for service in services.iter() {
+            let config = self.clone();
+            let service = service.clone();
+            let opts = opts.clone();
+            handles.push(spawn_scoped(async move {
+                let id = service.effective_id(&opts).unwrap_or_default();
+                let status = match config.service_register_opts(&service, &opts).await {
+                    Ok(status) => status,
+                    Err(err) => {
+                        log::error!("consul register {} err: {}", id, err);
+                        err.status()
+                    }
+                };
+                (id, status)
+            }));
+        }
+        let mut out = vec![];
+        for handle in handles.into_iter() {
+            out.push(handle.await);
+        }
+        if rollback && out.iter().any(|(_, status)| !status.is_success()) {
+            let mut status = None;
+            let mut errors = vec![];
+            for (id, _) in out
+                .iter()
+                .filter(|(id, status)| !id.is_empty() && status.is_success())
+            {
+                if let Err(err) = self.service_deregister(id.to_string()).await {
+                    log::error!("consul rollback {} err: {}", id, err);
+                    status.get_or_insert(err.status());
+                    errors.push(format!("{}: {}", id, err));
+                }
+            }
+            if let Some(status) = status {
+                return Err(Error::from_str(
+                    status,
+                    format!("deregister {}", errors.join(", ")),
+                ));
+            }
+        }
+        Ok(out)
+    }
+
     /// service_deregister is used to register a new service with
     /// the local agent
     ///
@@ -177,11 +703,22 @@ impl ConsulConfig {
     /// println!("{}", s);
     /// ```
     pub async fn service_deregister(&self, service_id: String) -> surf::Result<StatusCode> {
+        self.service_deregister_opts(service_id, &WriteOptions::default())
+            .await
+    }
+
+    /// service_deregister_opts is service_deregister with WriteOptions, e.g.
+    /// to record the reason of the deregistration in the audit log.
+    pub async fn service_deregister_opts(
+        &self,
+        service_id: String,
+        opts: &WriteOptions,
+    ) -> surf::Result<StatusCode> {
         if self.config.is_some() {
             let uri = format!("/v1/agent/service/deregister/{}", service_id);
-            let req = self.new_request(Method::Put, &uri).await?;
-            let client = surf::Client::new();
-            let res = client.send(req).await?;
+            let mut req = self.new_request(Method::Put, &uri).await?;
+            set_write_options(&mut req, opts);
+            let res = self.send(req).await?;
             Ok(res.status())
         } else {
             Err(Error::from_str(StatusCode::BadRequest, "client init err"))
@@ -191,21 +728,65 @@ impl ConsulConfig {
     pub async fn watch_services() -> surf::Result<()> {
         let config =  CONSUL_CONFIG.clone();
         let config = config.read().await;
-        if config.watch_services.is_some() {
-            loop {
-                let watch_services = config.watch_services.as_ref().unwrap();
+        config.watch_services_self().await
+    }
+
+    /// watch_services_self keeps the addresses of the configured
+    /// watch_services up to date in SERVICES_ADDRESS.
+    pub async fn watch_services_self(&self) -> surf::Result<()> {
+        self.watch_services_opts(&HashMap::new(), None).await
+    }
+
+    /// watch_services_opts is watch_services_self resuming each service not
+    /// cached yet from its index in `indexes`, e.g. one persisted before a
+    /// restart, instead of 0. `indexes` is keyed like SERVICES_ADDRESS, by the
+    /// service name followed by the tag. `wait` overrides the config
+    /// wait_time.
+    pub async fn watch_services_opts(
+        &self,
+        indexes: &HashMap<String, u64>,
+        wait: Option<time::Duration>,
+    ) -> surf::Result<()> {
+        if self.watch_services.is_some() {
+            self.startup_jitter().await;
+            let watch_services = self.watch_services.as_ref().unwrap();
+            let opts: Vec<QueryOptions> = watch_services
+                .iter()
+                .map(|watch_service| {
+                    let tag = watch_service.tag.as_deref().unwrap_or_default();
+                    let key = format!("{}{}", watch_service.service_name, tag);
+                    QueryOptions {
+                        WaitIndex: indexes.get(&key).map(|index| *index as usize),
+                        WaitTime: wait,
+                        ..QueryOptions::default()
+                    }
+                })
+                .collect();
+            let mut backoff = RECONNECT_MIN_BACKOFF;
+            'watch: loop {
                 let mut service_await = vec![];
 
-                for watch_service in watch_services.iter() {
-                    service_await.push(config.get_address(watch_service))
+                for (watch_service, opts) in watch_services.iter().zip(opts.iter()) {
+                    service_await.push(self.get_address(watch_service, opts))
                 }
                 let mut vv = HashMap::new();
                 for v in service_await.into_iter() {
-                    let (key, service_address) = v.await?;
-                    if key != "" {
-                        vv.insert(key, service_address);
+                    match v.await {
+                        Ok((key, service_address)) => {
+                            if key != "" {
+                                vv.insert(key, service_address);
+                            }
+                        }
+                        Err(err) => {
+                            // the next round resumes from the index cached in SERVICES_ADDRESS.
+                            log::warn!("consul watch reconnecting in {:?}: {}", backoff, err);
+                            task::sleep(backoff).await;
+                            backoff = next_backoff(backoff);
+                            continue 'watch;
+                        }
                     }
                 }
+                backoff = RECONNECT_MIN_BACKOFF;
                 if vv.len() != 0 {
                     let services_addresses = SERVICES_ADDRESS.clone();
                     let mut services_addresses = services_addresses.write().await;
@@ -218,10 +799,244 @@ impl ConsulConfig {
         Ok(())
     }
 
+    /// startup_jitter sleeps for a random delay of up to `startup_jitter`
+    /// milliseconds, once per process, so a fleet restarting at the same time
+    /// doesn't register and query all at once. Callers arriving during the
+    /// delay wait for its end too.
+    async fn startup_jitter(&self) {
+        let max = self
+            .config
+            .as_ref()
+            .and_then(|config| config.startup_jitter)
+            .unwrap_or(0);
+        if max == 0 {
+            return;
+        }
+        let until = *STARTUP_JITTER_UNTIL.lock().await.get_or_insert_with(|| {
+            let delay = rand::thread_rng().gen_range(0..=max);
+            time::Instant::now() + time::Duration::from_millis(delay)
+        });
+        let delay = until.saturating_duration_since(time::Instant::now());
+        if !delay.is_zero() {
+            task::sleep(delay).await;
+        }
+    }
+
+    /// spawn_watch_services runs watch_services_self in a background task,
+    /// which is stopped by shutdown.
+    pub async fn spawn_watch_services(&self) {
+        let config = self.clone();
+        let handle = task::spawn(async move {
+            if let Err(err) = config.watch_services_self().await {
+                log::error!("consul watch services err: {}", err);
+            }
+        });
+        BACKGROUND_TASKS.lock().await.push(handle);
+    }
+
+    /// agent_services returns the services registered with the local agent.
+    pub async fn agent_services(&self) -> surf::Result<HashMap<String, AgentService>> {
+        self.agent_services_opts(&QueryOptions::default()).await
+    }
+
+    /// agent_services_opts is agent_services with QueryOptions, e.g. a Filter
+    /// expression such as `Service == "web"` evaluated by the agent.
+    pub async fn agent_services_opts(
+        &self,
+        opts: &QueryOptions,
+    ) -> surf::Result<HashMap<String, AgentService>> {
+        let mut req = self.new_request(Method::Get, "/v1/agent/services").await?;
+        set_query_options(&mut req, opts)?;
+        let mut res = self.send(req).await?;
+        let out: HashMap<String, AgentService> = read_json(&mut res).await?;
+        Ok(out)
+    }
+
+    /// agent_checks returns the checks registered with the local agent.
+    pub async fn agent_checks(&self) -> surf::Result<HashMap<String, AgentCheck>> {
+        self.agent_checks_opts(&QueryOptions::default()).await
+    }
+
+    /// agent_checks_opts is agent_checks with QueryOptions, e.g. a Filter
+    /// expression such as `Status == "critical"` evaluated by the agent.
+    pub async fn agent_checks_opts(
+        &self,
+        opts: &QueryOptions,
+    ) -> surf::Result<HashMap<String, AgentCheck>> {
+        let mut req = self.new_request(Method::Get, "/v1/agent/checks").await?;
+        set_query_options(&mut req, opts)?;
+        let mut res = self.send(req).await?;
+        let out: HashMap<String, AgentCheck> = read_json(&mut res).await?;
+        Ok(out)
+    }
+
+    /// agent_members returns the LAN members known to the agent, or the WAN
+    /// members of the servers with `wan`.
+    pub async fn agent_members(&self, wan: bool) -> surf::Result<Vec<AgentMember>> {
+        self.agent_members_opts(wan, &QueryOptions::default()).await
+    }
+
+    /// agent_members_opts is agent_members with QueryOptions, e.g. a Filter
+    /// such as `Status == 1` to list only the alive members.
+    pub async fn agent_members_opts(
+        &self,
+        wan: bool,
+        opts: &QueryOptions,
+    ) -> surf::Result<Vec<AgentMember>> {
+        let mut req = self.new_request(Method::Get, "/v1/agent/members").await?;
+        set_query_options(&mut req, opts)?;
+        if wan {
+            let mut query: HashMap<&str, String> = HashMap::new();
+            query.insert("wan", String::from("1"));
+            merge_query(&mut req, &query);
+        }
+        let mut res = self.send(req).await?;
+        let out: Vec<AgentMember> = read_json(&mut res).await?;
+        Ok(out)
+    }
+
+    /// agent_config returns the effective configuration of the local agent,
+    /// e.g. its datacenter when the Config doesn't set one.
+    pub async fn agent_config(&self) -> surf::Result<AgentConfig> {
+        let req = self.new_request(Method::Get, "/v1/agent/self").await?;
+        let mut res = self.send(req).await?;
+        let out: AgentSelf = read_json(&mut res).await?;
+        Ok(out.agent_config())
+    }
+
+    /// auto_discover_datacenter sets the datacenter of the Config to the
+    /// agent's when it isn't set, and returns it.
+    pub async fn auto_discover_datacenter(&mut self) -> surf::Result<String> {
+        if let Some(datacenter) = self.config.as_ref().and_then(|c| c.datacenter.clone()) {
+            return Ok(datacenter);
+        }
+        let datacenter = self.agent_config().await?.Datacenter.ok_or_else(|| {
+            Error::from_str(StatusCode::BadRequest, "agent reported no datacenter")
+        })?;
+        if let Some(config) = self.config.as_mut() {
+            config.datacenter = Some(datacenter.to_string());
+        }
+        Ok(datacenter)
+    }
+
+    /// check_pass is used to set a TTL check to the passing state.
+    pub async fn check_pass(&self, check_id: &str, note: &str) -> surf::Result<StatusCode> {
+        let path = format!("/v1/agent/check/pass/{}", check_id);
+        let mut req = self.new_request(Method::Put, &path).await?;
+        if !note.is_empty() {
+            let mut query: HashMap<&str, String> = HashMap::new();
+            query.insert("note", note.to_string());
+            merge_query(&mut req, &query);
+        }
+        let res = self.send(req).await?;
+        Ok(res.status())
+    }
+
+    /// heartbeat_all sets each of the TTL checks to the passing state
+    /// concurrently, and returns the result for each, in order. A failed
+    /// update doesn't stop the others.
+    pub async fn heartbeat_all(
+        &self,
+        check_ids: &[String],
+    ) -> Vec<(String, surf::Result<StatusCode>)> {
+        let mut handles = vec![];
+        for check_id in check_ids.iter() {
+            let config = self.clone();
+            let check_id = check_id.to_string();
+            handles.push(spawn_scoped(async move {
+                let out = config.check_pass(&check_id, "").await;
+                (check_id, out)
+            }));
+        }
+        let mut out = vec![];
+        for handle in handles {
+            out.push(handle.await);
+        }
+        out
+    }
+
+    /// spawn_heartbeat passes the TTL check every `interval` in a background
+    /// task, which is stopped by shutdown.
+    pub async fn spawn_heartbeat(&self, check_id: &str, interval: time::Duration) {
+        let config = self.clone();
+        let check_id = check_id.to_string();
+        let handle = task::spawn(async move {
+            loop {
+                if let Err(err) = config.check_pass(&check_id, "").await {
+                    log::error!("consul heartbeat {} err: {}", check_id, err);
+                }
+                task::sleep(interval).await;
+            }
+        });
+        BACKGROUND_TASKS.lock().await.push(handle);
+    }
+
+    /// service_register_guarded registers the service and deregisters it
+    /// again on shutdown.
+    pub async fn service_register_guarded(
+        &self,
+        service: &AgentServiceRegistration,
+    ) -> surf::Result<StatusCode> {
+        self.service_register_guarded_opts(service, &ServiceRegisterOpts::default())
+            .await
+    }
+
+    /// service_register_guarded_opts is service_register_guarded with
+    /// ServiceRegisterOpts.
+    pub async fn service_register_guarded_opts(
+        &self,
+        service: &AgentServiceRegistration,
+        opts: &ServiceRegisterOpts,
+    ) -> surf::Result<StatusCode> {
+        let status = self.service_register_opts(service, opts).await?;
+        if let Some(id) = service.effective_id(opts) {
+            GUARDED_SERVICES.lock().await.push(id);
+        }
+        Ok(status)
+    }
+
+    /// shutdown stops the background tasks spawned by this crate, cancelling
+    /// any blocking query in flight, and deregisters the guarded services.
+    /// Each task is given up to `timeout` to stop. A failed deregistration
+    /// doesn't stop the others; the failures are returned in one error.
+    ///
+    /// The tasks and guarded services are kept per process rather than per
+    /// client, so shutdown stops those started through any client.
+    pub async fn shutdown(self, timeout: time::Duration) -> surf::Result<()> {
+        let handles: Vec<task::JoinHandle<()>> = BACKGROUND_TASKS.lock().await.drain(..).collect();
+        for handle in handles.into_iter() {
+            if async_std::future::timeout(timeout, handle.cancel())
+                .await
+                .is_err()
+            {
+                log::warn!("consul background task did not stop within {:?}", timeout);
+            }
+        }
+        let service_ids: Vec<String> = GUARDED_SERVICES.lock().await.drain(..).collect();
+        let mut status = None;
+        let mut errors = vec![];
+        for service_id in service_ids.into_iter() {
+            if let Err(err) = self.service_deregister(service_id.clone()).await {
+                log::error!("consul deregister {} err: {}", service_id, err);
+                status.get_or_insert(err.status());
+                errors.push(format!("{}: {}", service_id, err));
+            }
+        }
+        match status {
+            Some(status) => Err(Error::from_str(
+                status,
+                format!("deregister {}", errors.join(", ")),
+            )),
+            None => Ok(()),
+        }
+    }
+
     async fn health_service(
         &self,
         watch_service: &WatchService,
-    ) -> surf::Result<(u64, Vec<ServiceEntry>)> {
+        opts: &QueryOptions,
+    ) -> surf::Result<(QueryMeta, Vec<ServiceEntry>)> {
+        let index = opts.WaitIndex.unwrap_or(0);
         let path = format!("/v1/health/service/{}", watch_service.service_name);
         if self.config.is_some() {
             let mut req = self.new_request(Method::Get, &path).await?;
@@ -231,38 +1046,43 @@ impl ConsulConfig {
             if tag != "" {
                 query.insert("tag", tag.to_string());
             }
-            let services_addresses = SERVICES_ADDRESS.clone();
-            let services_addresses = services_addresses.read().await;
-            let key = format!("{}{}", watch_service.service_name, tag);
-            let service_address = services_addresses.get(&key);
-            let mut index = 0;
-            if service_address.is_some() {
-                let service_address = service_address.unwrap();
-                index = service_address.index;
+            if let Some(datacenter) = watch_service.datacenter.as_ref() {
+                query.insert("dc", datacenter.to_string());
+            }
+            // the health endpoint has no node parameter, so filter on it.
+            if let Some(node) = watch_service.node.as_ref() {
+                query.insert("filter", node_filter(node));
             }
             query.insert("index", index.to_string());
 
-            if watch_service.passing_only.is_some() {
-                let passing = watch_service.passing_only.unwrap();
-                if passing {
-                    let config = self.config.as_ref().unwrap();
-                    let wait;
-                    if config.wait_time.is_some() {
-                        wait = config.wait_time.as_ref().unwrap().to_string();
-                    } else {
-                        wait = String::from("5s")
-                    }
-                    query.insert("passing", "1".to_string());
-                    query.insert("wait", wait);
+            let passing_only = watch_service.passing_only == Some(true);
+            let warning_ok = passing_only && watch_service.warning_ok == Some(true);
+            if passing_only && !warning_ok {
+                query.insert("passing", "1".to_string());
+            }
+            // only a blocking query (index > 0) waits for changes.
+            if index > 0 {
+                let config = self.config.as_ref().unwrap();
+                let wait;
+                if let Some(wait_time) = opts.WaitTime {
+                    wait = GoDuration(wait_time).to_string();
+                } else if config.wait_time.is_some() {
+                    wait = config.wait_time.as_ref().unwrap().to_string();
+                } else {
+                    wait = String::from("5s")
                 }
-            };
-            req.set_query(&query)?;
+                query.insert("wait", wait);
+            }
+            merge_query(&mut req, &query);
             let uri = req.url().to_string();
             log::debug!("{}", uri);
-            let client = surf::Client::new();
-            let mut res = client.send(req).await?;
-            let out: Vec<ServiceEntry> = res.body_json().await?;
-            Ok((index, out))
+            let mut res = self.send(req).await?;
+            let meta = parse_query_meta(&res);
+            let mut out: Vec<ServiceEntry> = read_json(&mut res).await?;
+            if warning_ok {
+                out.retain(ServiceEntry::passing_or_warning);
+            }
+            Ok((meta, out))
         } else {
             Err(Error::from_str(StatusCode::BadRequest, "client init err"))
         }
@@ -271,8 +1091,33 @@ impl ConsulConfig {
     async fn get_address(
         &self,
         watch_service: &WatchService,
+        opts: &QueryOptions,
     ) -> surf::Result<(String, ServiceAddress)> {
-        let (cur_index, entry) = self.health_service(watch_service).await?;
+        let mut tag = "";
+        if watch_service.tag.is_some() {
+            tag = watch_service.tag.as_ref().unwrap();
+        };
+        let key = format!("{}{}", watch_service.service_name, tag);
+        let mut cur_index = opts.WaitIndex.unwrap_or(0) as u64;
+        {
+            let services_addresses = SERVICES_ADDRESS.clone();
+            let services_addresses = services_addresses.read().await;
+            if let Some(service_address) = services_addresses.get(&key) {
+                cur_index = service_address.index;
+            }
+        }
+        let opts = QueryOptions {
+            WaitIndex: Some(cur_index as usize),
+            ..opts.clone()
+        };
+        if self.streaming_backend() {
+            return self.get_address_streaming(watch_service, key, &opts).await;
+        }
+        let (_, entry) = self.health_service(watch_service, &opts).await?;
+        WATCH_POLLS
+            .write()
+            .await
+            .insert(key.clone(), time::SystemTime::now());
         let mut service_addresses = vec![];
         let mut service_addresses_link = LinkedList::new();
         let mut index = 0;
@@ -295,222 +1140,4256 @@ impl ConsulConfig {
         if service_addresses.len() == 0 {
             return Ok((String::new(), ServiceAddress::default()));
         };
-        let mut tag = "";
-        if watch_service.tag.is_some() {
-            tag = watch_service.tag.as_ref().unwrap();
-        };
-        let key = format!("{}{}", watch_service.service_name, tag);
+        if self.sort_addresses() {
+            service_addresses.sort();
+            service_addresses_link = service_addresses.iter().cloned().collect();
+        }
         let service_addresses = ServiceAddress {
             index,
             address: service_addresses,
             address_link: service_addresses_link,
+            last_update: Some(time::Instant::now()),
         };
 
         Ok((key, service_addresses))
     }
 
-    pub async fn random_policy(&self, service_name: &str, tag: &str) -> surf::Result<String> {
-        let key = format!("{}{}", service_name, tag);
-        let services_addresses = SERVICES_ADDRESS.clone();
-        let services_addresses = services_addresses.read().await;
-        let service_addresses = services_addresses.get(&key);
-        if service_addresses.is_some() {
-            let service_addresses = service_addresses.unwrap();
-            let range = service_addresses.address.len();
-            if range == 0 {
-                return Err(Error::from_str(
-                    StatusCode::BadRequest,
-                    "consul server address is empty",
-                ));
-            };
-            let mut r = rand::thread_rng();
-            let idx: usize = r.gen_range(0..range);
-            let address = service_addresses.address.get(idx).unwrap();
-            return Ok(String::from(address));
+    /// get_address_streaming is get_address for an agent serving blocking
+    /// health queries from its streaming backend. The backend turns the event
+    /// stream (a snapshot then deltas) into the full list of instances, so
+    /// every response replaces the cached addresses, including when the last
+    /// instance is gone. It falls back to get_address for good once a blocking
+    /// query isn't served by the streaming backend.
+    async fn get_address_streaming(
+        &self,
+        watch_service: &WatchService,
+        key: String,
+        opts: &QueryOptions,
+    ) -> surf::Result<(String, ServiceAddress)> {
+        let index = opts.WaitIndex.unwrap_or(0);
+        let (meta, entries) = self.health_service(watch_service, opts).await?;
+        WATCH_POLLS
+            .write()
+            .await
+            .insert(key.clone(), time::SystemTime::now());
+        if index > 0 && meta.QueryBackend.as_deref() != Some(QUERY_BACKEND_STREAMING) {
+            log::warn!("consul streaming backend unsupported, falling back to blocking queries");
+            STREAMING_UNSUPPORTED.store(true, Ordering::SeqCst);
+        }
+        let mut address = entry_addresses(&entries);
+        if self.sort_addresses() {
+            address.sort();
+        }
+        let service_address = ServiceAddress {
+            index: meta.LastIndex,
+            address_link: address.iter().cloned().collect(),
+            address,
+            last_update: Some(time::Instant::now()),
+        };
+        Ok((key, service_address))
+    }
+
+    fn streaming_backend(&self) -> bool {
+        let enabled = self
+            .config
+            .as_ref()
+            .and_then(|config| config.streaming_backend)
+            .unwrap_or(false);
+        enabled && !STREAMING_UNSUPPORTED.load(Ordering::SeqCst)
+    }
+
+    /// resolve reads the current passing instances of a service and refreshes
+    /// the cached addresses. If Consul can't be reached and `max_stale` is set,
+    /// the cached addresses are returned marked as stale, provided they were
+    /// fetched within `max_stale` seconds. With a `circuit_breaker` configured,
+    /// a service that keeps failing or has no passing instance is failed fast
+    /// with ConsulError::CircuitOpen until its cooldown has passed.
+    pub async fn resolve(&self, watch_service: &WatchService) -> surf::Result<ResolvedAddress> {
+        let tag = watch_service.tag.clone().unwrap_or_default();
+        let key = format!("{}{}", watch_service.service_name, tag);
+        let breaker = self
+            .config
+            .as_ref()
+            .and_then(|config| config.circuit_breaker.as_ref());
+        if let Some(breaker) = breaker {
+            let mut breakers = CIRCUIT_BREAKERS.write().await;
+            let cooldown = time::Duration::from_secs(breaker.cooldown);
+            if !breakers.entry(key.clone()).or_default().allow(cooldown) {
+                return Err(Error::new(
+                    StatusCode::ServiceUnavailable,
+                    ConsulError::CircuitOpen(key),
+                ));
+            }
+        }
+        let out = self.resolve_self(watch_service, &key).await;
+        if let Some(breaker) = breaker {
+            let mut breakers = CIRCUIT_BREAKERS.write().await;
+            let state = breakers.entry(key).or_default();
+            match out.as_ref() {
+                Ok(resolved) if !resolved.stale && !resolved.address.is_empty() => {
+                    state.record_success()
+                }
+                _ => state.record_failure(breaker.failure_threshold),
+            }
+        }
+        out
+    }
+
+    /// watch_status returns the cached state of each of the watch_services.
+    pub async fn watch_status(&self) -> Vec<WatchStatus> {
+        let services_addresses = SERVICES_ADDRESS.read().await;
+        let polls = WATCH_POLLS.read().await;
+        let mut out = vec![];
+        for watch_service in self.watch_services.iter().flatten() {
+            let tag = watch_service.tag.clone().unwrap_or_default();
+            let key = format!("{}{}", watch_service.service_name, tag);
+            let mut status = WatchStatus {
+                service_name: watch_service.service_name.to_string(),
+                tag: watch_service.tag.clone(),
+                last_poll: polls.get(&key).cloned(),
+                ..WatchStatus::default()
+            };
+            if let Some(service_address) = services_addresses.get(&key) {
+                status.index = service_address.index;
+                status.address = service_address.address.clone();
+            }
+            out.push(status);
+        }
+        out
+    }
+
+    fn sort_addresses(&self) -> bool {
+        self.config
+            .as_ref()
+            .and_then(|config| config.sort_addresses)
+            .unwrap_or(false)
+    }
+
+    /// export_hosts reads the passing instances of each service and renders
+    /// them as /etc/hosts lines, one per IP, e.g. `10.0.0.1 web.service.consul`,
+    /// for tools that can't query Consul. Instances registered with a hostname
+    /// rather than an IP are left out.
+    pub async fn export_hosts(&self, services: &[&str]) -> surf::Result<String> {
+        let mut out = String::new();
+        for (service, addresses) in self.resolve_all(services).await? {
+            let mut ips: Vec<&str> = vec![];
+            for address in addresses.iter() {
+                let ip = address
+                    .rsplit_once(':')
+                    .map_or(address.as_str(), |(ip, _)| ip);
+                if ip.parse::<std::net::IpAddr>().is_ok() && !ips.contains(&ip) {
+                    ips.push(ip);
+                }
+            }
+            for ip in ips {
+                out.push_str(&format!("{} {}.service.consul\n", ip, service));
+            }
+        }
+        Ok(out)
+    }
+
+    /// export_env reads the passing instances of each service and renders
+    /// them as environment variables, e.g. `WEB_API_ADDR=10.0.0.1:80,10.0.0.2:80`
+    /// for `web-api`.
+    pub async fn export_env(&self, services: &[&str]) -> surf::Result<String> {
+        let mut out = String::new();
+        for (service, addresses) in self.resolve_all(services).await? {
+            let name: String = service
+                .chars()
+                .map(|c| {
+                    if c.is_ascii_alphanumeric() {
+                        c.to_ascii_uppercase()
+                    } else {
+                        '_'
+                    }
+                })
+                .collect();
+            out.push_str(&format!("{}_ADDR={}\n", name, addresses.join(",")));
+        }
+        Ok(out)
+    }
+
+    /// resolve_all reads the addresses of the passing instances of each
+    /// service, leaving out those without one. Unlike resolve it leaves the
+    /// cached addresses and the circuit breakers alone.
+    async fn resolve_all(&self, services: &[&str]) -> surf::Result<Vec<(String, Vec<String>)>> {
+        let mut out = vec![];
+        for service in services.iter() {
+            let watch_service = WatchService {
+                service_name: service.to_string(),
+                passing_only: Some(true),
+                ..WatchService::default()
+            };
+            let (_, entries) = self
+                .health_service(&watch_service, &QueryOptions::default())
+                .await?;
+            let addresses = entry_addresses(&entries);
+            if !addresses.is_empty() {
+                out.push((service.to_string(), addresses));
+            }
+        }
+        Ok(out)
+    }
+
+    /// resolve_srv returns the passing instances of a service as SRV records,
+    /// weighted by their `Weights.Passing`.
+    pub async fn resolve_srv(
+        &self,
+        service: &str,
+        tag: Option<&str>,
+    ) -> surf::Result<Vec<SrvRecord>> {
+        let watch_service = WatchService {
+            service_name: service.to_string(),
+            tag: tag.map(String::from),
+            passing_only: Some(true),
+            ..WatchService::default()
+        };
+        let (_, entries) = self
+            .health_service(&watch_service, &QueryOptions::default())
+            .await?;
+        Ok(entries.iter().filter_map(SrvRecord::from_entry).collect())
+    }
+
+    /// instance_health returns every instance of a service, healthy or not,
+    /// with the name and output of each of its failing checks.
+    pub async fn instance_health(
+        &self,
+        service: &str,
+        tag: Option<&str>,
+    ) -> surf::Result<Vec<InstanceHealth>> {
+        let watch_service = WatchService {
+            service_name: service.to_string(),
+            tag: tag.map(String::from),
+            ..WatchService::default()
+        };
+        let (_, entries) = self
+            .health_service(&watch_service, &QueryOptions::default())
+            .await?;
+        Ok(entries.iter().map(InstanceHealth::from_entry).collect())
+    }
+
+    /// service_health_global returns the aggregated status of a service in
+    /// each of `dcs`, queried concurrently. A datacenter that can't be queried
+    /// is HealthStatus::Unreachable rather than failing the whole call. It
+    /// isn't reported as HealthStatus::Unknown, which carries a check status
+    /// this crate doesn't know, so the two can be told apart.
+    pub async fn service_health_global(
+        &self,
+        service: &str,
+        dcs: &[&str],
+    ) -> surf::Result<HashMap<String, HealthStatus>> {
+        let mut handles = vec![];
+        for dc in dcs.iter() {
+            let config = self.clone();
+            let dc = dc.to_string();
+            let watch_service = WatchService {
+                service_name: service.to_string(),
+                datacenter: Some(dc.clone()),
+                ..WatchService::default()
+            };
+            handles.push(spawn_scoped(async move {
+                let opts = QueryOptions::default();
+                let status = match config.health_service(&watch_service, &opts).await {
+                    Ok((_, entries)) => HealthStatus::from_entries(&entries),
+                    Err(err) => {
+                        log::warn!(
+                            "consul health of {} in {} unknown: {}",
+                            watch_service.service_name,
+                            dc,
+                            err
+                        );
+                        HealthStatus::Unreachable
+                    }
+                };
+                (dc, status)
+            }));
+        }
+        let mut out = HashMap::new();
+        for handle in handles {
+            let (dc, status) = handle.await;
+            out.insert(dc, status);
+        }
+        Ok(out)
+    }
+
+    /// circuit_states returns the circuit breaker state of each resolved service.
+    pub async fn circuit_states(&self) -> HashMap<String, CircuitState> {
+        let breakers = CIRCUIT_BREAKERS.read().await;
+        breakers
+            .iter()
+            .map(|(key, breaker)| (key.to_string(), breaker.state))
+            .collect()
+    }
+
+    async fn resolve_self(
+        &self,
+        watch_service: &WatchService,
+        key: &str,
+    ) -> surf::Result<ResolvedAddress> {
+        let key = key.to_string();
+        let services_addresses = SERVICES_ADDRESS.clone();
+        match self
+            .health_service(watch_service, &QueryOptions::default())
+            .await
+        {
+            Ok((_, entries)) => {
+                let mut address = entry_addresses(&entries);
+                if self.sort_addresses() {
+                    address.sort();
+                }
+                let mut services_addresses = services_addresses.write().await;
+                let service_address = services_addresses.entry(key).or_default();
+                service_address.address = address.clone();
+                service_address.address_link = address.iter().cloned().collect();
+                service_address.last_update = Some(time::Instant::now());
+                Ok(ResolvedAddress {
+                    address,
+                    stale: false,
+                })
+            }
+            Err(err) => {
+                let max_stale = self.config.as_ref().and_then(|config| config.max_stale);
+                if let Some(max_stale) = max_stale {
+                    let services_addresses = services_addresses.read().await;
+                    if let Some(service_address) = services_addresses.get(&key) {
+                        let fresh = service_address.last_update.is_some_and(|last_update| {
+                            last_update.elapsed() <= time::Duration::from_secs(max_stale)
+                        });
+                        if fresh && !service_address.address.is_empty() {
+                            log::warn!(
+                                "consul unreachable, using stale addresses for {}: {}",
+                                key,
+                                err
+                            );
+                            return Ok(ResolvedAddress {
+                                address: service_address.address.clone(),
+                                stale: true,
+                            });
+                        }
+                    }
+                }
+                Err(err)
+            }
+        }
+    }
+
+    /// resolve_federated tries each datacenter in `dc_order` and returns the
+    /// passing instances of the first one that has any, giving a simple
+    /// "prefer local DC, fall back to remote" resolution without prepared
+    /// queries. A datacenter that can't be queried is skipped. An empty vec
+    /// is returned when no datacenter has instances, and the last error when
+    /// none of them could be queried.
+    pub async fn resolve_federated(
+        &self,
+        service: &str,
+        tag: Option<&str>,
+        dc_order: &[&str],
+    ) -> surf::Result<Vec<String>> {
+        let mut failed = 0;
+        let mut last_err = None;
+        for dc in dc_order.iter() {
+            let watch_service = WatchService {
+                service_name: service.to_string(),
+                tag: tag.map(String::from),
+                passing_only: Some(true),
+                datacenter: Some(dc.to_string()),
+                ..WatchService::default()
+            };
+            let entries = match self
+                .health_service(&watch_service, &QueryOptions::default())
+                .await
+            {
+                Ok((_, entries)) => entries,
+                Err(err) => {
+                    log::warn!("consul resolve {} in {} err: {}", service, dc, err);
+                    failed += 1;
+                    last_err = Some(err);
+                    continue;
+                }
+            };
+            let addresses = entry_addresses(&entries);
+            if !addresses.is_empty() {
+                return Ok(addresses);
+            }
+        }
+        match last_err {
+            Some(err) if failed == dc_order.len() => Err(err),
+            _ => Ok(vec![]),
+        }
+    }
+
+    /// agent_monitor streams the agent's log lines at `log_level` until the
+    /// connection closes. The body is read line by line as it arrives, and
+    /// dropping the stream closes the connection.
+    pub async fn agent_monitor(
+        &self,
+        log_level: &str,
+    ) -> surf::Result<impl Stream<Item = surf::Result<String>>> {
+        let mut req = self.new_request(Method::Get, "/v1/agent/monitor").await?;
+        let mut query: HashMap<&str, String> = HashMap::new();
+        query.insert("loglevel", log_level.to_string());
+        merge_query(&mut req, &query);
+        let res = self.send_stream(req).await?;
+        Ok(res.lines().map(|line| line.map_err(Error::from)))
+    }
+
+    /// agent_monitor_reconnect is agent_monitor that never ends: when the
+    /// connection fails or closes it yields a `MonitorEvent::Reconnecting` and
+    /// reopens the log stream with exponential backoff. Dropping the stream
+    /// closes the connection.
+    pub fn agent_monitor_reconnect(&self, log_level: &str) -> impl Stream<Item = MonitorEvent> {
+        MonitorStream {
+            monitor: Some(Monitor {
+                config: self.clone(),
+                log_level: log_level.to_string(),
+                lines: None,
+                backoff: RECONNECT_MIN_BACKOFF,
+                reconnecting: false,
+            }),
+            pending: None,
+        }
+    }
+
+    /// raw_get sends a GET to any endpoint `path`, e.g. `/v1/status/leader`,
+    /// with the usual token, datacenter and namespace handling, and returns
+    /// the JSON response. An empty response is returned as null.
+    pub async fn raw_get(
+        &self,
+        path: &str,
+        opts: &QueryOptions,
+    ) -> surf::Result<serde_json::Value> {
+        let mut req = self.new_request(Method::Get, path).await?;
+        set_query_options(&mut req, opts)?;
+        let res = self.send(req).await?;
+        raw_body(res).await
+    }
+
+    /// raw_put sends `body` as JSON in a PUT to any endpoint `path`, and
+    /// returns the JSON response like raw_get.
+    pub async fn raw_put(
+        &self,
+        path: &str,
+        body: serde_json::Value,
+    ) -> surf::Result<serde_json::Value> {
+        let mut req = self.new_request(Method::Put, path).await?;
+        req.body_json(&body)?;
+        let res = self.send(req).await?;
+        raw_body(res).await
+    }
+
+    /// kv_get returns the pair at `key`, or None if it doesn't exist.
+    pub async fn kv_get(&self, key: &str) -> surf::Result<Option<KVPair>> {
+        self.kv_get_opts(key, &QueryOptions::default()).await
+    }
+
+    /// kv_get_opts is kv_get with QueryOptions, e.g. AllowStale for a
+    /// low-latency read served by any server, or RequireConsistent for a
+    /// value used for coordination.
+    pub async fn kv_get_opts(
+        &self,
+        key: &str,
+        opts: &QueryOptions,
+    ) -> surf::Result<Option<KVPair>> {
+        let (out, _) = self.kv_get_meta(key, opts).await?;
+        Ok(out)
+    }
+
+    async fn kv_get_meta(
+        &self,
+        key: &str,
+        opts: &QueryOptions,
+    ) -> surf::Result<(Option<KVPair>, QueryMeta)> {
+        let path = format!("/v1/kv/{}", key);
+        let mut req = self.new_request(Method::Get, &path).await?;
+        set_query_options(&mut req, opts)?;
+        match self.send(req).await {
+            Ok(mut res) => {
+                let meta = parse_query_meta(&res);
+                let out: Vec<KVPair> = read_json(&mut res).await?;
+                Ok((out.into_iter().next(), meta))
+            }
+            Err(err) if err.status() == StatusCode::NotFound => Ok((None, QueryMeta::default())),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// kv_list returns every pair under `prefix`.
+    pub async fn kv_list(&self, prefix: &str) -> surf::Result<Vec<KVPair>> {
+        self.kv_list_opts(prefix, &QueryOptions::default()).await
+    }
+
+    /// kv_list_opts is kv_list with QueryOptions.
+    pub async fn kv_list_opts(
+        &self,
+        prefix: &str,
+        opts: &QueryOptions,
+    ) -> surf::Result<Vec<KVPair>> {
+        let path = format!("/v1/kv/{}", prefix);
+        let mut req = self.new_request(Method::Get, &path).await?;
+        set_query_options(&mut req, opts)?;
+        let mut query: HashMap<&str, String> = HashMap::new();
+        query.insert("recurse", "true".to_string());
+        merge_query(&mut req, &query);
+        match self.send(req).await {
+            Ok(mut res) => Ok(read_json(&mut res).await?),
+            Err(err) if err.status() == StatusCode::NotFound => Ok(vec![]),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// kv_export lists every key under `prefix` recursively, as returned by
+    /// `consul kv export` once converted with KVExportEntry::from.
+    pub async fn kv_export(&self, prefix: &str) -> surf::Result<Vec<KVPair>> {
+        self.kv_export_opts(prefix, &QueryOptions::default()).await
+    }
+
+    /// kv_export_opts is kv_export with QueryOptions, e.g. NAMESPACE_WILDCARD
+    /// to export the prefix from every namespace.
+    pub async fn kv_export_opts(
+        &self,
+        prefix: &str,
+        opts: &QueryOptions,
+    ) -> surf::Result<Vec<KVPair>> {
+        self.kv_list_opts(prefix, opts).await
+    }
+
+    /// kv_put writes the decoded Value of the pair at its Key, along with its
+    /// Flags, returning whether it was written.
+    pub async fn kv_put(&self, pair: &KVPair) -> surf::Result<bool> {
+        self.kv_put_opts(pair, &WriteOptions::default()).await
+    }
+
+    /// kv_put_opts is kv_put with WriteOptions.
+    pub async fn kv_put_opts(&self, pair: &KVPair, opts: &WriteOptions) -> surf::Result<bool> {
+        let value = base64::decode(pair.Value.as_deref().unwrap_or_default())
+            .map_err(|err| Error::from_str(StatusCode::BadRequest, err.to_string()))?;
+        let path = format!("/v1/kv/{}", pair.Key);
+        let mut req = self.new_request(Method::Put, &path).await?;
+        set_write_options(&mut req, opts);
+        if let Some(flags) = pair.Flags.filter(|flags| *flags != 0) {
+            let mut query: HashMap<&str, String> = HashMap::new();
+            query.insert("flags", flags.to_string());
+            merge_query(&mut req, &query);
+        }
+        req.body_bytes(value);
+        let mut res = self.send(req).await?;
+        let out: bool = read_json(&mut res).await?;
+        Ok(out)
+    }
+
+    /// kv_import writes every pair with kv_put, like `consul kv import`. A
+    /// failed key doesn't stop the import; the keys that couldn't be written
+    /// are returned in ConsulError::ImportFailed.
+    pub async fn kv_import(&self, pairs: &[KVPair]) -> surf::Result<()> {
+        let mut failed = vec![];
+        let mut status = StatusCode::InternalServerError;
+        for pair in pairs.iter() {
+            match self.kv_put(pair).await {
+                Ok(true) => {}
+                Ok(false) => failed.push(pair.Key.to_string()),
+                Err(err) => {
+                    log::error!("consul kv import {} err: {}", pair.Key, err);
+                    status = err.status();
+                    failed.push(pair.Key.to_string());
+                }
+            }
+        }
+        if failed.is_empty() {
+            Ok(())
+        } else {
+            Err(Error::new(status, ConsulError::ImportFailed(failed)))
+        }
+    }
+
+    /// kv_acquire writes `value` at `key` and locks it for `session`,
+    /// returning whether the lock was acquired.
+    pub async fn kv_acquire(&self, key: &str, session: &str, value: &[u8]) -> surf::Result<bool> {
+        self.kv_lock_op(key, "acquire", session, value).await
+    }
+
+    /// kv_release unlocks `key` held by `session`.
+    pub async fn kv_release(&self, key: &str, session: &str) -> surf::Result<bool> {
+        self.kv_lock_op(key, "release", session, &[]).await
+    }
+
+    async fn kv_lock_op(
+        &self,
+        key: &str,
+        op: &str,
+        session: &str,
+        value: &[u8],
+    ) -> surf::Result<bool> {
+        let path = format!("/v1/kv/{}", key);
+        let mut req = self.new_request(Method::Put, &path).await?;
+        let mut query: HashMap<&str, String> = HashMap::new();
+        query.insert(op, session.to_string());
+        merge_query(&mut req, &query);
+        req.body_bytes(value);
+        let mut res = self.send(req).await?;
+        let out: bool = read_json(&mut res).await?;
+        Ok(out)
+    }
+
+    /// kv_lock_blocking acquires the lock on `key` for `session`, returning
+    /// false if it is still held by another session after `timeout`. While
+    /// the lock is held it waits on a blocking query of the key, so it wakes
+    /// as soon as the holder releases it. A key that is free but can't be
+    /// acquired is in the lock-delay of an invalidated session: the LockDelay
+    /// of the holder is read with session_info and the rest of it is slept
+    /// out, falling back to retrying every LOCK_RETRY_TIME if it is unknown.
+    pub async fn kv_lock_blocking(
+        &self,
+        key: &str,
+        session: &str,
+        timeout: time::Duration,
+    ) -> surf::Result<bool> {
+        let deadline = time::Instant::now() + timeout;
+        let mut index = 0;
+        let mut held = true;
+        let mut holder: Option<String> = None;
+        let mut lock_delay: Option<time::Duration> = None;
+        let mut released_at: Option<time::Instant> = None;
+        loop {
+            if self.kv_acquire(key, session, &[]).await? {
+                return Ok(true);
+            }
+            let remaining = deadline.saturating_duration_since(time::Instant::now());
+            if remaining.is_zero() {
+                return Ok(false);
+            }
+            let opts = QueryOptions {
+                WaitIndex: Some(if held { index } else { 0 }),
+                WaitTime: Some(remaining),
+                ..QueryOptions::default()
+            };
+            let (pair, meta) = self.kv_get_meta(key, &opts).await?;
+            let now_held = pair.as_ref().is_some_and(|pair| pair.Session.is_some());
+            if let Some(current) = pair.and_then(|pair| pair.Session) {
+                if holder.as_ref() != Some(&current) {
+                    lock_delay = self
+                        .session_info(&current)
+                        .await?
+                        .and_then(|entry| entry.LockDelay)
+                        .map(|delay| delay.as_duration());
+                    holder = Some(current);
+                }
+            }
+            if held && !now_held {
+                released_at = Some(time::Instant::now());
+            }
+            if !held && !now_held {
+                let delay = lock_delay
+                    .zip(released_at)
+                    .and_then(|(delay, at)| delay.checked_sub(at.elapsed()))
+                    .filter(|delay| !delay.is_zero())
+                    .unwrap_or(LOCK_RETRY_TIME);
+                task::sleep(std::cmp::min(delay, remaining)).await;
+            }
+            held = now_held;
+            index = meta.LastIndex as usize;
+        }
+    }
+
+    /// session_create creates a session and returns its ID.
+    pub async fn session_create(&self, session: &SessionEntry) -> surf::Result<String> {
+        self.session_create_opts(session, &WriteOptions::default())
+            .await
+    }
+
+    pub async fn session_create_opts(
+        &self,
+        session: &SessionEntry,
+        opts: &WriteOptions,
+    ) -> surf::Result<String> {
+        let mut req = self.new_request(Method::Put, "/v1/session/create").await?;
+        set_write_options(&mut req, opts);
+        req.body_json(session)?;
+        let mut res = self.send(req).await?;
+        let out: SessionCreateResponse = read_json(&mut res).await?;
+        Ok(out.ID)
+    }
+
+    /// session_list returns every session in the datacenter.
+    pub async fn session_list(&self) -> surf::Result<Vec<SessionEntry>> {
+        self.session_list_opts(&QueryOptions::default()).await
+    }
+
+    pub async fn session_list_opts(&self, opts: &QueryOptions) -> surf::Result<Vec<SessionEntry>> {
+        self.sessions("/v1/session/list", opts).await
+    }
+
+    /// session_node returns the sessions anchored to `node`, e.g. to find the
+    /// locks still held after the node failed.
+    pub async fn session_node(&self, node: &str) -> surf::Result<Vec<SessionEntry>> {
+        self.session_node_opts(node, &QueryOptions::default()).await
+    }
+
+    pub async fn session_node_opts(
+        &self,
+        node: &str,
+        opts: &QueryOptions,
+    ) -> surf::Result<Vec<SessionEntry>> {
+        let path = format!("/v1/session/node/{}", node);
+        self.sessions(&path, opts).await
+    }
+
+    /// session_info returns the session `id`, or None if it doesn't exist.
+    pub async fn session_info(&self, id: &str) -> surf::Result<Option<SessionEntry>> {
+        self.session_info_opts(id, &QueryOptions::default()).await
+    }
+
+    pub async fn session_info_opts(
+        &self,
+        id: &str,
+        opts: &QueryOptions,
+    ) -> surf::Result<Option<SessionEntry>> {
+        let path = format!("/v1/session/info/{}", id);
+        Ok(self.sessions(&path, opts).await?.into_iter().next())
+    }
+
+    async fn sessions(&self, path: &str, opts: &QueryOptions) -> surf::Result<Vec<SessionEntry>> {
+        let mut req = self.new_request(Method::Get, path).await?;
+        set_query_options(&mut req, opts)?;
+        let mut res = self.send(req).await?;
+        let out: Option<Vec<SessionEntry>> = read_json(&mut res).await?;
+        Ok(out.unwrap_or_default())
+    }
+
+    /// peering_generate_token generates the token the cluster `name` uses to
+    /// establish a peering with this one. Requires a management token.
+    pub async fn peering_generate_token(
+        &self,
+        name: &str,
+    ) -> surf::Result<PeeringGenerateTokenResponse> {
+        let mut req = self.new_request(Method::Post, "/v1/peering/token").await?;
+        req.body_json(&PeeringGenerateTokenRequest {
+            PeerName: name.to_string(),
+            ..PeeringGenerateTokenRequest::default()
+        })?;
+        let mut res = self.send(req).await?;
+        let out: PeeringGenerateTokenResponse = read_json(&mut res).await?;
+        Ok(out)
+    }
+
+    /// peering_establish establishes the peering `name` with the token
+    /// generated by the other cluster. Requires a management token.
+    pub async fn peering_establish(&self, name: &str, token: &str) -> surf::Result<StatusCode> {
+        let mut req = self
+            .new_request(Method::Post, "/v1/peering/establish")
+            .await?;
+        req.body_json(&PeeringEstablishRequest {
+            PeerName: name.to_string(),
+            PeeringToken: token.to_string(),
+            ..PeeringEstablishRequest::default()
+        })?;
+        let res = self.send(req).await?;
+        Ok(res.status())
+    }
+
+    /// peering_read returns the peering `name`, or None if there is none.
+    pub async fn peering_read(&self, name: &str) -> surf::Result<Option<Peering>> {
+        let path = format!("/v1/peering/{}", name);
+        let req = self.new_request(Method::Get, &path).await?;
+        match self.send(req).await {
+            Ok(mut res) => Ok(Some(read_json(&mut res).await?)),
+            Err(err) if err.status() == StatusCode::NotFound => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// peering_list returns the peerings of the cluster.
+    pub async fn peering_list(&self) -> surf::Result<Vec<Peering>> {
+        let req = self.new_request(Method::Get, "/v1/peerings").await?;
+        let mut res = self.send(req).await?;
+        let out: Vec<Peering> = read_json(&mut res).await?;
+        Ok(out)
+    }
+
+    /// config_entry_set writes a config entry, returning whether it was written.
+    pub async fn config_entry_set<T: serde::Serialize>(&self, entry: &T) -> surf::Result<bool> {
+        let mut req = self.new_request(Method::Put, "/v1/config").await?;
+        req.body_json(entry)?;
+        let mut res = self.send(req).await?;
+        let out: bool = read_json(&mut res).await?;
+        Ok(out)
+    }
+
+    /// config_entry_apply_service_defaults writes a `service-defaults` config entry.
+    pub async fn config_entry_apply_service_defaults(
+        &self,
+        sd: &ServiceDefaults,
+    ) -> surf::Result<bool> {
+        sd.validate()?;
+        let mut entry = sd.clone();
+        entry.Kind = Some(String::from(SERVICE_DEFAULTS));
+        self.config_entry_set(&entry).await
+    }
+
+    /// config_entry_apply_proxy_defaults writes the global `proxy-defaults`
+    /// config entry. Kind and Name are forced to `proxy-defaults` and `global`.
+    pub async fn config_entry_apply_proxy_defaults(
+        &self,
+        pd: &ProxyDefaults,
+    ) -> surf::Result<bool> {
+        pd.validate()?;
+        let mut entry = pd.clone();
+        entry.Kind = Some(String::from(PROXY_DEFAULTS));
+        entry.Name = Some(String::from(PROXY_CONFIG_GLOBAL));
+        self.config_entry_set(&entry).await
+    }
+
+    /// config_entry_apply_exported_services writes an `exported-services`
+    /// config entry. Its Name, the partition, defaults to `default`.
+    pub async fn config_entry_apply_exported_services(
+        &self,
+        es: &ExportedServices,
+    ) -> surf::Result<bool> {
+        es.validate()?;
+        let mut entry = es.clone();
+        entry.Kind = Some(String::from(EXPORTED_SERVICES));
+        if entry.Name.is_none() {
+            entry.Name = Some(String::from(EXPORTED_SERVICES_DEFAULT_PARTITION));
+        }
+        self.config_entry_set(&entry).await
+    }
+
+    /// connect_ca_get_config returns the configuration of the Connect CA
+    /// provider.
+    pub async fn connect_ca_get_config(&self) -> surf::Result<CAConfig> {
+        self.connect_ca_get_config_opts(&QueryOptions::default())
+            .await
+    }
+
+    pub async fn connect_ca_get_config_opts(&self, opts: &QueryOptions) -> surf::Result<CAConfig> {
+        let mut req = self
+            .new_request(Method::Get, "/v1/connect/ca/configuration")
+            .await?;
+        set_query_options(&mut req, opts)?;
+        let mut res = self.send(req).await?;
+        let out: CAConfig = read_json(&mut res).await?;
+        Ok(out)
+    }
+
+    /// connect_ca_set_config reconfigures the Connect CA, e.g. to rotate its
+    /// root or switch provider. It requires an operator:write token.
+    pub async fn connect_ca_set_config(&self, config: &CAConfig) -> surf::Result<StatusCode> {
+        self.connect_ca_set_config_opts(config, &WriteOptions::default())
+            .await
+    }
+
+    pub async fn connect_ca_set_config_opts(
+        &self,
+        config: &CAConfig,
+        opts: &WriteOptions,
+    ) -> surf::Result<StatusCode> {
+        let mut req = self
+            .new_request(Method::Put, "/v1/connect/ca/configuration")
+            .await?;
+        set_write_options(&mut req, opts);
+        req.body_json(config)?;
+        let res = self.send(req).await?;
+        Ok(res.status())
+    }
+
+    /// operator_usage returns the service and node counts of each datacenter.
+    /// It requires an operator:read token.
+    pub async fn operator_usage(&self) -> surf::Result<Usage> {
+        self.operator_usage_opts(&QueryOptions::default()).await
+    }
+
+    pub async fn operator_usage_opts(&self, opts: &QueryOptions) -> surf::Result<Usage> {
+        let mut req = self.new_request(Method::Get, "/v1/operator/usage").await?;
+        set_query_options(&mut req, opts)?;
+        let mut res = self.send(req).await?;
+        let out: Usage = read_json(&mut res).await?;
+        Ok(out)
+    }
+
+    /// keyring_list returns the gossip encryption keys installed in each
+    /// datacenter and how many nodes have each key. Requires a management token.
+    pub async fn keyring_list(&self) -> surf::Result<Vec<KeyringResponse>> {
+        self.keyring_list_opts(&QueryOptions::default()).await
+    }
+
+    pub async fn keyring_list_opts(
+        &self,
+        opts: &QueryOptions,
+    ) -> surf::Result<Vec<KeyringResponse>> {
+        let mut req = self
+            .new_request(Method::Get, "/v1/operator/keyring")
+            .await?;
+        set_query_options(&mut req, opts)?;
+        let mut res = self.send(req).await?;
+        let out: Vec<KeyringResponse> = read_json(&mut res).await?;
+        Ok(out)
+    }
+
+    /// keyring_install installs a new gossip encryption key into the cluster.
+    pub async fn keyring_install(&self, key: &str) -> surf::Result<StatusCode> {
+        self.keyring_install_opts(key, &QueryOptions::default())
+            .await
+    }
+
+    pub async fn keyring_install_opts(
+        &self,
+        key: &str,
+        opts: &QueryOptions,
+    ) -> surf::Result<StatusCode> {
+        self.keyring_write(Method::Post, key, opts).await
+    }
+
+    /// keyring_use changes the primary gossip encryption key used by the cluster.
+    pub async fn keyring_use(&self, key: &str) -> surf::Result<StatusCode> {
+        self.keyring_use_opts(key, &QueryOptions::default()).await
+    }
+
+    pub async fn keyring_use_opts(
+        &self,
+        key: &str,
+        opts: &QueryOptions,
+    ) -> surf::Result<StatusCode> {
+        self.keyring_write(Method::Put, key, opts).await
+    }
+
+    /// keyring_remove removes a gossip encryption key from the cluster.
+    pub async fn keyring_remove(&self, key: &str) -> surf::Result<StatusCode> {
+        self.keyring_remove_opts(key, &QueryOptions::default())
+            .await
+    }
+
+    pub async fn keyring_remove_opts(
+        &self,
+        key: &str,
+        opts: &QueryOptions,
+    ) -> surf::Result<StatusCode> {
+        self.keyring_write(Method::Delete, key, opts).await
+    }
+
+    async fn keyring_write(
+        &self,
+        method: Method,
+        key: &str,
+        opts: &QueryOptions,
+    ) -> surf::Result<StatusCode> {
+        let mut req = self.new_request(method, "/v1/operator/keyring").await?;
+        set_query_options(&mut req, opts)?;
+        req.body_json(&KeyringRequest {
+            Key: key.to_string(),
+        })?;
+        let res = self.send(req).await?;
+        Ok(res.status())
+    }
+
+    /// agent_connect_proxy_config returns the resolved configuration, including
+    /// upstreams, of a Connect proxy registered with the local agent.
+    pub async fn agent_connect_proxy_config(
+        &self,
+        proxy_service_id: &str,
+    ) -> surf::Result<ConnectProxyConfig> {
+        let (out, _) = self
+            .agent_connect_proxy_config_opts(proxy_service_id, &QueryOptions::default())
+            .await?;
+        Ok(out)
+    }
+
+    /// agent_connect_proxy_config_opts is agent_connect_proxy_config with
+    /// QueryOptions. Proxy config isn't stored in Raft, so blocking is done by
+    /// setting WaitHash to the LastContentHash of the previous response.
+    pub async fn agent_connect_proxy_config_opts(
+        &self,
+        proxy_service_id: &str,
+        opts: &QueryOptions,
+    ) -> surf::Result<(ConnectProxyConfig, QueryMeta)> {
+        let path = format!("/v1/agent/connect/proxy/{}", proxy_service_id);
+        let mut req = self.new_request(Method::Get, &path).await?;
+        set_query_options(&mut req, opts)?;
+        let mut res = self.send(req).await?;
+        let meta = parse_query_meta(&res);
+        let out: ConnectProxyConfig = read_json(&mut res).await?;
+        Ok((out, meta))
+    }
+
+    /// agent_service_blocking blocks for up to `wait` until the definition of
+    /// the local agent service `id` differs from `hash`, and returns it with its
+    /// new content hash to pass to the next call. An empty `hash` returns
+    /// immediately. Once the service is deregistered, the error carries
+    /// ConsulError::ServiceNotFound.
+    pub async fn agent_service_blocking(
+        &self,
+        id: &str,
+        hash: &str,
+        wait: time::Duration,
+    ) -> surf::Result<(AgentService, String)> {
+        let path = format!("/v1/agent/service/{}", id);
+        let mut req = self.new_request(Method::Get, &path).await?;
+        let mut opts = QueryOptions::default();
+        if !hash.is_empty() {
+            opts.WaitHash = Some(hash.to_string());
+            opts.WaitTime = Some(wait);
+        }
+        set_query_options(&mut req, &opts)?;
+        let mut res = self.send(req).await?;
+        let meta = parse_query_meta(&res);
+        let out: AgentService = read_json(&mut res).await?;
+        let hash = meta
+            .LastContentHash
+            .or_else(|| out.ContentHash.clone())
+            .unwrap_or_default();
+        Ok((out, hash))
+    }
+
+    /// catalog_register registers a node, and optionally a service and its
+    /// checks, in the catalog. Set SkipNodeUpdate to register against an
+    /// existing node without overwriting its address or metadata.
+    pub async fn catalog_register(
+        &self,
+        registration: &CatalogRegistration,
+        opts: &WriteOptions,
+    ) -> surf::Result<bool> {
+        let mut req = self
+            .new_request(Method::Put, "/v1/catalog/register")
+            .await?;
+        set_write_options(&mut req, opts);
+        req.body_json(registration)?;
+        let mut res = self.send(req).await?;
+        let out: bool = read_json(&mut res).await?;
+        Ok(out)
+    }
+
+    /// catalog_service returns every registered instance of a service,
+    /// regardless of health.
+    pub async fn catalog_service(
+        &self,
+        service: &str,
+        tag: Option<&str>,
+        opts: &QueryOptions,
+    ) -> surf::Result<Vec<CatalogService>> {
+        let (out, _) = self.catalog_service_meta(service, tag, opts).await?;
+        Ok(out)
+    }
+
+    /// catalog_service_blocking blocks for up to `wait` until the catalog
+    /// instances of a service change past `index`, and returns them with the
+    /// new index to pass to the next call. Unlike the health endpoint, it
+    /// reports registrations whatever their health. An `index` of 0 returns
+    /// immediately.
+    pub async fn catalog_service_blocking(
+        &self,
+        name: &str,
+        tag: Option<&str>,
+        dc: Option<&str>,
+        index: u64,
+        wait: time::Duration,
+    ) -> surf::Result<(Vec<CatalogService>, u64)> {
+        let mut opts = QueryOptions {
+            Datacenter: dc.map(String::from),
+            ..QueryOptions::default()
+        };
+        if index > 0 {
+            opts.WaitIndex = Some(index as usize);
+            opts.WaitTime = Some(wait);
+        }
+        let (out, meta) = self.catalog_service_meta(name, tag, &opts).await?;
+        Ok((out, meta.LastIndex))
+    }
+
+    async fn catalog_service_meta(
+        &self,
+        service: &str,
+        tag: Option<&str>,
+        opts: &QueryOptions,
+    ) -> surf::Result<(Vec<CatalogService>, QueryMeta)> {
+        let path = format!("/v1/catalog/service/{}", service);
+        let mut req = self.new_request(Method::Get, &path).await?;
+        set_query_options(&mut req, opts)?;
+        if let Some(tag) = tag {
+            let mut query: HashMap<&str, String> = HashMap::new();
+            query.insert("tag", tag.to_string());
+            merge_query(&mut req, &query);
+        }
+        let mut res = self.send(req).await?;
+        let meta = parse_query_meta(&res);
+        let out: Vec<CatalogService> = read_json(&mut res).await?;
+        Ok((out, meta))
+    }
+
+    /// ui_services returns every service of the datacenter with its tags,
+    /// nodes and check counts in a single call. It is the internal endpoint
+    /// of the Consul UI, which isn't covered by API compatibility guarantees.
+    pub async fn ui_services(&self, dc: Option<&str>) -> surf::Result<Vec<UiService>> {
+        let mut req = self
+            .new_request(Method::Get, "/v1/internal/ui/services")
+            .await?;
+        let opts = QueryOptions {
+            Datacenter: dc.map(String::from),
+            ..QueryOptions::default()
+        };
+        set_query_options(&mut req, &opts)?;
+        let mut res = self.send(req).await?;
+        let out: Vec<UiService> = read_json(&mut res).await?;
+        Ok(out)
+    }
+
+    /// health_checks returns the checks of every instance of a service.
+    pub async fn health_checks(
+        &self,
+        service: &str,
+        opts: &QueryOptions,
+    ) -> surf::Result<Vec<HealthCheck>> {
+        let path = format!("/v1/health/checks/{}", service);
+        let mut req = self.new_request(Method::Get, &path).await?;
+        set_query_options(&mut req, opts)?;
+        let mut res = self.send(req).await?;
+        let out: Vec<HealthCheck> = read_json(&mut res).await?;
+        Ok(out)
+    }
+
+    /// health_state returns the checks in `state` across the datacenter, e.g.
+    /// HEALTH_CRITICAL, or HEALTH_ANY for all of them.
+    pub async fn health_state(
+        &self,
+        state: &str,
+        opts: &QueryOptions,
+    ) -> surf::Result<Vec<HealthCheck>> {
+        let (out, _) = self.health_state_meta(state, opts).await?;
+        Ok(out)
+    }
+
+    /// health_state_blocking blocks for up to `wait` until the checks in
+    /// `state` change past `index`, and returns them with the new index to
+    /// pass to the next call. An `index` of 0 returns immediately.
+    pub async fn health_state_blocking(
+        &self,
+        state: &str,
+        dc: Option<&str>,
+        index: u64,
+        wait: time::Duration,
+    ) -> surf::Result<(Vec<HealthCheck>, u64)> {
+        let mut opts = QueryOptions {
+            Datacenter: dc.map(String::from),
+            ..QueryOptions::default()
+        };
+        if index > 0 {
+            opts.WaitIndex = Some(index as usize);
+            opts.WaitTime = Some(wait);
+        }
+        let (out, meta) = self.health_state_meta(state, &opts).await?;
+        Ok((out, meta.LastIndex))
+    }
+
+    async fn health_state_meta(
+        &self,
+        state: &str,
+        opts: &QueryOptions,
+    ) -> surf::Result<(Vec<HealthCheck>, QueryMeta)> {
+        let path = format!("/v1/health/state/{}", state);
+        let mut req = self.new_request(Method::Get, &path).await?;
+        set_query_options(&mut req, opts)?;
+        let mut res = self.send(req).await?;
+        let meta = parse_query_meta(&res);
+        let out: Vec<HealthCheck> = read_json(&mut res).await?;
+        Ok((out, meta))
+    }
+
+    /// catalog_service_with_health returns every registered instance of a
+    /// service in the datacenter together with its checks and aggregated
+    /// status, so unhealthy instances are listed rather than hidden.
+    pub async fn catalog_service_with_health(
+        &self,
+        name: &str,
+        tag: Option<&str>,
+        dc: Option<&str>,
+    ) -> surf::Result<Vec<CatalogServiceChecksInfo>> {
+        let opts = QueryOptions {
+            Datacenter: dc.map(String::from),
+            ..QueryOptions::default()
+        };
+        let services = self.catalog_service(name, tag, &opts).await?;
+        let checks = self.health_checks(name, &opts).await?;
+        let out = services
+            .into_iter()
+            .map(|service| {
+                let checks: Vec<HealthCheck> = checks
+                    .iter()
+                    .filter(|check| {
+                        check.Node == service.Node && check.ServiceID == service.ServiceID
+                    })
+                    .cloned()
+                    .collect();
+                let checks = HealthChecks(checks);
+                CatalogServiceChecksInfo {
+                    AggregatedStatus: checks.aggregated_status(),
+                    Service: service,
+                    Checks: checks,
+                }
+            })
+            .collect();
+        Ok(out)
+    }
+
+    /// prepared_query_execute executes a prepared query by ID or name. The
+    /// response reports the datacenter that served the results and how many
+    /// times the query failed over to a remote datacenter.
+    pub async fn prepared_query_execute(
+        &self,
+        query_id_or_name: &str,
+        opts: &QueryOptions,
+    ) -> surf::Result<PreparedQueryExecuteResponse> {
+        let path = format!("/v1/query/{}/execute", query_id_or_name);
+        let mut req = self.new_request(Method::Get, &path).await?;
+        set_query_options(&mut req, opts)?;
+        let mut res = self.send(req).await?;
+        let out: PreparedQueryExecuteResponse = read_json(&mut res).await?;
+        Ok(out)
+    }
+
+    pub async fn random_policy(&self, service_name: &str, tag: &str) -> surf::Result<String> {
+        let key = format!("{}{}", service_name, tag);
+        let services_addresses = SERVICES_ADDRESS.clone();
+        let services_addresses = services_addresses.read().await;
+        let service_addresses = services_addresses.get(&key);
+        if service_addresses.is_some() {
+            let service_addresses = service_addresses.unwrap();
+            let range = service_addresses.address.len();
+            if range == 0 {
+                return Err(Error::from_str(
+                    StatusCode::BadRequest,
+                    "consul server address is empty",
+                ));
+            };
+            let mut r = rand::thread_rng();
+            let idx: usize = r.gen_range(0..range);
+            let address = service_addresses.address.get(idx).unwrap();
+            return Ok(String::from(address));
         }
         Err(Error::from_str(
             StatusCode::BadRequest,
             "consul server address is empty",
         ))
     }
-}
+}
+
+/// entry_addresses returns the `address:port` of every service entry.
+fn entry_addresses(entries: &[ServiceEntry]) -> Vec<String> {
+    entries
+        .iter()
+        .filter_map(|entry| entry.Service.as_ref())
+        .filter_map(|service| match (service.Address.as_ref(), service.Port) {
+            (Some(address), Some(port)) => Some(format!("{}:{}", address, port)),
+            _ => None,
+        })
+        .collect()
+}
+
+/// merge_query adds the given parameters to the request's query string,
+/// replacing any parameters of the same name already set by new_request.
+fn merge_query(req: &mut surf::Request, query: &HashMap<&str, String>) {
+    let mut pairs: Vec<(String, String)> = req
+        .url()
+        .query_pairs()
+        .filter(|(key, _)| !query.contains_key(key.as_ref()))
+        .map(|(key, value)| (key.into_owned(), value.into_owned()))
+        .collect();
+    for (key, value) in query.iter() {
+        pairs.push((key.to_string(), value.to_string()));
+    }
+    let req: &mut surf::http::Request = req.as_mut();
+    let url = req.url_mut();
+    if pairs.is_empty() {
+        url.set_query(None);
+    } else {
+        url.query_pairs_mut().clear().extend_pairs(pairs);
+    }
+}
+
+/// set_query_options applies the QueryOptions to the request.
+fn set_query_options(req: &mut surf::Request, opts: &QueryOptions) -> surf::Result<()> {
+    let mut query: HashMap<&str, String> = HashMap::new();
+    if let Some(namespace) = opts.Namespace.as_ref() {
+        query.insert("ns", namespace.to_string());
+    }
+    if let Some(datacenter) = opts.Datacenter.as_ref() {
+        query.insert("dc", datacenter.to_string());
+    }
+    if let Some(near) = opts.Near.as_ref() {
+        query.insert("near", near.to_string());
+    }
+    if opts.Connect == Some(true) {
+        query.insert("connect", String::from("true"));
+    }
+    if let Some(relay_factor) = opts.RelayFactor {
+        if relay_factor > 5 {
+            return Err(Error::from_str(
+                StatusCode::BadRequest,
+                "relay factor must be from 0 to 5 (inclusive)",
+            ));
+        }
+        query.insert("relay-factor", relay_factor.to_string());
+    }
+    if opts.LocalOnly == Some(true) {
+        query.insert("local-only", String::from("true"));
+    }
+    if let Some(filter) = opts.Filter.as_ref() {
+        query.insert("filter", filter.to_string());
+    }
+    match (
+        opts.AllowStale == Some(true),
+        opts.RequireConsistent == Some(true),
+    ) {
+        (true, true) => {
+            return Err(Error::from_str(
+                StatusCode::BadRequest,
+                "AllowStale and RequireConsistent are mutually exclusive",
+            ));
+        }
+        (true, false) => {
+            query.insert("stale", String::new());
+        }
+        (false, true) => {
+            query.insert("consistent", String::new());
+        }
+        (false, false) => {}
+    }
+    if opts.UseCache == Some(true) {
+        query.insert("cached", String::new());
+        let mut cache_control = vec![];
+        if let Some(max_age) = opts.MaxAge.filter(|max_age| !max_age.is_zero()) {
+            cache_control.push(format!("max-age={}", max_age.as_secs()));
+        }
+        if let Some(stale) = opts.StaleIfError.filter(|stale| !stale.is_zero()) {
+            cache_control.push(format!("stale-if-error={}", stale.as_secs()));
+        }
+        if !cache_control.is_empty() {
+            req.set_header("Cache-Control", cache_control.join(", "));
+        }
+    }
+    if let Some(index) = opts.WaitIndex.filter(|index| *index > 0) {
+        query.insert("index", index.to_string());
+    }
+    if let Some(hash) = opts.WaitHash.as_ref() {
+        query.insert("hash", hash.to_string());
+    }
+    if let Some(wait) = opts.WaitTime {
+        query.insert("wait", format!("{}ms", wait.as_millis()));
+    }
+    if let Some(token) = opts.Token.as_ref() {
+        req.set_header("X-Consul-Token", token.as_str());
+    }
+    merge_query(req, &query);
+    Ok(())
+}
+
+/// set_write_options applies the WriteOptions to the request.
+fn set_write_options(req: &mut surf::Request, opts: &WriteOptions) {
+    let mut query: HashMap<&str, String> = HashMap::new();
+    if let Some(namespace) = opts.Namespace.as_ref() {
+        query.insert("ns", namespace.to_string());
+    }
+    if let Some(datacenter) = opts.Datacenter.as_ref() {
+        query.insert("dc", datacenter.to_string());
+    }
+    if let Some(token) = opts.Token.as_ref() {
+        req.set_header("X-Consul-Token", token.as_str());
+    }
+    if let Some(audit) = opts.Audit.as_ref() {
+        set_audit_meta(req, audit);
+    }
+    merge_query(req, &query);
+}
+
+/// set_audit_meta sends the AuditMeta as the X-Consul-Reason and
+/// X-Consul-Actor headers.
+fn set_audit_meta(req: &mut surf::Request, audit: &AuditMeta) {
+    if let Some(reason) = audit.Reason.as_ref() {
+        req.set_header("X-Consul-Reason", reason.as_str());
+    }
+    if let Some(actor) = audit.Actor.as_ref() {
+        req.set_header("X-Consul-Actor", actor.as_str());
+    }
+}
+
+/// node_filter builds the filter expression matching the entries on `node`.
+pub fn node_filter(node: &str) -> String {
+    format!(
+        "Node.Node == \"{}\"",
+        node.replace('\\', "\\\\").replace('"', "\\\"")
+    )
+}
+
+/// parse_query_meta reads the QueryMeta from the response headers.
+fn parse_query_meta(res: &surf::Response) -> QueryMeta {
+    let header = |name: &str| res.header(name).map(|value| value.as_str().to_string());
+    QueryMeta {
+        LastIndex: header("X-Consul-Index")
+            .and_then(|index| index.parse().ok())
+            .unwrap_or(0),
+        LastContentHash: header("X-Consul-ContentHash"),
+        KnownLeader: header("X-Consul-KnownLeader").map(|known| known == "true"),
+        LastContact: header("X-Consul-LastContact").and_then(|last| last.parse().ok()),
+        QueryBackend: header("X-Consul-Query-Backend"),
+        CacheHit: header("X-Cache").map(|cache| cache.eq_ignore_ascii_case("HIT")),
+        CacheAge: header("Age")
+            .and_then(|age| age.parse().ok())
+            .map(time::Duration::from_secs),
+    }
+}
+
+/// Config is used to configure the creation of a client
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[allow(non_snake_case)]
+pub struct Config {
+    /// Address is the address of the Consul server
+    pub address: Option<String>,
+
+    /// Scheme is the URI scheme for the Consul server
+    pub scheme: Option<String>,
+
+    /// Datacenter to use. If not provided, the default agent datacenter is used
+    /// and no `dc` is sent. See `auto_discover_datacenter`.
+    pub datacenter: Option<String>,
+
+    /// Transport is the Transport to use for the http client.
+    /// pub Transport: surf::Client,
+    /// HttpClient is the client to use. Default will be
+    /// used if not provided.
+    /// pub HttpClient: Option<surf::Client>,
+
+    /// HttpAuth is the auth info to use for http access.
+
+    /// pub HttpAuth: Option<http_types::auth::BasicAuth>,
+
+    /// WaitTime limits how long a Watch will block. If not provided,
+    /// the agent default values will be used.
+    pub wait_time: Option<GoDuration>,
+
+    /// Token is used to provide a per-request ACL token
+    /// which overrides the agent's default token.
+    pub token: Option<String>,
+
+    /// TokenFile is a file containing the current token to use for this client.
+    /// If provided it is read once at startup and never again.
+    pub token_file: Option<String>,
+
+    /// Namespace is the name of the namespace to send along for the request
+    /// when no other Namespace is present in the QueryOptions
+    pub namespace: Option<String>,
+
+    pub tls_config: Option<TLSConfig>,
+
+    /// Http2 enables HTTP/2 (ALPN `h2`) on TLS connections, falling back to
+    /// HTTP/1.1 when the server doesn't negotiate it. Requests then share
+    /// multiplexed connections, so blocking queries don't stall short reads.
+    /// Requires surf's default `curl-client` backend.
+    pub http2: Option<bool>,
+
+    /// RequestIdHeader is the header name (e.g. `X-Correlation-ID`) used to send
+    /// the correlation id set with `with_request_id` on every request.
+    pub request_id_header: Option<String>,
+
+    /// ExtraHeaders are added to every request, e.g. for an auth proxy in front
+    /// of Consul. They can be overridden per call with `with_headers`.
+    pub extra_headers: Option<HashMap<String, String>>,
+
+    /// StartupJitter is the maximum random delay, in milliseconds, applied once
+    /// before the first registration or watch query. Unset or 0 disables it.
+    pub startup_jitter: Option<u64>,
+
+    /// CircuitBreaker fails `resolve` fast for a service after repeated
+    /// failures. Unset disables it.
+    pub circuit_breaker: Option<CircuitBreakerConfig>,
+
+    /// MaxStale is how long, in seconds, cached addresses may still be served
+    /// by `resolve` when Consul can't be reached. Unset disables the fallback.
+    pub max_stale: Option<u64>,
+
+    /// RateLimitRetries is how many times a GET rate limited by Consul (429)
+    /// is retried before failing with ConsulError::RateLimited. Defaults to 2.
+    pub rate_limit_retries: Option<u32>,
+
+    /// SortAddresses sorts the cached addresses of a service by `host:port`,
+    /// so every client agrees on their order. Defaults to Consul's order.
+    pub sort_addresses: Option<bool>,
+
+    /// MaxResponseSize is the largest response body, in bytes, that is read.
+    /// A larger one fails with ConsulError::ResponseTooLarge. Unset disables
+    /// the limit. The agent_monitor stream isn't limited.
+    pub max_response_size: Option<u64>,
+
+    /// StreamingBackend makes watch_services rely on the streaming backend of
+    /// Consul 1.10+ agents (`use_streaming_backend`) for its blocking health
+    /// queries, replacing the cached addresses with each update. It falls back
+    /// to plain blocking queries when the agent doesn't stream.
+    pub streaming_backend: Option<bool>,
+}
+
+/// TLSConfig is used to generate a TLSClientConfig that's useful for talking to
+/// Consul using TLS.
+#[derive(Default, Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[allow(non_snake_case)]
+pub struct TLSConfig {
+    /// Address is the optional address of the Consul server. The port, if any
+    /// will be removed from here and this will be set to the ServerName of the
+    /// resulting config.
+    pub address: Option<String>,
+
+    /// CAFile is the optional path to the CA certificate used for Consul
+    /// communication, defaults to the system bundle if not specified.
+    pub ca_file: Option<String>,
+
+    /// CAPath is the optional path to a directory of CA certificates to use for
+    /// Consul communication, defaults to the system bundle if not specified.
+    pub ca_path: Option<String>,
+
+    /// CAPem is the optional PEM-encoded CA certificate used for Consul
+    /// communication, defaults to the system bundle if not specified.
+    pub ca_pem: Option<String>,
+
+    /// CertFile is the optional path to the certificate for Consul
+    /// communication. If this is set then you need to also set KeyFile.
+    pub cert_file: Option<String>,
+
+    /// CertPEM is the optional PEM-encoded certificate for Consul
+    /// communication. If this is set then you need to also set KeyPEM.
+    pub cert_pem: Option<String>,
+
+    /// KeyFile is the optional path to the private key for Consul communication.
+    /// If this is set then you need to also set CertFile.
+    pub key_file: Option<String>,
+
+    /// KeyPEM is the optional PEM-encoded private key for Consul communication.
+    /// If this is set then you need to also set CertPEM.
+    pub key_pem: Option<String>,
+
+    /// InsecureSkipVerify if set to true will disable TLS host verification.
+    pub insecure_skip_verify: Option<bool>,
+}
+
+/// NAMESPACE_WILDCARD as the QueryOptions Namespace lists across every
+/// namespace. Each result then carries its own Namespace.
+pub const NAMESPACE_WILDCARD: &str = "*";
+
+/// QueryOptions are used to parameterize a query
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[allow(non_snake_case)]
+pub struct QueryOptions {
+    /// Namespace overrides the `default` namespace
+    /// Note: Namespaces are available only in Consul Enterprise
+    /// Listings accept NAMESPACE_WILDCARD to span every namespace.
+    pub Namespace: Option<String>,
+
+    /// Providing a datacenter overwrites the DC provided
+    /// by the Config
+    pub Datacenter: Option<String>,
+
+    /// AllowStale allows any Consul server (non-leader) to service
+    /// a read. This allows for lower latency and higher throughput
+    pub AllowStale: Option<bool>,
+
+    /// RequireConsistent forces the read to be fully consistent.
+    /// This is more expensive but prevents ever performing a stale
+    /// read.
+    pub RequireConsistent: Option<bool>,
+
+    /// UseCache requests that the agent cache results locally. See
+    /// https:///www.consul.io/api/features/caching.html for more details on the
+    /// semantics.
+    ///
+    /// catalog_service is served by background refresh caching, which returns
+    /// at once and refreshes asynchronously; prepared_query_execute uses
+    /// simple caching. QueryMeta.CacheHit and CacheAge report how the
+    /// response was served.
+    pub UseCache: Option<bool>,
+
+    /// MaxAge limits how old a cached value will be returned if UseCache is true.
+    /// If there is a cached response that is older than the MaxAge, it is treated
+    /// as a cache miss and a new fetch invoked. If the fetch fails, the error is
+    /// returned. Clients that wish to allow for stale results on error can set
+    /// StaleIfError to a longer duration to change this behavior. It is ignored
+    /// if the endpoint supports background refresh caching. See
+    /// https:///www.consul.io/api/features/caching.html for more details.
+    pub MaxAge: Option<time::Duration>,
+
+    /// StaleIfError specifies how stale the client will accept a cached response
+    /// if the servers are unavailable to fetch a fresh one. Only makes sense when
+    /// UseCache is true and MaxAge is set to a lower, non-zero value. It is
+    /// ignored if the endpoint supports background refresh caching. See
+    /// https:///www.consul.io/api/features/caching.html for more details.
+    pub StaleIfError: Option<time::Duration>,
+
+    /// WaitIndex is used to enable a blocking query. Waits
+    /// until the timeout or the next index is reached
+    pub WaitIndex: Option<usize>,
+
+    /// WaitHash is used by some endpoints instead of WaitIndex to perform blocking
+    /// on state based on a hash of the response rather than a monotonic index.
+    /// This is required when the state being blocked on is not stored in Raft, for
+    /// example agent-local proxy configuration.
+    pub WaitHash: Option<String>,
+
+    /// WaitTime is used to bound the duration of a wait.
+    /// Defaults to that of the Config, but can be overridden.
+    pub WaitTime: Option<time::Duration>,
+
+    /// Token is used to provide a per-request ACL token
+    /// which overrides the agent's default token.
+    pub Token: Option<String>,
+
+    /// Near is used to provide a node name that will sort the results
+    /// in ascending order based on the estimated round trip time from
+    /// that node. Setting this to "_agent" will use the agent's node
+    /// for the sort.
+    pub Near: Option<String>,
+
+    /// NodeMeta is used to filter results by nodes with the given
+    /// metadata key/value pairs. Currently, only one key/value pair can
+    /// be provided for filtering.
+    pub NodeMeta: Option<HashMap<String, String>>,
+
+    /// RelayFactor is used in keyring operations to cause responses to be
+    /// relayed back to the sender through N other random nodes. Must be
+    /// a value from 0 to 5 (inclusive).
+    pub RelayFactor: Option<u8>,
+
+    /// LocalOnly is used in keyring list operation to force the keyring
+    /// query to only hit local servers (no WAN traffic).
+    pub LocalOnly: Option<bool>,
+
+    /// Connect filters prepared query execution to only include Connect-capable
+    /// services. This currently affects prepared query execution.
+    pub Connect: Option<bool>,
+
+    /// Filter requests filtering data prior to it being returned. The string
+    /// is a go-bexpr compatible expression.
+    pub Filter: Option<String>,
+}
+
+/// WriteOptions are used to parameterize a write
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[allow(non_snake_case)]
+pub struct WriteOptions {
+    /// Namespace overrides the `default` namespace
+    /// Note: Namespaces are available only in Consul Enterprise
+    pub Namespace: Option<String>,
+
+    /// Providing a datacenter overwrites the DC provided
+    /// by the Config
+    pub Datacenter: Option<String>,
+
+    /// Token is used to provide a per-request ACL token
+    /// which overrides the agent's default token.
+    pub Token: Option<String>,
+
+    /// Audit is recorded by the audit log along with the write.
+    pub Audit: Option<AuditMeta>,
+}
+
+/// AuditMeta is the metadata of a write recorded by the Enterprise audit log.
+/// It is sent as headers, which OSS agents ignore.
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[allow(non_snake_case)]
+pub struct AuditMeta {
+    /// Reason explains why the write was made.
+    pub Reason: Option<String>,
+
+    /// Actor is who, or what, made the write.
+    pub Actor: Option<String>,
+}
+
+/// QueryMeta is used to return meta data about a query
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[allow(non_snake_case)]
+pub struct QueryMeta {
+    /// LastIndex. This can be used as a WaitIndex to perform
+    /// a blocking query
+    pub LastIndex: u64,
+
+    /// LastContentHash. This can be used as a WaitHash to perform a blocking query
+    /// for endpoints that support hash-based blocking. Endpoints that do not
+    /// support it will return an empty hash.
+    pub LastContentHash: Option<String>,
+
+    /// Is there a known leader
+    pub KnownLeader: Option<bool>,
+
+    /// Time of last contact from the leader for the
+    /// server servicing the request, in milliseconds
+    pub LastContact: Option<u64>,
+
+    /// QueryBackend is the backend that served a blocking query,
+    /// `streaming` or `blocking-query`. Agents before 1.10 don't report it.
+    pub QueryBackend: Option<String>,
+
+    /// CacheHit is set for a UseCache query served from the agent cache,
+    /// from the `X-Cache` header. It is None when the cache wasn't used.
+    pub CacheHit: Option<bool>,
+
+    /// CacheAge is how long ago the cached response was fetched from the
+    /// servers, from the `Age` header. With background refresh it is the
+    /// time since the last refresh.
+    pub CacheAge: Option<time::Duration>,
+}
+
+/// QUERY_BACKEND_STREAMING is the QueryBackend of the streaming backend.
+pub const QUERY_BACKEND_STREAMING: &str = "streaming";
+
+/// VERIFY_TIMEOUT bounds each attempt of the VerifyBeforeRegister check.
+const VERIFY_TIMEOUT: time::Duration = time::Duration::from_secs(2);
+
+/// raw_body decodes the JSON body of the response, or null if it is empty.
+async fn raw_body(mut res: surf::Response) -> surf::Result<serde_json::Value> {
+    let body = res.body_bytes().await?;
+    if body.iter().all(|byte| byte.is_ascii_whitespace()) {
+        return Ok(serde_json::Value::Null);
+    }
+    serde_json::from_slice(&body).map_err(|err| invalid_response(&res, &body, err))
+}
+
+/// BODY_SNIPPET_LEN caps the characters of the body kept by
+/// ConsulError::InvalidResponse.
+const BODY_SNIPPET_LEN: usize = 200;
+
+/// read_json decodes the JSON body of the response, failing with
+/// ConsulError::InvalidResponse if it isn't the expected JSON.
+async fn read_json<T: serde::de::DeserializeOwned>(res: &mut surf::Response) -> surf::Result<T> {
+    let body = res.body_bytes().await?;
+    serde_json::from_slice(&body).map_err(|err| invalid_response(res, &body, err))
+}
+
+/// invalid_response describes a body that failed to decode, so a response
+/// from something other than Consul, such as a proxy, can be told apart.
+fn invalid_response(res: &surf::Response, body: &[u8], err: serde_json::Error) -> Error {
+    let body = String::from_utf8_lossy(body);
+    let body = body.trim();
+    let mut snippet: String = body.chars().take(BODY_SNIPPET_LEN).collect();
+    if snippet.len() < body.len() {
+        snippet.push_str("...");
+    }
+    Error::new(
+        StatusCode::BadGateway,
+        ConsulError::InvalidResponse {
+            status: res.status() as u16,
+            content_type: res.content_type().map(|mime| mime.to_string()),
+            body: snippet,
+            message: err.to_string(),
+        },
+    )
+}
+
+/// join_url joins the address and the path with exactly one slash, whether
+/// or not the address ends with one or the path starts with one.
+fn join_url(address: &str, path: &str) -> String {
+    format!(
+        "{}/{}",
+        address.trim_end_matches('/'),
+        path.trim_start_matches('/')
+    )
+}
+
+/// shorten_wait lowers the wait of a blocking query so the response, which
+/// Consul delays by up to a sixteenth of the wait, comes back within
+/// `remaining`. A blocking query without a wait, which Consul would hold for
+/// its default of five minutes, is given one. The wait is at least 1ms, as
+/// `wait=0ms` would be the default again.
+fn shorten_wait(req: &mut surf::Request, remaining: time::Duration) {
+    let query: HashMap<String, String> = req.url().query_pairs().into_owned().collect();
+    let max = std::cmp::max(remaining - remaining / 16, time::Duration::from_millis(1));
+    let shorten = match query.get("wait") {
+        Some(wait) => parse_go_duration(wait).is_ok_and(|wait| wait > max),
+        None => query.get("index").is_some_and(|index| index != "0"),
+    };
+    if shorten {
+        let mut query: HashMap<&str, String> = HashMap::new();
+        query.insert("wait", format!("{}ms", max.as_millis()));
+        merge_query(req, &query);
+    }
+}
+
+/// hostname returns the name of the local host, or `localhost` if unknown.
+pub(crate) fn hostname() -> String {
+    std::env::var("HOSTNAME")
+        .ok()
+        .or_else(|| std::fs::read_to_string("/proc/sys/kernel/hostname").ok())
+        .or_else(|| std::fs::read_to_string("/etc/hostname").ok())
+        .map(|hostname| hostname.trim().to_string())
+        .filter(|hostname| !hostname.is_empty())
+        .unwrap_or_else(|| String::from("localhost"))
+}
+
+/// limit_body buffers the body of the response, failing with
+/// ConsulError::ResponseTooLarge past `limit` bytes.
+async fn limit_body(mut res: surf::Response, limit: u64) -> surf::Result<surf::Response> {
+    let too_large = || {
+        Error::new(
+            StatusCode::InternalServerError,
+            ConsulError::ResponseTooLarge { limit },
+        )
+    };
+    if res.len().is_some_and(|len| len as u64 > limit) {
+        return Err(too_large());
+    }
+    let mut body = vec![];
+    res.take_body()
+        .into_reader()
+        .take(limit + 1)
+        .read_to_end(&mut body)
+        .await?;
+    if body.len() as u64 > limit {
+        return Err(too_large());
+    }
+    res.set_body(body);
+    Ok(res)
+}
+
+/// error_body reads up to `limit` bytes of the body of an error response,
+/// dropping the rest.
+async fn error_body(res: &mut surf::Response, limit: u64) -> String {
+    let mut body = vec![];
+    let _ = res
+        .take_body()
+        .into_reader()
+        .take(limit)
+        .read_to_end(&mut body)
+        .await;
+    String::from_utf8_lossy(&body).to_string()
+}
+
+/// succeeds_within reports whether `fut` completes successfully within `timeout`.
+async fn succeeds_within<T, E, F>(timeout: time::Duration, fut: F) -> bool
+where
+    F: Future<Output = Result<T, E>>,
+{
+    matches!(async_std::future::timeout(timeout, fut).await, Ok(Ok(_)))
+}
+
+/// MAX_ERROR_BODY_SIZE is how much of an error response body is read when
+/// `max_response_size` isn't set.
+const MAX_ERROR_BODY_SIZE: u64 = 64 * 1024;
+/// DEFAULT_RATE_LIMIT_RETRIES is how many times a rate-limited GET is retried
+/// when `rate_limit_retries` isn't set.
+const DEFAULT_RATE_LIMIT_RETRIES: u32 = 2;
+/// LOCK_RETRY_TIME is how often kv_lock_blocking retries a free key during a
+/// lock-delay whose length is unknown.
+const LOCK_RETRY_TIME: time::Duration = time::Duration::from_secs(1);
+/// RECONNECT_MIN_BACKOFF is the first delay before a stream or watch reconnects.
+const RECONNECT_MIN_BACKOFF: time::Duration = time::Duration::from_millis(200);
+/// RECONNECT_MAX_BACKOFF caps the delay between reconnection attempts.
+const RECONNECT_MAX_BACKOFF: time::Duration = time::Duration::from_secs(30);
+
+fn next_backoff(backoff: time::Duration) -> time::Duration {
+    std::cmp::min(backoff * 2, RECONNECT_MAX_BACKOFF)
+}
+
+/// MonitorEvent is an item of agent_monitor_reconnect.
+#[derive(Debug, Clone, PartialEq)]
+pub enum MonitorEvent {
+    /// Line is a log line of the agent.
+    Line(String),
+    /// Reconnecting reports why the log stream is being reopened.
+    Reconnecting(String),
+}
+
+type LogLines = std::pin::Pin<Box<dyn Stream<Item = surf::Result<String>> + Send>>;
+
+struct Monitor {
+    config: ConsulConfig,
+    log_level: String,
+    lines: Option<LogLines>,
+    backoff: time::Duration,
+    reconnecting: bool,
+}
+
+impl Monitor {
+    async fn next(&mut self) -> MonitorEvent {
+        if self.lines.is_none() {
+            if self.reconnecting {
+                task::sleep(self.backoff).await;
+                self.backoff = next_backoff(self.backoff);
+            }
+            match self.config.agent_monitor(&self.log_level).await {
+                Ok(lines) => self.lines = Some(Box::pin(lines)),
+                Err(err) => return self.reconnect(err.to_string()),
+            }
+        }
+        match self.lines.as_mut().unwrap().next().await {
+            Some(Ok(line)) => {
+                self.backoff = RECONNECT_MIN_BACKOFF;
+                MonitorEvent::Line(line)
+            }
+            Some(Err(err)) => self.reconnect(err.to_string()),
+            None => self.reconnect(String::from("log stream closed")),
+        }
+    }
+
+    fn reconnect(&mut self, reason: String) -> MonitorEvent {
+        log::warn!(
+            "consul monitor reconnecting in {:?}: {}",
+            self.backoff,
+            reason
+        );
+        self.lines = None;
+        self.reconnecting = true;
+        MonitorEvent::Reconnecting(reason)
+    }
+}
+
+type MonitorFuture = std::pin::Pin<Box<dyn Future<Output = (Monitor, MonitorEvent)> + Send>>;
+
+struct MonitorStream {
+    monitor: Option<Monitor>,
+    pending: Option<MonitorFuture>,
+}
+
+impl Stream for MonitorStream {
+    type Item = MonitorEvent;
+
+    fn poll_next(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Option<MonitorEvent>> {
+        let this = self.get_mut();
+        if this.pending.is_none() {
+            let mut monitor = this.monitor.take().unwrap();
+            this.pending = Some(Box::pin(async move {
+                let event = monitor.next().await;
+                (monitor, event)
+            }));
+        }
+        match this.pending.as_mut().unwrap().as_mut().poll(cx) {
+            std::task::Poll::Ready((monitor, event)) => {
+                this.pending = None;
+                this.monitor = Some(monitor);
+                std::task::Poll::Ready(Some(event))
+            }
+            std::task::Poll::Pending => std::task::Poll::Pending,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::api::{Config, ConsulConfig};
+    use std::collections::HashMap;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+
+    /// MockRequest is a request received by the mock Consul server.
+    #[derive(Debug, Clone, Default)]
+    pub struct MockRequest {
+        pub method: String,
+        pub path: String,
+        pub query: HashMap<String, String>,
+        pub headers: HashMap<String, String>,
+        pub body: String,
+    }
+
+    /// MockResponse is the reply the mock Consul server sends back.
+    #[derive(Debug, Clone, Default)]
+    pub struct MockResponse {
+        pub status: u16,
+        pub headers: Vec<(String, String)>,
+        pub body: String,
+    }
+
+    impl MockResponse {
+        pub fn new(status: u16, body: &str) -> Self {
+            MockResponse {
+                status,
+                headers: vec![],
+                body: body.to_string(),
+            }
+        }
+    }
+
+    /// mock_server starts an HTTP server answering every request with
+    /// `handler`, and returns its address along with the requests received.
+    pub fn mock_server<F>(handler: F) -> (String, Arc<Mutex<Vec<MockRequest>>>)
+    where
+        F: Fn(&MockRequest) -> MockResponse + Send + Sync + 'static,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = format!("http://{}", listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(vec![]));
+        let received = requests.clone();
+        let handler = Arc::new(handler);
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = match stream {
+                    Ok(stream) => stream,
+                    Err(_) => continue,
+                };
+                let handler = handler.clone();
+                let received = received.clone();
+                // each connection is served on its own thread, so a blocking
+                // query doesn't hold up the other requests.
+                thread::spawn(move || {
+                    let mut reader = BufReader::new(stream.try_clone().unwrap());
+                    let mut line = String::new();
+                    if reader.read_line(&mut line).is_err() {
+                        return;
+                    }
+                    let mut parts = line.split_whitespace();
+                    let method = parts.next().unwrap_or_default().to_string();
+                    let target = parts.next().unwrap_or_default().to_string();
+                    let url = surf::Url::parse(&format!("http://localhost{}", target)).unwrap();
+                    let mut request = MockRequest {
+                        method,
+                        path: url.path().to_string(),
+                        query: url.query_pairs().into_owned().collect(),
+                        ..MockRequest::default()
+                    };
+                    loop {
+                        let mut header = String::new();
+                        if reader.read_line(&mut header).is_err() || header.trim().is_empty() {
+                            break;
+                        }
+                        if let Some((name, value)) = header.split_once(':') {
+                            request
+                                .headers
+                                .insert(name.trim().to_lowercase(), value.trim().to_string());
+                        }
+                    }
+                    if request.headers.get("expect").map(String::as_str) == Some("100-continue") {
+                        let _ = stream.write_all(b"HTTP/1.1 100 Continue\r\n\r\n");
+                    }
+                    let length: usize = request
+                        .headers
+                        .get("content-length")
+                        .and_then(|length| length.parse().ok())
+                        .unwrap_or(0);
+                    let mut body = vec![0; length];
+                    let _ = reader.read_exact(&mut body);
+                    request.body = String::from_utf8_lossy(&body).to_string();
+                    received.lock().unwrap().push(request.clone());
+                    let response = handler(&request);
+                    let mut out = format!(
+                        "HTTP/1.1 {} Mock\r\nContent-Length: {}\r\nConnection: close\r\n",
+                        response.status,
+                        response.body.len()
+                    );
+                    for (name, value) in response.headers.iter() {
+                        out.push_str(&format!("{}: {}\r\n", name, value));
+                    }
+                    out.push_str("\r\n");
+                    out.push_str(&response.body);
+                    let _ = stream.write_all(out.as_bytes());
+                });
+            }
+        });
+        (address, requests)
+    }
+
+    /// mock_consul returns a client pointed at a mock server.
+    pub fn mock_consul(address: &str) -> ConsulConfig {
+        let mut config = Config::default();
+        config.address = Some(address.to_string());
+        ConsulConfig {
+            config: Some(config),
+            watch_services: None,
+        }
+    }
+
+    #[test]
+    fn test_request_id_header() {
+        use crate::agent::AgentServiceRegistration;
+        use crate::api::with_request_id;
+        use async_std::task::block_on;
+        let (address, requests) = mock_server(|_| MockResponse::new(200, ""));
+        let mut consul = mock_consul(&address);
+        consul.config.as_mut().unwrap().request_id_header = Some(String::from("X-Correlation-ID"));
+        let mut service = AgentServiceRegistration::default();
+        service.ID = Some(String::from("web-1"));
+        service.Name = Some(String::from("web"));
+        block_on(with_request_id("req-42", consul.service_register(&service))).unwrap();
+        block_on(consul.service_register(&service)).unwrap();
+        let requests = requests.lock().unwrap();
+        assert_eq!(
+            requests[0].headers.get("x-correlation-id").unwrap(),
+            "req-42"
+        );
+        assert!(requests[1].headers.get("x-correlation-id").is_none());
+    }
+
+    #[test]
+    fn test_request_id_scoped() {
+        use crate::api::{with_request_id, REQUEST_ID};
+        use async_std::future::{pending, poll_fn, timeout};
+        use async_std::task::{block_on, yield_now, Poll};
+        use std::future::Future;
+        use std::time::Duration;
+        let current = || REQUEST_ID.with(|id| id.borrow().clone());
+        let (a, b) = block_on(async {
+            let mut a = Box::pin(with_request_id("req-a", async {
+                yield_now().await;
+                current()
+            }));
+            let mut b = Box::pin(with_request_id("req-b", async {
+                yield_now().await;
+                current()
+            }));
+            let (mut out_a, mut out_b) = (None, None);
+            poll_fn(|cx| {
+                if out_a.is_none() {
+                    if let Poll::Ready(out) = a.as_mut().poll(cx) {
+                        out_a = Some(out);
+                    }
+                }
+                if out_b.is_none() {
+                    if let Poll::Ready(out) = b.as_mut().poll(cx) {
+                        out_b = Some(out);
+                    }
+                }
+                match (out_a.is_some(), out_b.is_some()) {
+                    (true, true) => Poll::Ready(()),
+                    _ => Poll::Pending,
+                }
+            })
+            .await;
+            (out_a.unwrap(), out_b.unwrap())
+        });
+        assert_eq!(a.as_deref(), Some("req-a"));
+        assert_eq!(b.as_deref(), Some("req-b"));
+
+        let leaked = block_on(async {
+            let cancelled = with_request_id("req-c", pending::<()>());
+            assert!(timeout(Duration::from_millis(10), cancelled).await.is_err());
+            current()
+        });
+        assert_eq!(leaked, None);
+    }
+
+    #[test]
+    fn test_resolve_stale_fallback() {
+        use crate::api::SERVICES_ADDRESS;
+        use crate::health::ServiceAddress;
+        use crate::watch::WatchService;
+        use async_std::task::block_on;
+        use std::time::Instant;
+        let closed = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = format!("http://{}", closed.local_addr().unwrap());
+        drop(closed);
+        let mut consul = mock_consul(&address);
+        let mut service = WatchService::default();
+        service.service_name = String::from("stale_web");
+        {
+            let mut services_addresses = block_on(SERVICES_ADDRESS.write());
+            services_addresses.insert(
+                String::from("stale_web"),
+                ServiceAddress {
+                    address: vec![String::from("10.0.0.1:80")],
+                    last_update: Some(Instant::now()),
+                    ..ServiceAddress::default()
+                },
+            );
+        }
+        assert!(block_on(consul.resolve(&service)).is_err());
+        consul.config.as_mut().unwrap().max_stale = Some(30);
+        let resolved = block_on(consul.resolve(&service)).unwrap();
+        assert!(resolved.stale);
+        assert_eq!(resolved.address, vec!["10.0.0.1:80"]);
+    }
+
+    #[test]
+    fn test_prepared_query_execute() {
+        use crate::api::QueryOptions;
+        use async_std::task::block_on;
+        let (address, requests) = mock_server(|_| {
+            MockResponse::new(
+                200,
+                r#"{"Service":"web","Nodes":[{"Service":{"Address":"10.0.2.1","Port":80}}],"DNS":{"TTL":"10s"},"Datacenter":"dc2","Failovers":1}"#,
+            )
+        });
+        let consul = mock_consul(&address);
+        let mut opts = QueryOptions::default();
+        opts.Near = Some(String::from("_agent"));
+        let res = block_on(consul.prepared_query_execute("web-query", &opts)).unwrap();
+        assert_eq!(res.Datacenter.as_deref(), Some("dc2"));
+        assert_eq!(res.Failovers, Some(1));
+        assert_eq!(res.Nodes.unwrap().len(), 1);
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests[0].path, "/v1/query/web-query/execute");
+        assert_eq!(requests[0].query.get("near").unwrap(), "_agent");
+    }
+
+    #[test]
+    fn test_agent_monitor() {
+        use async_std::stream::StreamExt;
+        use async_std::task::block_on;
+        let (address, requests) =
+            mock_server(|_| MockResponse::new(200, "[DEBUG] agent: first\n[INFO] agent: second\n"));
+        let consul = mock_consul(&address);
+        let lines = block_on(async {
+            let mut stream = Box::pin(consul.agent_monitor("debug").await.unwrap());
+            let mut lines = vec![];
+            while let Some(line) = stream.next().await {
+                lines.push(line.unwrap());
+            }
+            lines
+        });
+        assert_eq!(lines, vec!["[DEBUG] agent: first", "[INFO] agent: second"]);
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests[0].query.get("loglevel").unwrap(), "debug");
+    }
+
+    #[test]
+    fn test_agent_monitor_reconnect() {
+        use crate::api::MonitorEvent;
+        use async_std::stream::StreamExt;
+        use async_std::task::block_on;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        let connections = AtomicUsize::new(0);
+        let (address, _) = mock_server(move |_| match connections.fetch_add(1, Ordering::SeqCst) {
+            0 => MockResponse::new(500, "agent restarting"),
+            1 => MockResponse::new(200, "[INFO] agent: first\n"),
+            _ => MockResponse::new(200, "[INFO] agent: second\n"),
+        });
+        let consul = mock_consul(&address);
+        let events = block_on(async {
+            let mut stream = Box::pin(consul.agent_monitor_reconnect("info"));
+            let mut events = vec![];
+            for _ in 0..4 {
+                events.push(stream.next().await.unwrap());
+            }
+            events
+        });
+        assert!(matches!(events[0], MonitorEvent::Reconnecting(_)));
+        assert_eq!(
+            events[1],
+            MonitorEvent::Line(String::from("[INFO] agent: first"))
+        );
+        assert!(matches!(events[2], MonitorEvent::Reconnecting(_)));
+        assert_eq!(
+            events[3],
+            MonitorEvent::Line(String::from("[INFO] agent: second"))
+        );
+    }
+
+    #[test]
+    fn test_watch_services_reconnect() {
+        use crate::api::SERVICES_ADDRESS;
+        use crate::watch::WatchService;
+        use async_std::task::block_on;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::time::Duration;
+        let attempts = AtomicUsize::new(0);
+        let (address, requests) = mock_server(move |_| {
+            if attempts.fetch_add(1, Ordering::SeqCst) == 0 {
+                return MockResponse::new(500, "No cluster leader");
+            }
+            let mut res = MockResponse::new(
+                200,
+                r#"[{"Service":{"Address":"10.0.3.1","Port":80,"ModifyIndex":7}}]"#,
+            );
+            res.headers
+                .push((String::from("X-Consul-Index"), String::from("7")));
+            res
+        });
+        let mut consul = mock_consul(&address);
+        let mut service = WatchService::default();
+        service.service_name = String::from("reconnect_web");
+        consul.watch_services = Some(vec![service]);
+        let _ = block_on(async_std::future::timeout(
+            Duration::from_secs(1),
+            consul.watch_services_self(),
+        ));
+        let services_addresses = block_on(SERVICES_ADDRESS.read());
+        let service_address = services_addresses.get("reconnect_web").unwrap();
+        assert_eq!(service_address.address, vec!["10.0.3.1:80"]);
+        let requests = requests.lock().unwrap();
+        assert!(requests.len() > 2);
+        assert_eq!(requests.last().unwrap().query.get("index").unwrap(), "7");
+    }
+
+    #[test]
+    fn test_health_service_passing_without_wait() {
+        use crate::api::QueryOptions;
+        use crate::watch::WatchService;
+        use async_std::task::block_on;
+        let (address, requests) = mock_server(|_| MockResponse::new(200, "[]"));
+        let consul = mock_consul(&address);
+        let mut service = WatchService::default();
+        service.service_name = String::from("web");
+        service.passing_only = Some(true);
+        block_on(consul.health_service(&service, &QueryOptions::default())).unwrap();
+        block_on(consul.health_service(
+            &service,
+            &QueryOptions {
+                WaitIndex: Some(3),
+                ..QueryOptions::default()
+            },
+        ))
+        .unwrap();
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests[0].query.get("passing").unwrap(), "1");
+        assert!(requests[0].query.get("wait").is_none());
+        assert_eq!(requests[1].query.get("passing").unwrap(), "1");
+        assert!(requests[1].query.get("wait").is_some());
+    }
 
-/// Config is used to configure the creation of a client
-#[derive(Default, Debug, Clone, Serialize, Deserialize)]
-#[allow(non_snake_case)]
-pub struct Config {
-    /// Address is the address of the Consul server
-    pub address: Option<String>,
+    #[test]
+    fn test_health_service_warning_ok() {
+        use crate::api::QueryOptions;
+        use crate::watch::WatchService;
+        use async_std::task::block_on;
+        let (address, requests) = mock_server(|_| {
+            MockResponse::new(
+                200,
+                r#"[{"Service":{"ID":"web-1","Address":"10.0.7.1","Port":80},"Checks":[{"CheckID":"serfHealth","Status":"passing"}]},{"Service":{"ID":"web-2","Address":"10.0.7.2","Port":80},"Checks":[{"CheckID":"service:web-2","Status":"warning"}]},{"Service":{"ID":"web-3","Address":"10.0.7.3","Port":80},"Checks":[{"CheckID":"service:web-3","Status":"critical"}]}]"#,
+            )
+        });
+        let consul = mock_consul(&address);
+        let mut service = WatchService::default();
+        service.service_name = String::from("web");
+        service.passing_only = Some(true);
+        service.warning_ok = Some(true);
+        let (_, entries) =
+            block_on(consul.health_service(&service, &QueryOptions::default())).unwrap();
+        let ids: Vec<_> = entries
+            .iter()
+            .map(|entry| entry.Service.as_ref().unwrap().ID.clone().unwrap())
+            .collect();
+        assert_eq!(ids, vec!["web-1", "web-2"]);
+        {
+            let requests = requests.lock().unwrap();
+            assert!(requests[0].query.get("passing").is_none());
+        }
 
-    /// Scheme is the URI scheme for the Consul server
-    pub scheme: Option<String>,
+        service.warning_ok = None;
+        block_on(consul.health_service(&service, &QueryOptions::default())).unwrap();
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests[1].query.get("passing").unwrap(), "1");
+    }
 
-    /// Datacenter to use. If not provided, the default agent datacenter is used.
-    pub datacenter: Option<String>,
+    #[test]
+    fn test_health_service_node_filter() {
+        use crate::api::node_filter;
+        use crate::api::QueryOptions;
+        use crate::watch::WatchService;
+        use async_std::task::block_on;
+        assert_eq!(node_filter("host1"), r#"Node.Node == "host1""#);
+        assert_eq!(node_filter(r#"a"b"#), r#"Node.Node == "a\"b""#);
+        let (address, requests) = mock_server(|_| MockResponse::new(200, "[]"));
+        let consul = mock_consul(&address);
+        let mut service = WatchService::default();
+        service.service_name = String::from("web");
+        service.node = Some(String::from("host1"));
+        block_on(consul.health_service(&service, &QueryOptions::default())).unwrap();
+        let requests = requests.lock().unwrap();
+        assert_eq!(
+            requests[0].query.get("filter").unwrap(),
+            r#"Node.Node == "host1""#
+        );
+    }
 
-    /// Transport is the Transport to use for the http client.
-    /// pub Transport: surf::Client,
-    /// HttpClient is the client to use. Default will be
-    /// used if not provided.
-    /// pub HttpClient: Option<surf::Client>,
+    #[test]
+    fn test_get_address_seeded_wait_index() {
+        use crate::api::QueryOptions;
+        use crate::watch::WatchService;
+        use async_std::task::block_on;
+        use std::time::Duration;
+        let (address, requests) = mock_server(|_| MockResponse::new(200, "[]"));
+        let consul = mock_consul(&address);
+        let mut service = WatchService::default();
+        service.service_name = String::from("seeded-web");
+        let opts = QueryOptions {
+            WaitIndex: Some(42),
+            WaitTime: Some(Duration::from_secs(90)),
+            ..QueryOptions::default()
+        };
+        block_on(consul.get_address(&service, &opts)).unwrap();
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests[0].query.get("index").unwrap(), "42");
+        assert_eq!(requests[0].query.get("wait").unwrap(), "1m30s");
+    }
 
-    /// HttpAuth is the auth info to use for http access.
+    #[test]
+    fn test_watch_services_opts_indexes() {
+        use crate::watch::WatchService;
+        use async_std::task::{block_on, sleep, spawn};
+        use std::time::Duration;
+        let (address, requests) = mock_server(|_| {
+            thread::sleep(Duration::from_millis(20));
+            MockResponse::new(200, "[]")
+        });
+        let mut consul = mock_consul(&address);
+        consul.watch_services = Some(
+            vec!["indexed-a", "indexed-b"]
+                .into_iter()
+                .map(|name| WatchService {
+                    service_name: name.to_string(),
+                    ..WatchService::default()
+                })
+                .collect(),
+        );
+        let mut indexes = HashMap::new();
+        indexes.insert(String::from("indexed-a"), 42);
+        let wait = Some(Duration::from_secs(90));
+        let handle = spawn(async move { consul.watch_services_opts(&indexes, wait).await });
+        block_on(async {
+            sleep(Duration::from_millis(100)).await;
+            handle.cancel().await;
+        });
+        let requests = requests.lock().unwrap();
+        let first = |path: &str| requests.iter().find(|req| req.path == path).unwrap();
+        let a = first("/v1/health/service/indexed-a");
+        assert_eq!(a.query.get("index").unwrap(), "42");
+        assert_eq!(a.query.get("wait").unwrap(), "1m30s");
+        let b = first("/v1/health/service/indexed-b");
+        assert_eq!(b.query.get("index").unwrap(), "0");
+        assert!(!b.query.contains_key("wait"));
+    }
+
+    #[test]
+    fn test_health_service_html_response() {
+        use crate::api::QueryOptions;
+        use crate::error::ConsulError;
+        use crate::watch::WatchService;
+        use async_std::task::block_on;
+        let (address, _) = mock_server(|_| {
+            let mut res = MockResponse::new(200, "<html><body>502 Bad Gateway</body></html>");
+            res.headers
+                .push((String::from("Content-Type"), String::from("text/html")));
+            res
+        });
+        let consul = mock_consul(&address);
+        let mut service = WatchService::default();
+        service.service_name = String::from("web");
+        let err = block_on(consul.health_service(&service, &QueryOptions::default())).unwrap_err();
+        match err.downcast_ref::<ConsulError>() {
+            Some(ConsulError::InvalidResponse {
+                status,
+                content_type,
+                body,
+                ..
+            }) => {
+                assert_eq!(*status, 200);
+                assert!(content_type.as_deref().unwrap().starts_with("text/html"));
+                assert!(body.starts_with("<html>"));
+            }
+            other => panic!("unexpected error {:?}", other),
+        }
+        assert!(err.to_string().contains("text/html"));
+    }
+
+    #[test]
+    fn test_watch_status() {
+        use crate::api::{SERVICES_ADDRESS, WATCH_POLLS};
+        use crate::health::ServiceAddress;
+        use crate::watch::WatchService;
+        use async_std::task::block_on;
+        let mut consul = mock_consul("http://127.0.0.1:8500");
+        let mut watched = WatchService::default();
+        watched.service_name = String::from("status_web");
+        watched.tag = Some(String::from("v1"));
+        let mut unresolved = WatchService::default();
+        unresolved.service_name = String::from("status_db");
+        consul.watch_services = Some(vec![watched, unresolved]);
+        {
+            let mut services_addresses = block_on(SERVICES_ADDRESS.write());
+            services_addresses.insert(
+                String::from("status_webv1"),
+                ServiceAddress {
+                    index: 12,
+                    address: vec![String::from("10.0.4.1:80")],
+                    ..ServiceAddress::default()
+                },
+            );
+        }
+        let before = std::time::SystemTime::now();
+        block_on(WATCH_POLLS.write()).insert(String::from("status_webv1"), before);
+        let status = block_on(consul.watch_status());
+        assert_eq!(status.len(), 2);
+        assert_eq!(status[0].service_name, "status_web");
+        assert_eq!(status[0].tag.as_deref(), Some("v1"));
+        assert_eq!(status[0].index, 12);
+        assert_eq!(status[0].last_poll, Some(before));
+        assert_eq!(status[0].address, vec!["10.0.4.1:80"]);
+        assert_eq!(status[1].service_name, "status_db");
+        assert!(status[1].last_poll.is_none());
+        assert!(status[1].address.is_empty());
+    }
+
+    #[test]
+    fn test_rate_limit_retry() {
+        use crate::error::ConsulError;
+        use async_std::task::block_on;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::time::{Duration, Instant};
+        let attempts = AtomicUsize::new(0);
+        let (address, requests) = mock_server(move |_| {
+            if attempts.fetch_add(1, Ordering::SeqCst) == 0 {
+                let mut res = MockResponse::new(429, "rate limit exceeded");
+                res.headers
+                    .push((String::from("Retry-After"), String::from("1")));
+                return res;
+            }
+            MockResponse::new(200, "{}")
+        });
+        let mut consul = mock_consul(&address);
+        let started = Instant::now();
+        let services = block_on(consul.agent_services()).unwrap();
+        assert!(services.is_empty());
+        assert!(started.elapsed() >= Duration::from_secs(1));
+        assert_eq!(requests.lock().unwrap().len(), 2);
+
+        let (address, _) = mock_server(|_| {
+            let mut res = MockResponse::new(429, "rate limit exceeded");
+            res.headers
+                .push((String::from("Retry-After"), String::from("30")));
+            res
+        });
+        consul = mock_consul(&address);
+        consul.config.as_mut().unwrap().rate_limit_retries = Some(0);
+        let err = block_on(consul.agent_services()).unwrap_err();
+        assert_eq!(err.status(), 429);
+        assert_eq!(
+            err.downcast_ref::<ConsulError>(),
+            Some(&ConsulError::RateLimited {
+                retry_after: Some(30)
+            })
+        );
+
+        let (address, requests) = mock_server(|_| {
+            let mut res = MockResponse::new(429, "rate limit exceeded");
+            res.headers
+                .push((String::from("Retry-After"), String::from("86400")));
+            res
+        });
+        consul = mock_consul(&address);
+        let started = Instant::now();
+        let err = block_on(consul.agent_services()).unwrap_err();
+        assert!(started.elapsed() < Duration::from_secs(1));
+        assert_eq!(
+            err.downcast_ref::<ConsulError>(),
+            Some(&ConsulError::RateLimited {
+                retry_after: Some(86400)
+            })
+        );
+        assert_eq!(requests.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_kv_export_import() {
+        use crate::error::ConsulError;
+        use crate::kv::KVPair;
+        use async_std::task::block_on;
+        use std::collections::BTreeMap;
+        let store: Mutex<BTreeMap<String, (u64, String)>> = Mutex::new(BTreeMap::new());
+        store
+            .lock()
+            .unwrap()
+            .insert(String::from("app/db"), (42, base64::encode("host=db")));
+        store
+            .lock()
+            .unwrap()
+            .insert(String::from("app/web/port"), (0, base64::encode("80")));
+        let (address, _) = mock_server(move |req| {
+            let key = req.path.trim_start_matches("/v1/kv/").to_string();
+            let mut store = store.lock().unwrap();
+            match req.method.as_str() {
+                "GET" => {
+                    let pairs: Vec<String> = store
+                        .iter()
+                        .filter(|(k, _)| k.starts_with(&key))
+                        .map(|(k, (flags, value))| {
+                            format!(r#"{{"Key":"{}","Flags":{},"Value":"{}"}}"#, k, flags, value)
+                        })
+                        .collect();
+                    if pairs.is_empty() {
+                        return MockResponse::new(404, "");
+                    }
+                    MockResponse::new(200, &format!("[{}]", pairs.join(",")))
+                }
+                _ if key.ends_with("readonly") => MockResponse::new(403, "Permission denied"),
+                _ => {
+                    let flags = req
+                        .query
+                        .get("flags")
+                        .map(|flags| flags.parse().unwrap())
+                        .unwrap_or(0);
+                    store.insert(key, (flags, base64::encode(&req.body)));
+                    MockResponse::new(200, "true")
+                }
+            }
+        });
+        let consul = mock_consul(&address);
+        let exported = block_on(consul.kv_export("app/")).unwrap();
+        assert_eq!(exported.len(), 2);
+        let moved: Vec<KVPair> = exported
+            .iter()
+            .map(|pair| KVPair {
+                Key: pair.Key.replacen("app/", "copy/", 1),
+                ..pair.clone()
+            })
+            .collect();
+        block_on(consul.kv_import(&moved)).unwrap();
+        let imported = block_on(consul.kv_export("copy/")).unwrap();
+        assert_eq!(imported, moved);
+        assert!(block_on(consul.kv_export("missing/")).unwrap().is_empty());
+
+        let mut partial = moved.clone();
+        partial[0].Key = String::from("copy/readonly");
+        let err = block_on(consul.kv_import(&partial)).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<ConsulError>(),
+            Some(&ConsulError::ImportFailed(vec![String::from(
+                "copy/readonly"
+            )]))
+        );
+    }
+
+    #[test]
+    fn test_namespace_wildcard() {
+        use crate::api::{QueryOptions, NAMESPACE_WILDCARD};
+        use async_std::task::block_on;
+        let (address, requests) = mock_server(|req| match req.path.as_str() {
+            "/v1/catalog/service/web" => MockResponse::new(
+                200,
+                r#"[{"Node":"n1","ServiceID":"web-1","Namespace":"team-a"},{"Node":"n2","ServiceID":"web-1","Namespace":"team-b"}]"#,
+            ),
+            _ => MockResponse::new(200, "[]"),
+        });
+        let mut consul = mock_consul(&address);
+        consul.config.as_mut().unwrap().namespace = Some(String::from("team-a"));
+        let mut opts = QueryOptions::default();
+        opts.Namespace = Some(NAMESPACE_WILDCARD.to_string());
+        let services = block_on(consul.catalog_service("web", None, &opts)).unwrap();
+        assert_eq!(services[1].Namespace.as_deref(), Some("team-b"));
+        block_on(consul.health_checks("web", &opts)).unwrap();
+        block_on(consul.kv_export_opts("app/", &opts)).unwrap();
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 3);
+        for request in requests.iter() {
+            assert_eq!(request.query.get("ns").unwrap(), "*");
+        }
+    }
+
+    #[test]
+    fn test_kv_consistency_mode() {
+        use crate::api::QueryOptions;
+        use async_std::task::block_on;
+        let (address, requests) = mock_server(|req| match req.path.as_str() {
+            "/v1/kv/app/leader" => MockResponse::new(
+                200,
+                r#"[{"Key":"app/leader","Flags":0,"Value":"bm9kZS0x","ModifyIndex":7}]"#,
+            ),
+            "/v1/kv/app/" => MockResponse::new(200, "[]"),
+            _ => MockResponse::new(404, ""),
+        });
+        let consul = mock_consul(&address);
+        let stale = QueryOptions {
+            AllowStale: Some(true),
+            ..QueryOptions::default()
+        };
+        let consistent = QueryOptions {
+            RequireConsistent: Some(true),
+            ..QueryOptions::default()
+        };
+        block_on(consul.kv_list_opts("app/", &stale)).unwrap();
+        let pair = block_on(consul.kv_get_opts("app/leader", &consistent))
+            .unwrap()
+            .unwrap();
+        assert_eq!(pair.Value.as_deref(), Some("bm9kZS0x"));
+        assert!(block_on(consul.kv_get("app/missing")).unwrap().is_none());
+        let both = QueryOptions {
+            AllowStale: Some(true),
+            RequireConsistent: Some(true),
+            ..QueryOptions::default()
+        };
+        assert!(block_on(consul.kv_get_opts("app/leader", &both)).is_err());
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 3);
+        assert_eq!(requests[0].query.get("stale").unwrap(), "");
+        assert!(requests[0].query.get("consistent").is_none());
+        assert_eq!(requests[1].query.get("consistent").unwrap(), "");
+        assert!(requests[1].query.get("stale").is_none());
+        assert!(requests[2].query.get("stale").is_none());
+        assert!(requests[2].query.get("consistent").is_none());
+    }
+
+    #[test]
+    fn test_service_health_global() {
+        use crate::health::HealthStatus;
+        use async_std::task::block_on;
+        let (address, _) = mock_server(|req| match req.query.get("dc").map(String::as_str) {
+            Some("dc1") => MockResponse::new(
+                200,
+                r#"[{"Service":{"ID":"web-1","Port":80},"Checks":[{"CheckID":"serfHealth","Status":"passing"},{"CheckID":"service:web-1","Status":"passing"}]}]"#,
+            ),
+            Some("dc3") => MockResponse::new(
+                200,
+                r#"[{"Service":{"ID":"web-1","Port":80},"Checks":[{"CheckID":"service:web-1","Status":"bogus"}]}]"#,
+            ),
+            _ => MockResponse::new(500, "No path to datacenter"),
+        });
+        let consul = mock_consul(&address);
+        let statuses =
+            block_on(consul.service_health_global("web", &["dc1", "dc2", "dc3"])).unwrap();
+        assert_eq!(statuses.len(), 3);
+        assert_eq!(statuses["dc1"], HealthStatus::Passing);
+        assert_eq!(statuses["dc2"], HealthStatus::Unreachable);
+        assert_eq!(statuses["dc3"], HealthStatus::Unknown("bogus".into()));
+    }
+
+    #[test]
+    fn test_heartbeat_all() {
+        use async_std::task::block_on;
+        let (address, requests) = mock_server(|req| match req.path.as_str() {
+            "/v1/agent/check/pass/ttl-3" => {
+                MockResponse::new(404, r#"CheckID "ttl-3" does not have associated TTL"#)
+            }
+            _ => MockResponse::new(200, ""),
+        });
+        let consul = mock_consul(&address);
+        let check_ids = vec![
+            String::from("ttl-1"),
+            String::from("ttl-2"),
+            String::from("ttl-3"),
+        ];
+        let results = block_on(consul.heartbeat_all(&check_ids));
+        assert_eq!(results.len(), 3);
+        assert_eq!(results[0].0, "ttl-1");
+        assert_eq!(*results[0].1.as_ref().unwrap(), 200);
+        assert_eq!(*results[1].1.as_ref().unwrap(), 200);
+        assert_eq!(results[2].0, "ttl-3");
+        assert_eq!(results[2].1.as_ref().unwrap_err().status(), 404);
+        assert_eq!(requests.lock().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn test_cached_query_meta() {
+        use crate::api::QueryOptions;
+        use async_std::task::block_on;
+        use std::time::Duration;
+        let (address, requests) = mock_server(|req| {
+            let mut res = MockResponse::new(200, "[]");
+            if req.query.contains_key("cached") {
+                res.headers
+                    .push((String::from("X-Cache"), String::from("HIT")));
+                res.headers.push((String::from("Age"), String::from("12")));
+            }
+            res
+        });
+        let consul = mock_consul(&address);
+        let opts = QueryOptions {
+            UseCache: Some(true),
+            MaxAge: Some(Duration::from_secs(30)),
+            StaleIfError: Some(Duration::from_secs(60)),
+            ..QueryOptions::default()
+        };
+        let (_, meta) = block_on(consul.catalog_service_meta("web", None, &opts)).unwrap();
+        assert_eq!(meta.CacheHit, Some(true));
+        assert_eq!(meta.CacheAge, Some(Duration::from_secs(12)));
+        let (_, meta) =
+            block_on(consul.catalog_service_meta("web", None, &QueryOptions::default())).unwrap();
+        assert_eq!(meta.CacheHit, None);
+        assert_eq!(meta.CacheAge, None);
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests[0].query.get("cached").unwrap(), "");
+        assert_eq!(
+            requests[0].headers.get("cache-control").unwrap(),
+            "max-age=30, stale-if-error=60"
+        );
+        assert!(requests[1].headers.get("cache-control").is_none());
+    }
+
+    #[test]
+    fn test_register_name_conflict() {
+        use crate::agent::{AgentServiceRegistration, ServiceRegisterOpts};
+        use crate::error::ConsulError;
+        use async_std::task::block_on;
+        let (address, requests) = mock_server(|req| match req.path.as_str() {
+            "/v1/agent/services" => MockResponse::new(
+                200,
+                r#"{"leader-1":{"ID":"leader-1","Service":"leader","Port":9000}}"#,
+            ),
+            _ => MockResponse::new(200, ""),
+        });
+        let consul = mock_consul(&address);
+        let opts = ServiceRegisterOpts {
+            RejectNameConflict: true,
+            ..ServiceRegisterOpts::default()
+        };
+        let mut service = AgentServiceRegistration::default();
+        service.Name = Some(String::from("leader"));
+        service.ID = Some(String::from("leader-2"));
+        let err = block_on(consul.service_register_opts(&service, &opts)).unwrap_err();
+        assert_eq!(err.status(), 409);
+        assert_eq!(
+            err.downcast_ref::<ConsulError>(),
+            Some(&ConsulError::Conflict(String::from("leader-1")))
+        );
+        {
+            let requests = requests.lock().unwrap();
+            assert_eq!(requests.len(), 1);
+        }
+
+        service.ID = Some(String::from("leader-1"));
+        block_on(consul.service_register_opts(&service, &opts)).unwrap();
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests[2].path, "/v1/agent/service/register");
+    }
+
+    #[test]
+    fn test_kv_lock_blocking() {
+        use async_std::task::block_on;
+        use std::time::{Duration, Instant};
+        // the holding session, the index of the key and the end of the
+        // lock-delay.
+        let state = Arc::new(Mutex::new((None::<String>, 1u64, None::<Instant>)));
+        let lock = state.clone();
+        let (address, requests) = mock_server(move |req| {
+            if req.method == "PUT" {
+                let mut state = lock.lock().unwrap();
+                let out = match (req.query.get("acquire"), req.query.get("release")) {
+                    (Some(session), _)
+                        if (state.0.is_none() || state.0.as_ref() == Some(session))
+                            && state.2.is_none_or(|until| Instant::now() >= until) =>
+                    {
+                        state.0 = Some(session.to_string());
+                        true
+                    }
+                    (_, Some(session)) if state.0.as_ref() == Some(session) => {
+                        state.0 = None;
+                        true
+                    }
+                    _ => false,
+                };
+                if out {
+                    state.1 += 1;
+                }
+                return MockResponse::new(200, &out.to_string());
+            }
+            if let Some(id) = req.path.strip_prefix("/v1/session/info/") {
+                let body = serde_json::json!([{ "ID": id, "LockDelay": 300_000_000u64 }]);
+                return MockResponse::new(200, &body.to_string());
+            }
+            let index: u64 = req
+                .query
+                .get("index")
+                .and_then(|index| index.parse().ok())
+                .unwrap_or(0);
+            let wait: u64 = req
+                .query
+                .get("wait")
+                .and_then(|wait| wait.trim_end_matches("ms").parse().ok())
+                .unwrap_or(0);
+            let deadline = Instant::now() + Duration::from_millis(wait);
+            while lock.lock().unwrap().1 <= index && Instant::now() < deadline {
+                thread::sleep(Duration::from_millis(5));
+            }
+            let state = lock.lock().unwrap();
+            let body = serde_json::json!([{
+                "Key": "service/leader",
+                "Session": state.0,
+                "ModifyIndex": state.1,
+            }]);
+            let mut res = MockResponse::new(200, &body.to_string());
+            res.headers
+                .push((String::from("X-Consul-Index"), state.1.to_string()));
+            res
+        });
+        let consul = mock_consul(&address);
+        assert!(block_on(consul.kv_acquire("service/leader", "session-1", b"node-1")).unwrap());
+
+        let holder = consul.clone();
+        let release = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(200));
+            block_on(holder.kv_release("service/leader", "session-1")).unwrap()
+        });
+        let started = Instant::now();
+        let timeout = Duration::from_secs(5);
+        assert!(block_on(consul.kv_lock_blocking("service/leader", "session-2", timeout)).unwrap());
+        assert!(started.elapsed() >= Duration::from_millis(200));
+        assert!(started.elapsed() < Duration::from_secs(1));
+        assert!(release.join().unwrap());
+        assert_eq!(state.lock().unwrap().0.as_deref(), Some("session-2"));
+        assert!(requests
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|req| req.method == "GET" && req.query.contains_key("index")));
+
+        let timeout = Duration::from_millis(300);
+        assert!(
+            !block_on(consul.kv_lock_blocking("service/leader", "session-3", timeout)).unwrap()
+        );
+
+        // invalidating session-2 starts its lock-delay, which is slept out
+        // instead of being retried every LOCK_RETRY_TIME.
+        let invalidate = state.clone();
+        let invalidated = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(100));
+            let mut state = invalidate.lock().unwrap();
+            state.0 = None;
+            state.1 += 1;
+            state.2 = Some(Instant::now() + Duration::from_millis(300));
+        });
+        let started = Instant::now();
+        let timeout = Duration::from_secs(5);
+        assert!(block_on(consul.kv_lock_blocking("service/leader", "session-3", timeout)).unwrap());
+        invalidated.join().unwrap();
+        assert!(started.elapsed() >= Duration::from_millis(400));
+        assert!(started.elapsed() < Duration::from_secs(1));
+        assert_eq!(state.lock().unwrap().0.as_deref(), Some("session-3"));
+        assert!(requests
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|req| req.path == "/v1/session/info/session-2"));
+    }
+
+    #[test]
+    fn test_with_deadline() {
+        use crate::api::with_deadline;
+        use crate::error::ConsulError;
+        use async_std::task::block_on;
+        use std::time::{Duration, Instant};
+        let (address, requests) = mock_server(|req| {
+            if req.path == "/v1/agent/services" {
+                thread::sleep(Duration::from_secs(1));
+                return MockResponse::new(200, "{}");
+            }
+            let mut res = MockResponse::new(200, "[]");
+            res.headers
+                .push((String::from("X-Consul-Index"), String::from("6")));
+            res
+        });
+        let consul = mock_consul(&address);
+        let started = Instant::now();
+        let deadline = started + Duration::from_millis(300);
+        let (index, err) = block_on(with_deadline(deadline, async {
+            let wait = Duration::from_secs(10);
+            let (_, index) = consul
+                .catalog_service_blocking("web", None, None, 5, wait)
+                .await
+                .unwrap();
+            (index, consul.agent_services().await.unwrap_err())
+        }));
+        assert_eq!(index, 6);
+        assert_eq!(
+            err.downcast_ref::<ConsulError>(),
+            Some(&ConsulError::Timeout)
+        );
+        assert!(started.elapsed() < Duration::from_millis(800));
+        let err = block_on(with_deadline(Instant::now(), consul.agent_services())).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<ConsulError>(),
+            Some(&ConsulError::Timeout)
+        );
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 2);
+        let wait = requests[0].query.get("wait").unwrap();
+        let wait: u64 = wait.trim_end_matches("ms").parse().unwrap();
+        assert!(wait <= 300);
+    }
+
+    #[test]
+    fn test_shorten_wait() {
+        use crate::api::shorten_wait;
+        use std::time::Duration;
+        let wait = |url: &str, remaining: Duration| {
+            let mut req = surf::get(url).build();
+            shorten_wait(&mut req, remaining);
+            req.url()
+                .query_pairs()
+                .find(|(key, _)| key == "wait")
+                .map(|(_, wait)| wait.to_string())
+        };
+        let remaining = Duration::from_millis(1600);
+        let url = "http://127.0.0.1:8500/v1/health/service/web";
+        assert_eq!(
+            wait(&format!("{}?wait=10s", url), remaining).unwrap(),
+            "1500ms"
+        );
+        assert_eq!(wait(&format!("{}?wait=1s", url), remaining).unwrap(), "1s");
+        assert_eq!(
+            wait(&format!("{}?index=5", url), remaining).unwrap(),
+            "1500ms"
+        );
+        assert_eq!(wait(&format!("{}?index=0", url), remaining), None);
+        assert_eq!(wait(url, remaining), None);
+        let remaining = Duration::from_micros(500);
+        assert_eq!(wait(&format!("{}?index=5", url), remaining).unwrap(), "1ms");
+    }
+
+    #[test]
+    fn test_spawn_scoped_deadline() {
+        use crate::api::with_deadline;
+        use crate::error::ConsulError;
+        use async_std::task::block_on;
+        use std::time::Instant;
+        let (address, requests) = mock_server(|_| MockResponse::new(200, ""));
+        let consul = mock_consul(&address);
+        let check_ids = vec![String::from("ttl-1"), String::from("ttl-2")];
+        let results = block_on(with_deadline(
+            Instant::now(),
+            consul.heartbeat_all(&check_ids),
+        ));
+        assert_eq!(results.len(), 2);
+        for (_, result) in results.iter() {
+            let err = result.as_ref().unwrap_err();
+            assert_eq!(
+                err.downcast_ref::<ConsulError>(),
+                Some(&ConsulError::Timeout)
+            );
+        }
+        assert!(requests.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_session_node() {
+        use crate::session::{SessionEntry, SESSION_BEHAVIOR_RELEASE};
+        use async_std::task::block_on;
+        use std::time::Duration;
+        let sessions = Arc::new(Mutex::new(vec![]));
+        let created = sessions.clone();
+        let (address, _) = mock_server(move |req| match req.path.as_str() {
+            "/v1/session/create" => {
+                let mut session: serde_json::Value = serde_json::from_str(&req.body).unwrap();
+                session["ID"] = serde_json::json!("adf4238a-882b-9ddc-4a9d-5b6758e4159e");
+                session["LockDelay"] = serde_json::json!(15_000_000_000u64);
+                created.lock().unwrap().push(session);
+                MockResponse::new(200, r#"{"ID":"adf4238a-882b-9ddc-4a9d-5b6758e4159e"}"#)
+            }
+            "/v1/session/node/host1" => {
+                let sessions = created.lock().unwrap();
+                MockResponse::new(200, &serde_json::to_string(&*sessions).unwrap())
+            }
+            _ => MockResponse::new(200, "null"),
+        });
+        let consul = mock_consul(&address);
+        let session = SessionEntry {
+            Name: Some(String::from("leader-lock")),
+            Node: Some(String::from("host1")),
+            Behavior: Some(SESSION_BEHAVIOR_RELEASE.to_string()),
+            ..SessionEntry::default()
+        };
+        let id = block_on(consul.session_create(&session)).unwrap();
+        let sessions = block_on(consul.session_node("host1")).unwrap();
+        assert_eq!(sessions.len(), 1);
+        assert_eq!(sessions[0].ID.as_ref(), Some(&id));
+        assert_eq!(sessions[0].Name.as_deref(), Some("leader-lock"));
+        assert_eq!(
+            sessions[0].LockDelay.unwrap().as_duration(),
+            Duration::from_secs(15)
+        );
+        assert!(block_on(consul.session_node("host2")).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_export_hosts_env() {
+        use crate::api::SERVICES_ADDRESS;
+        use async_std::task::block_on;
+        let (address, _) = mock_server(|req| match req.path.as_str() {
+            "/v1/health/service/export-api" => MockResponse::new(
+                200,
+                r#"[{"Service":{"Address":"10.0.9.1","Port":8080}},{"Service":{"Address":"10.0.9.2","Port":8080}},{"Service":{"Address":"10.0.9.2","Port":8081}},{"Service":{"Address":"api.internal","Port":8080}}]"#,
+            ),
+            _ => MockResponse::new(200, "[]"),
+        });
+        let consul = mock_consul(&address);
+        let services = ["export-api", "export-missing"];
+        let hosts = block_on(consul.export_hosts(&services)).unwrap();
+        assert_eq!(
+            hosts,
+            "10.0.9.1 export-api.service.consul\n10.0.9.2 export-api.service.consul\n"
+        );
+        let env = block_on(consul.export_env(&services)).unwrap();
+        assert_eq!(
+            env,
+            "EXPORT_API_ADDR=10.0.9.1:8080,10.0.9.2:8080,10.0.9.2:8081,api.internal:8080\n"
+        );
+        // exporting doesn't go through resolve, so the cache is left alone.
+        let services_addresses = block_on(SERVICES_ADDRESS.read());
+        assert!(!services_addresses.contains_key("export-api"));
+    }
+
+    #[test]
+    fn test_ui_services() {
+        use crate::agent::ServiceKind;
+        use async_std::task::block_on;
+        let (address, requests) = mock_server(|_| {
+            MockResponse::new(
+                200,
+                r#"[{"Kind":"","Name":"web","Datacenter":"dc2","Tags":["v1"],"Nodes":["host1","host2"],"InstanceCount":3,"ChecksPassing":5,"ChecksWarning":1,"ChecksCritical":2,"ExternalSources":null,"ConnectedWithProxy":true,"ConnectedWithGateway":false},{"Kind":"connect-proxy","Name":"web-sidecar-proxy","InstanceCount":3,"ChecksPassing":6,"ChecksWarning":0,"ChecksCritical":0}]"#,
+            )
+        });
+        let consul = mock_consul(&address);
+        let services = block_on(consul.ui_services(Some("dc2"))).unwrap();
+        assert_eq!(services.len(), 2);
+        let web = &services[0];
+        assert_eq!(web.Kind, Some(ServiceKind::Typical));
+        assert_eq!(web.InstanceCount, Some(3));
+        assert_eq!(web.ChecksPassing, Some(5));
+        assert_eq!(web.ChecksWarning, Some(1));
+        assert_eq!(web.ChecksCritical, Some(2));
+        assert_eq!(web.Nodes.as_ref().unwrap().len(), 2);
+        assert_eq!(services[1].Kind, Some(ServiceKind::ConnectProxy));
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests[0].path, "/v1/internal/ui/services");
+        assert_eq!(requests[0].query.get("dc").unwrap(), "dc2");
+    }
+
+    #[test]
+    fn test_join_url() {
+        use crate::api::join_url;
+        use async_std::task::block_on;
+        use surf::http::Method;
+        let cases = vec![
+            ("http://127.0.0.1:8500", "/v1/agent/self"),
+            ("http://127.0.0.1:8500/", "/v1/agent/self"),
+            ("http://127.0.0.1:8500", "v1/agent/self"),
+            ("http://127.0.0.1:8500/", "v1/agent/self"),
+        ];
+        for (address, path) in cases {
+            assert_eq!(
+                join_url(address, path),
+                "http://127.0.0.1:8500/v1/agent/self"
+            );
+        }
+        assert_eq!(
+            join_url("https://proxy.local/consul/", "/v1/agent/self"),
+            "https://proxy.local/consul/v1/agent/self"
+        );
+        let consul = mock_consul("http://127.0.0.1:8500/");
+        let req = block_on(consul.new_request(Method::Get, "/v1/agent/self")).unwrap();
+        assert_eq!(req.url().path(), "/v1/agent/self");
+    }
+
+    #[test]
+    fn test_sort_addresses() {
+        use crate::api::QueryOptions;
+        use crate::watch::WatchService;
+        use async_std::task::block_on;
+        let (address, _) = mock_server(|_| {
+            MockResponse::new(
+                200,
+                r#"[{"Service":{"Address":"10.0.5.2","Port":80,"ModifyIndex":3}},{"Service":{"Address":"10.0.5.1","Port":81,"ModifyIndex":3}},{"Service":{"Address":"10.0.5.1","Port":80,"ModifyIndex":3}}]"#,
+            )
+        });
+        let mut consul = mock_consul(&address);
+        let mut service = WatchService::default();
+        service.service_name = String::from("sorted_web");
+        let (_, cached) = block_on(consul.get_address(&service, &QueryOptions::default())).unwrap();
+        assert_eq!(
+            cached.address,
+            vec!["10.0.5.2:80", "10.0.5.1:81", "10.0.5.1:80"]
+        );
+        consul.config.as_mut().unwrap().sort_addresses = Some(true);
+        let sorted = vec!["10.0.5.1:80", "10.0.5.1:81", "10.0.5.2:80"];
+        let (_, cached) = block_on(consul.get_address(&service, &QueryOptions::default())).unwrap();
+        assert_eq!(cached.address, sorted);
+        let link: Vec<String> = cached.address_link.into_iter().collect();
+        assert_eq!(link, sorted);
+        let resolved = block_on(consul.resolve(&service)).unwrap();
+        assert_eq!(resolved.address, sorted);
+    }
+
+    #[test]
+    fn test_catalog_service_blocking() {
+        use async_std::task::block_on;
+        use std::time::Duration;
+        let (address, requests) = mock_server(|req| {
+            let (index, body) = match req.query.get("index").map(String::as_str) {
+                // a blocking query returns once web-2 registers.
+                Some("5") => {
+                    std::thread::sleep(Duration::from_millis(100));
+                    (
+                        "6",
+                        r#"[{"ServiceID":"web-1","Address":"10.0.6.1"},{"ServiceID":"web-2","Address":"10.0.6.2"}]"#,
+                    )
+                }
+                _ => ("5", r#"[{"ServiceID":"web-1","Address":"10.0.6.1"}]"#),
+            };
+            let mut res = MockResponse::new(200, body);
+            res.headers
+                .push((String::from("X-Consul-Index"), index.to_string()));
+            res
+        });
+        let consul = mock_consul(&address);
+        let wait = Duration::from_secs(10);
+        let (services, index) =
+            block_on(consul.catalog_service_blocking("web", Some("v1"), Some("dc2"), 0, wait))
+                .unwrap();
+        assert_eq!(services.len(), 1);
+        assert_eq!(index, 5);
+        let (services, index) =
+            block_on(consul.catalog_service_blocking("web", Some("v1"), Some("dc2"), index, wait))
+                .unwrap();
+        assert_eq!(services[1].ServiceID.as_deref(), Some("web-2"));
+        assert_eq!(index, 6);
+        let requests = requests.lock().unwrap();
+        assert!(requests[0].query.get("index").is_none());
+        assert!(requests[0].query.get("wait").is_none());
+        assert_eq!(requests[1].path, "/v1/catalog/service/web");
+        assert_eq!(requests[1].query.get("index").unwrap(), "5");
+        assert_eq!(requests[1].query.get("wait").unwrap(), "10000ms");
+        assert_eq!(requests[1].query.get("tag").unwrap(), "v1");
+        assert_eq!(requests[1].query.get("dc").unwrap(), "dc2");
+    }
+
+    #[test]
+    fn test_health_state_blocking() {
+        use crate::health::HEALTH_CRITICAL;
+        use async_std::task::block_on;
+        use std::time::Duration;
+        let (address, requests) = mock_server(|req| {
+            let (index, body) = match req.query.get("index").map(String::as_str) {
+                // a blocking query returns once db-1 turns critical.
+                Some("8") => {
+                    std::thread::sleep(Duration::from_millis(100));
+                    (
+                        "9",
+                        r#"[{"Node":"host1","CheckID":"service:web-1","Status":"critical","Output":"connection refused"},{"Node":"host2","CheckID":"service:db-1","Status":"critical","Output":"disk full"}]"#,
+                    )
+                }
+                _ => (
+                    "8",
+                    r#"[{"Node":"host1","CheckID":"service:web-1","Status":"critical","Output":"connection refused"}]"#,
+                ),
+            };
+            let mut res = MockResponse::new(200, body);
+            res.headers
+                .push((String::from("X-Consul-Index"), index.to_string()));
+            res
+        });
+        let consul = mock_consul(&address);
+        let wait = Duration::from_secs(10);
+        let (checks, index) =
+            block_on(consul.health_state_blocking(&HEALTH_CRITICAL, None, 0, wait)).unwrap();
+        assert_eq!(checks.len(), 1);
+        assert_eq!(index, 8);
+        let (checks, index) =
+            block_on(consul.health_state_blocking(&HEALTH_CRITICAL, None, index, wait)).unwrap();
+        assert_eq!(index, 9);
+        assert_eq!(checks[1].CheckID.as_deref(), Some("service:db-1"));
+        assert_eq!(checks[1].Output.as_deref(), Some("disk full"));
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests[0].path, "/v1/health/state/critical");
+        assert!(requests[0].query.get("index").is_none());
+        assert_eq!(requests[1].query.get("index").unwrap(), "8");
+        assert_eq!(requests[1].query.get("wait").unwrap(), "10000ms");
+    }
+
+    #[test]
+    fn test_audit_meta() {
+        use crate::agent::{AgentServiceRegistration, ServiceRegisterOpts};
+        use crate::api::{AuditMeta, WriteOptions};
+        use crate::kv::KVPair;
+        use async_std::task::block_on;
+        let (address, requests) = mock_server(|_| MockResponse::new(200, "true"));
+        let consul = mock_consul(&address);
+        let audit = AuditMeta {
+            Reason: Some(String::from("CHG-1234 decommission")),
+            Actor: Some(String::from("deploy-bot")),
+        };
+        let mut opts = WriteOptions::default();
+        opts.Audit = Some(audit.clone());
+        block_on(consul.service_deregister_opts(String::from("web-1"), &opts)).unwrap();
+        let mut pair = KVPair::default();
+        pair.Key = String::from("app/db");
+        block_on(consul.kv_put_opts(&pair, &opts)).unwrap();
+        let mut register_opts = ServiceRegisterOpts::default();
+        register_opts.Audit = Some(audit);
+        let service = AgentServiceRegistration::default();
+        block_on(consul.service_register_opts(&service, &register_opts)).unwrap();
+        block_on(consul.service_deregister(String::from("web-2"))).unwrap();
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests[0].path, "/v1/agent/service/deregister/web-1");
+        for request in requests[..3].iter() {
+            assert_eq!(
+                request.headers.get("x-consul-reason").unwrap(),
+                "CHG-1234 decommission"
+            );
+            assert_eq!(request.headers.get("x-consul-actor").unwrap(), "deploy-bot");
+        }
+        assert!(requests[3].headers.get("x-consul-reason").is_none());
+    }
+
+    #[test]
+    fn test_verify_before_register() {
+        use crate::agent::{AgentServiceRegistration, ServiceRegisterOpts};
+        use crate::error::ConsulError;
+        use async_std::task::block_on;
+        use std::time::{Duration, Instant};
+        let (address, requests) = mock_server(|_| MockResponse::new(200, ""));
+        let consul = mock_consul(&address);
+        let closed = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = closed.local_addr().unwrap().port();
+        drop(closed);
+        let mut service = AgentServiceRegistration::default();
+        service.Name = Some(String::from("web"));
+        service.Address = Some(String::from("127.0.0.1"));
+        service.Port = Some(port as usize);
+        let mut opts = ServiceRegisterOpts::default();
+        opts.VerifyBeforeRegister = true;
+        opts.VerifyRetryWindow = Some(Duration::from_millis(300));
+        let started = Instant::now();
+        let err = block_on(consul.service_register_opts(&service, &opts)).unwrap_err();
+        assert!(started.elapsed() >= Duration::from_millis(300));
+        assert_eq!(
+            err.downcast_ref::<ConsulError>(),
+            Some(&ConsulError::Unreachable(format!("127.0.0.1:{}", port)))
+        );
+        assert!(requests.lock().unwrap().is_empty());
+
+        let listening = TcpListener::bind("127.0.0.1:0").unwrap();
+        service.Port = Some(listening.local_addr().unwrap().port() as usize);
+        block_on(consul.service_register_opts(&service, &opts)).unwrap();
+        assert_eq!(requests.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_max_response_size() {
+        use crate::api::QueryOptions;
+        use crate::error::ConsulError;
+        use async_std::task::block_on;
+        let (address, _) = mock_server(|req| match req.path.as_str() {
+            "/v1/catalog/service/big" => {
+                let node = r#"{"Node":"node-with-a-long-name","ServiceID":"big"}"#;
+                MockResponse::new(200, &format!("[{}]", vec![node; 100].join(",")))
+            }
+            _ => MockResponse::new(200, r#"[{"Node":"n1","ServiceID":"small"}]"#),
+        });
+        let mut consul = mock_consul(&address);
+        consul.config.as_mut().unwrap().max_response_size = Some(1024);
+        let opts = QueryOptions::default();
+        let services = block_on(consul.catalog_service("small", None, &opts)).unwrap();
+        assert_eq!(services.len(), 1);
+        let err = block_on(consul.catalog_service("big", None, &opts)).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<ConsulError>(),
+            Some(&ConsulError::ResponseTooLarge { limit: 1024 })
+        );
+    }
+
+    #[test]
+    fn test_error_body_limit() {
+        use crate::error::ConsulError;
+        use async_std::task::block_on;
+        let (address, _) = mock_server(|_| MockResponse::new(500, &"x".repeat(100 * 1024)));
+        let mut consul = mock_consul(&address);
+        let message = |err: surf::Error| match err.downcast_ref::<ConsulError>() {
+            Some(ConsulError::Unexpected { message, .. }) => message.len(),
+            other => panic!("unexpected error {:?}", other),
+        };
+        let err = block_on(consul.agent_services()).unwrap_err();
+        assert_eq!(message(err), 64 * 1024);
+        consul.config.as_mut().unwrap().max_response_size = Some(1024);
+        let err = block_on(consul.agent_services()).unwrap_err();
+        assert_eq!(message(err), 1024);
+    }
 
-    /// pub HttpAuth: Option<http_types::auth::BasicAuth>,
+    #[test]
+    fn test_service_register_default_id() {
+        use crate::agent::{AgentServiceRegistration, ServiceRegisterOpts};
+        use crate::api::hostname;
+        use async_std::task::block_on;
+        let (address, requests) = mock_server(|_| MockResponse::new(200, ""));
+        let consul = mock_consul(&address);
+        let mut service = AgentServiceRegistration::default();
+        service.Name = Some(String::from("web"));
+        service.Port = Some(8080);
+        let mut opts = ServiceRegisterOpts::default();
+        opts.DefaultID = true;
+        block_on(consul.service_register_opts(&service, &opts)).unwrap();
+        block_on(consul.service_register_opts(&service, &opts)).unwrap();
+        service.ID = Some(String::from("web-1"));
+        block_on(consul.service_register_opts(&service, &opts)).unwrap();
+        let expected = format!("web-{}-8080", hostname());
+        let requests = requests.lock().unwrap();
+        for request in requests[..2].iter() {
+            let body: serde_json::Value = serde_json::from_str(&request.body).unwrap();
+            assert_eq!(body["ID"], expected.as_str());
+        }
+        let body: serde_json::Value = serde_json::from_str(&requests[2].body).unwrap();
+        assert_eq!(body["ID"], "web-1");
 
-    /// WaitTime limits how long a Watch will block. If not provided,
-    /// the agent default values will be used.
-    pub wait_time: Option<String>,
+        let mut service = AgentServiceRegistration::default();
+        assert_eq!(service.default_id("host1"), None);
+        service.Name = Some(String::from("db"));
+        assert_eq!(service.default_id("host1").as_deref(), Some("db-host1"));
+    }
 
-    /// Token is used to provide a per-request ACL token
-    /// which overrides the agent's default token.
-    pub token: Option<String>,
+    #[test]
+    fn test_resolve_srv() {
+        use crate::health::SrvRecord;
+        use async_std::task::block_on;
+        let (address, requests) = mock_server(|_| {
+            MockResponse::new(
+                200,
+                r#"[{"Node":{"Address":"10.0.7.9"},"Service":{"Address":"10.0.7.1","Port":80,"Weights":{"Passing":10,"Warning":1}}},{"Node":{"Address":"10.0.7.2"},"Service":{"Address":"","Port":81}},{"Service":{"Address":"10.0.7.3"}}]"#,
+            )
+        });
+        let consul = mock_consul(&address);
+        let records = block_on(consul.resolve_srv("web", Some("v1"))).unwrap();
+        assert_eq!(
+            records,
+            vec![
+                SrvRecord {
+                    target: String::from("10.0.7.1"),
+                    port: 80,
+                    weight: 10,
+                    priority: 1,
+                },
+                SrvRecord {
+                    target: String::from("10.0.7.2"),
+                    port: 81,
+                    weight: 1,
+                    priority: 1,
+                },
+            ]
+        );
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests[0].path, "/v1/health/service/web");
+        assert_eq!(requests[0].query.get("passing").unwrap(), "1");
+        assert_eq!(requests[0].query.get("tag").unwrap(), "v1");
+    }
 
-    /// TokenFile is a file containing the current token to use for this client.
-    /// If provided it is read once at startup and never again.
-    pub token_file: Option<String>,
+    #[test]
+    fn test_watch_services_streaming() {
+        use crate::api::SERVICES_ADDRESS;
+        use crate::watch::WatchService;
+        use async_std::task::block_on;
+        use std::time::Duration;
+        let (address, requests) = mock_server(|req| {
+            let (index, body) = match req.query.get("index").map(String::as_str) {
+                Some("10") => ("11", r#"[{"Service":{"Address":"10.0.8.1","Port":80}}]"#),
+                Some("11") => {
+                    std::thread::sleep(Duration::from_millis(50));
+                    ("11", r#"[{"Service":{"Address":"10.0.8.1","Port":80}}]"#)
+                }
+                _ => (
+                    "10",
+                    r#"[{"Service":{"Address":"10.0.8.1","Port":80}},{"Service":{"Address":"10.0.8.2","Port":80}}]"#,
+                ),
+            };
+            let mut res = MockResponse::new(200, body);
+            res.headers
+                .push((String::from("X-Consul-Index"), index.to_string()));
+            res.headers.push((
+                String::from("X-Consul-Query-Backend"),
+                String::from("streaming"),
+            ));
+            res
+        });
+        let mut consul = mock_consul(&address);
+        consul.config.as_mut().unwrap().streaming_backend = Some(true);
+        let mut service = WatchService::default();
+        service.service_name = String::from("streaming_web");
+        consul.watch_services = Some(vec![service]);
+        let _ = block_on(async_std::future::timeout(
+            Duration::from_millis(500),
+            consul.watch_services_self(),
+        ));
+        let services_addresses = block_on(SERVICES_ADDRESS.read());
+        let service_address = services_addresses.get("streaming_web").unwrap();
+        assert_eq!(service_address.address, vec!["10.0.8.1:80"]);
+        assert_eq!(service_address.index, 11);
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests[1].query.get("index").unwrap(), "10");
+        assert_eq!(requests[2].query.get("index").unwrap(), "11");
+    }
 
-    /// Namespace is the name of the namespace to send along for the request
-    /// when no other Namespace is present in the QueryOptions
-    pub namespace: Option<String>,
+    #[test]
+    fn test_instance_health() {
+        use crate::health::FailingCheck;
+        use async_std::task::block_on;
+        let (address, requests) = mock_server(|_| {
+            MockResponse::new(
+                200,
+                r#"[{"Node":{"Node":"host1","Address":"10.0.9.1"},"Service":{"Port":80},"Checks":[{"Name":"Serf Health Status","Status":"passing","Output":"Agent alive"},{"Name":"web-http","Status":"critical","Output":"dial tcp 10.0.9.1:80: connection refused"}]},{"Node":{"Node":"host2"},"Service":{"Address":"10.0.9.2","Port":80},"Checks":[{"Name":"web-http","Status":"passing","Output":"HTTP GET: 200 OK"}]}]"#,
+            )
+        });
+        let consul = mock_consul(&address);
+        let instances = block_on(consul.instance_health("web", None)).unwrap();
+        assert_eq!(instances[0].node.as_deref(), Some("host1"));
+        assert_eq!(instances[0].address, "10.0.9.1:80");
+        assert_eq!(instances[0].status, "critical");
+        assert_eq!(
+            instances[0].failing,
+            vec![FailingCheck {
+                name: String::from("web-http"),
+                status: String::from("critical"),
+                output: String::from("dial tcp 10.0.9.1:80: connection refused"),
+            }]
+        );
+        assert_eq!(instances[1].address, "10.0.9.2:80");
+        assert_eq!(instances[1].status, "passing");
+        assert!(instances[1].failing.is_empty());
+        assert!(requests.lock().unwrap()[0].query.get("passing").is_none());
+    }
 
-    pub tls_config: Option<TLSConfig>,
-}
+    #[test]
+    fn test_client_per_config() {
+        use crate::api::HTTP_CLIENTS;
+        use async_std::task::block_on;
+        let (address, requests) = mock_server(|_| MockResponse::new(200, "{}"));
+        let consul = mock_consul(&address);
+        let mut http2 = consul.clone();
+        http2.config.as_mut().unwrap().http2 = Some(true);
+        assert_ne!(consul.client_key(), http2.client_key());
+        block_on(consul.agent_services()).unwrap();
+        block_on(http2.agent_services()).unwrap();
+        {
+            let clients = block_on(HTTP_CLIENTS.read());
+            assert!(clients.contains_key(&consul.client_key()));
+            assert!(clients.contains_key(&http2.client_key()));
+        }
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 2);
+        for req in requests.iter() {
+            assert_ne!(
+                req.headers.get("connection").map(String::as_str),
+                Some("close")
+            );
+        }
+    }
 
-/// TLSConfig is used to generate a TLSClientConfig that's useful for talking to
-/// Consul using TLS.
-#[derive(Default, Debug, Clone, Serialize, Deserialize)]
-#[allow(non_snake_case)]
-pub struct TLSConfig {
-    /// Address is the optional address of the Consul server. The port, if any
-    /// will be removed from here and this will be set to the ServerName of the
-    /// resulting config.
-    pub address: Option<String>,
+    #[test]
+    fn test_reload_tls() {
+        use crate::api::{TLSConfig, HTTP_CLIENTS};
+        use async_std::task::block_on;
+        let (address, requests) = mock_server(|_| MockResponse::new(200, "{}"));
+        let mut consul = mock_consul(&address);
+        let dir = std::env::temp_dir().join(format!("consul-rs-tls-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let cert = dir.join("client.pem");
+        let key = dir.join("client-key.pem");
+        std::fs::write(&cert, "old cert").unwrap();
+        let mut tls = TLSConfig::default();
+        tls.cert_file = Some(cert.to_string_lossy().to_string());
+        tls.key_file = Some(key.to_string_lossy().to_string());
+        consul.config.as_mut().unwrap().tls_config = Some(tls);
+        let err = block_on(consul.reload_tls()).unwrap_err();
+        assert!(err.to_string().contains("client-key.pem"));
 
-    /// CAFile is the optional path to the CA certificate used for Consul
-    /// communication, defaults to the system bundle if not specified.
-    pub ca_file: Option<String>,
+        std::fs::write(&cert, "new cert").unwrap();
+        std::fs::write(&key, "new key").unwrap();
+        // the mock server doesn't speak TLS, so the probe fails and no client
+        // is kept for the config.
+        let mut https = consul.clone();
+        https.config.as_mut().unwrap().address = Some(address.replace("http://", "https://"));
+        assert!(block_on(https.reload_tls()).is_err());
+        assert!(!block_on(HTTP_CLIENTS.read()).contains_key(&https.client_key()));
 
-    /// CAPath is the optional path to a directory of CA certificates to use for
-    /// Consul communication, defaults to the system bundle if not specified.
-    pub ca_path: Option<String>,
+        block_on(consul.reload_tls()).unwrap();
+        assert!(block_on(HTTP_CLIENTS.read()).contains_key(&consul.client_key()));
+        block_on(consul.agent_services()).unwrap();
+        {
+            // the failed probe's TLS handshake shows up as a garbled request.
+            let requests = requests.lock().unwrap();
+            let paths: Vec<&str> = requests
+                .iter()
+                .filter(|req| req.method == "GET")
+                .map(|req| req.path.as_str())
+                .collect();
+            assert_eq!(paths, vec!["/v1/status/leader", "/v1/agent/services"]);
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 
-    /// CAPem is the optional PEM-encoded CA certificate used for Consul
-    /// communication, defaults to the system bundle if not specified.
-    pub ca_pem: Option<String>,
+    #[test]
+    fn test_raw_get_put() {
+        use crate::api::QueryOptions;
+        use async_std::task::block_on;
+        let (address, requests) = mock_server(|req| match req.path.as_str() {
+            "/v1/status/leader" => MockResponse::new(200, r#""10.0.10.1:8300""#),
+            _ => MockResponse::new(200, ""),
+        });
+        let mut consul = mock_consul(&address);
+        consul.config.as_mut().unwrap().token = Some(String::from("secret"));
+        let mut opts = QueryOptions::default();
+        opts.Datacenter = Some(String::from("dc2"));
+        let leader = block_on(consul.raw_get("/v1/status/leader", &opts)).unwrap();
+        assert_eq!(leader, serde_json::json!("10.0.10.1:8300"));
+        let body = serde_json::json!({"Name": "maintenance"});
+        let out = block_on(consul.raw_put("/v1/internal/new-endpoint", body.clone())).unwrap();
+        assert!(out.is_null());
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests[0].query.get("dc").unwrap(), "dc2");
+        assert_eq!(requests[0].headers.get("x-consul-token").unwrap(), "secret");
+        assert_eq!(requests[1].method, "PUT");
+        let sent: serde_json::Value = serde_json::from_str(&requests[1].body).unwrap();
+        assert_eq!(sent, body);
+    }
 
-    /// CertFile is the optional path to the certificate for Consul
-    /// communication. If this is set then you need to also set KeyFile.
-    pub cert_file: Option<String>,
+    #[test]
+    fn test_peering() {
+        use crate::peering::PeeringState;
+        use async_std::task::block_on;
+        let (address, requests) = mock_server(|req| match req.path.as_str() {
+            "/v1/peering/token" => MockResponse::new(200, r#"{"PeeringToken":"eyJDQSI6bnVsbH0="}"#),
+            "/v1/peering/establish" => MockResponse::new(200, "{}"),
+            "/v1/peering/cluster-02" => MockResponse::new(
+                200,
+                r#"{"ID":"462c45e8","Name":"cluster-02","State":"ACTIVE","PeerID":"e83a315c","PeerServerAddresses":["10.0.11.1:8502"]}"#,
+            ),
+            "/v1/peerings" => {
+                MockResponse::new(200, r#"[{"Name":"cluster-02","State":"PENDING"}]"#)
+            }
+            _ => MockResponse::new(404, ""),
+        });
+        let consul = mock_consul(&address);
+        let token = block_on(consul.peering_generate_token("cluster-02")).unwrap();
+        assert_eq!(token.PeeringToken, "eyJDQSI6bnVsbH0=");
+        block_on(consul.peering_establish("cluster-01", &token.PeeringToken)).unwrap();
+        let peering = block_on(consul.peering_read("cluster-02"))
+            .unwrap()
+            .unwrap();
+        assert_eq!(peering.State, Some(PeeringState::Active));
+        assert_eq!(peering.PeerID.as_deref(), Some("e83a315c"));
+        assert_eq!(peering.PeerServerAddresses.unwrap(), vec!["10.0.11.1:8502"]);
+        assert!(block_on(consul.peering_read("missing")).unwrap().is_none());
+        let peerings = block_on(consul.peering_list()).unwrap();
+        assert_eq!(peerings[0].State, Some(PeeringState::Pending));
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests[0].method, "POST");
+        let body: serde_json::Value = serde_json::from_str(&requests[0].body).unwrap();
+        assert_eq!(body["PeerName"], "cluster-02");
+        let body: serde_json::Value = serde_json::from_str(&requests[1].body).unwrap();
+        assert_eq!(body["PeeringToken"], "eyJDQSI6bnVsbH0=");
+    }
 
-    /// CertPEM is the optional PEM-encoded certificate for Consul
-    /// communication. If this is set then you need to also set KeyPEM.
-    pub cert_pem: Option<String>,
+    #[test]
+    fn test_agent_config() {
+        use async_std::task::block_on;
+        let (address, requests) = mock_server(|_| {
+            MockResponse::new(
+                200,
+                r#"{"Config":{"Datacenter":"dc3","PrimaryDatacenter":"dc1","NodeName":"host1","NodeID":"9d754d17","Revision":"3c1c22679","Server":false,"Version":"1.11.4"},"DebugConfig":{"HTTPPort":8500,"HTTPSPort":-1,"DNSPort":8600,"GRPCPort":8502,"SerfPortLAN":8301,"SerfPortWAN":8302,"ServerPort":8300,"Datacenter":"dc3","ACLTokens":{}},"Member":{"Name":"host1"},"Stats":{}}"#,
+            )
+        });
+        let consul = mock_consul(&address);
+        let config = block_on(consul.agent_config()).unwrap();
+        assert_eq!(config.Datacenter.as_deref(), Some("dc3"));
+        assert_eq!(config.NodeName.as_deref(), Some("host1"));
+        assert_eq!(config.Server, Some(false));
+        assert_eq!(config.Ports.HTTP, Some(8500));
+        assert_eq!(config.Ports.HTTPS, Some(-1));
+        assert_eq!(config.Ports.GRPC, Some(8502));
+        assert_eq!(requests.lock().unwrap()[0].path, "/v1/agent/self");
+    }
 
-    /// KeyFile is the optional path to the private key for Consul communication.
-    /// If this is set then you need to also set CertFile.
-    pub key_file: Option<String>,
+    #[test]
+    fn test_auto_discover_datacenter() {
+        use crate::api::ConsulConfig;
+        use async_std::task::block_on;
+        let (address, requests) = mock_server(|req| match req.path.as_str() {
+            "/v1/agent/self" => MockResponse::new(200, r#"{"Config":{"Datacenter":"dc3"}}"#),
+            _ => MockResponse::new(200, "{}"),
+        });
+        let mut consul = ConsulConfig::default();
+        consul.config.as_mut().unwrap().address = Some(address);
+        assert!(consul.config.as_ref().unwrap().datacenter.is_none());
+        block_on(consul.agent_services()).unwrap();
+        assert_eq!(block_on(consul.auto_discover_datacenter()).unwrap(), "dc3");
+        assert_eq!(block_on(consul.auto_discover_datacenter()).unwrap(), "dc3");
+        block_on(consul.agent_services()).unwrap();
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 3);
+        assert!(requests[0].query.get("dc").is_none());
+        assert!(requests[1].query.get("dc").is_none());
+        assert_eq!(requests[2].query.get("dc").unwrap(), "dc3");
+    }
 
-    /// KeyPEM is the optional PEM-encoded private key for Consul communication.
-    /// If this is set then you need to also set CertPEM.
-    pub key_pem: Option<String>,
+    #[test]
+    fn test_agent_members_filter() {
+        use crate::agent::MEMBER_STATUS_ALIVE;
+        use crate::api::QueryOptions;
+        use async_std::task::block_on;
+        let (address, requests) = mock_server(|_| {
+            MockResponse::new(
+                200,
+                r#"[{"Name":"host1","Addr":"10.0.12.1","Port":8301,"Tags":{"role":"node"},"Status":1}]"#,
+            )
+        });
+        let consul = mock_consul(&address);
+        let mut opts = QueryOptions::default();
+        opts.Filter = Some(format!(
+            r#"Status == {} and Tags.role == "node""#,
+            MEMBER_STATUS_ALIVE
+        ));
+        let members = block_on(consul.agent_members_opts(false, &opts)).unwrap();
+        assert_eq!(members[0].Status, Some(MEMBER_STATUS_ALIVE));
+        block_on(consul.agent_members(true)).unwrap();
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests[0].path, "/v1/agent/members");
+        assert_eq!(
+            requests[0].query.get("filter").unwrap(),
+            r#"Status == 1 and Tags.role == "node""#
+        );
+        assert!(requests[0].query.get("wan").is_none());
+        assert!(requests[1].query.get("filter").is_none());
+        assert_eq!(requests[1].query.get("wan").unwrap(), "1");
+    }
 
-    /// InsecureSkipVerify if set to true will disable TLS host verification.
-    pub insecure_skip_verify: Option<bool>,
-}
+    #[test]
+    fn test_operator_usage() {
+        use crate::api::QueryOptions;
+        use async_std::task::block_on;
+        let (address, requests) = mock_server(|_| {
+            MockResponse::new(
+                200,
+                r#"{"Usage":{"dc1":{"Services":3,"ServiceInstances":7,"ConnectServiceInstances":{"connect-proxy":2,"connect-native":0},"BillableServiceInstances":5,"Nodes":4}},"Index":13,"KnownLeader":true}"#,
+            )
+        });
+        let consul = mock_consul(&address);
+        let opts = QueryOptions {
+            Token: Some(String::from("operator-token")),
+            ..QueryOptions::default()
+        };
+        let usage = block_on(consul.operator_usage_opts(&opts)).unwrap();
+        let dc1 = &usage.Usage.unwrap()["dc1"];
+        assert_eq!(dc1.Services, Some(3));
+        assert_eq!(dc1.ServiceInstances, Some(7));
+        assert_eq!(dc1.BillableServiceInstances, Some(5));
+        assert_eq!(dc1.Nodes, Some(4));
+        assert_eq!(
+            dc1.ConnectServiceInstances.as_ref().unwrap()["connect-proxy"],
+            2
+        );
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests[0].path, "/v1/operator/usage");
+        assert_eq!(
+            requests[0].headers.get("x-consul-token").unwrap(),
+            "operator-token"
+        );
+    }
 
-/// QueryOptions are used to parameterize a query
-#[derive(Default, Debug, Clone, Serialize, Deserialize)]
-#[allow(non_snake_case)]
-pub struct QueryOptions {
-    /// Namespace overrides the `default` namespace
-    /// Note: Namespaces are available only in Consul Enterprise
-    pub Namespace: Option<String>,
+    #[test]
+    fn test_keyring() {
+        use crate::api::QueryOptions;
+        use async_std::task::block_on;
+        let (address, requests) = mock_server(|req| match req.method.as_str() {
+            "GET" => MockResponse::new(
+                200,
+                r#"[{"WAN":false,"Datacenter":"dc1","Keys":{"pUqJrVyVRj5jsiYEkM/tFQYfWyJIv4s3XkvDwy7Cu5s=":3},"NumNodes":3}]"#,
+            ),
+            _ => MockResponse::new(200, ""),
+        });
+        let consul = mock_consul(&address);
+        let mut opts = QueryOptions::default();
+        opts.RelayFactor = Some(2);
+        opts.LocalOnly = Some(true);
+        let keyrings = block_on(consul.keyring_list_opts(&opts)).unwrap();
+        assert_eq!(keyrings[0].Datacenter.as_deref(), Some("dc1"));
+        let keys = keyrings[0].Keys.as_ref().unwrap();
+        assert_eq!(
+            keys.get("pUqJrVyVRj5jsiYEkM/tFQYfWyJIv4s3XkvDwy7Cu5s="),
+            Some(&3)
+        );
+        block_on(consul.keyring_install("new-key")).unwrap();
+        block_on(consul.keyring_use("new-key")).unwrap();
+        block_on(consul.keyring_remove("old-key")).unwrap();
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests[0].query.get("relay-factor").unwrap(), "2");
+        assert_eq!(requests[0].query.get("local-only").unwrap(), "true");
+        let methods: Vec<&str> = requests.iter().map(|req| req.method.as_str()).collect();
+        assert_eq!(methods, vec!["GET", "POST", "PUT", "DELETE"]);
+        assert_eq!(requests[3].body, r#"{"Key":"old-key"}"#);
+    }
 
-    /// Providing a datacenter overwrites the DC provided
-    /// by the Config
-    pub Datacenter: Option<String>,
+    #[test]
+    fn test_relay_factor() {
+        use crate::api::{set_query_options, QueryOptions};
+        let url = surf::Url::parse("http://127.0.0.1:8500/v1/operator/keyring").unwrap();
+        let mut req = surf::Request::new(surf::http::Method::Get, url);
+        let mut opts = QueryOptions::default();
+        opts.RelayFactor = Some(6);
+        assert!(set_query_options(&mut req, &opts).is_err());
+        opts.RelayFactor = Some(3);
+        opts.LocalOnly = Some(true);
+        set_query_options(&mut req, &opts).unwrap();
+        let query: HashMap<String, String> = req.url().query_pairs().into_owned().collect();
+        assert_eq!(query.get("relay-factor").unwrap(), "3");
+        assert_eq!(query.get("local-only").unwrap(), "true");
+    }
 
-    /// AllowStale allows any Consul server (non-leader) to service
-    /// a read. This allows for lower latency and higher throughput
-    pub AllowStale: Option<bool>,
+    #[test]
+    fn test_service_register_replace_existing_checks() {
+        use crate::agent::{AgentServiceRegistration, ServiceRegisterOpts};
+        use async_std::task::block_on;
+        let (address, requests) = mock_server(|_| MockResponse::new(200, ""));
+        let mut consul = mock_consul(&address);
+        consul.config.as_mut().unwrap().datacenter = Some(String::from("dc1"));
+        let mut service = AgentServiceRegistration::default();
+        service.ID = Some(String::from("web-1"));
+        service.Name = Some(String::from("web"));
+        let mut opts = ServiceRegisterOpts::default();
+        opts.ReplaceExistingChecks = true;
+        block_on(consul.service_register_opts(&service, &opts)).unwrap();
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests[0].path, "/v1/agent/service/register");
+        assert_eq!(requests[0].query.len(), 2);
+        assert_eq!(requests[0].query.get("dc").unwrap(), "dc1");
+        assert_eq!(
+            requests[0].query.get("replace-existing-checks").unwrap(),
+            "true"
+        );
+        let body: AgentServiceRegistration = serde_json::from_str(&requests[0].body).unwrap();
+        assert_eq!(body.Name.as_deref(), Some("web"));
+    }
 
-    /// RequireConsistent forces the read to be fully consistent.
-    /// This is more expensive but prevents ever performing a stale
-    /// read.
-    pub RequireConsistent: Option<bool>,
+    #[test]
+    fn test_config_entry_apply_proxy_defaults() {
+        use crate::config_entry::ProxyDefaults;
+        use async_std::task::block_on;
+        let (address, requests) = mock_server(|_| MockResponse::new(200, "true"));
+        let consul = mock_consul(&address);
+        let mut entry = ProxyDefaults::default();
+        entry.Name = Some(String::from("web"));
+        let mut config = HashMap::new();
+        config.insert(String::from("protocol"), serde_json::json!("http"));
+        entry.Config = Some(config);
+        assert!(block_on(consul.config_entry_apply_proxy_defaults(&entry)).unwrap());
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests[0].method, "PUT");
+        assert_eq!(requests[0].path, "/v1/config");
+        let body: serde_json::Value = serde_json::from_str(&requests[0].body).unwrap();
+        assert_eq!(body["Kind"], "proxy-defaults");
+        assert_eq!(body["Name"], "global");
+        assert_eq!(body["Config"]["protocol"], "http");
+    }
 
-    /// UseCache requests that the agent cache results locally. See
-    /// https:///www.consul.io/api/features/caching.html for more details on the
-    /// semantics.
-    pub UseCache: Option<bool>,
+    #[test]
+    fn test_agent_connect_proxy_config() {
+        use crate::api::QueryOptions;
+        use async_std::task::block_on;
+        use std::time::Duration;
+        let (address, requests) = mock_server(|_| {
+            let mut res = MockResponse::new(
+                200,
+                r#"{"ProxyServiceID":"web-proxy","TargetServiceID":"web","TargetServiceName":"web","ContentHash":"a15dccb216d38a6e","Upstreams":[{"DestinationType":"service","DestinationName":"db","LocalBindPort":9191,"Config":{}}]}"#,
+            );
+            res.headers.push((
+                String::from("X-Consul-ContentHash"),
+                String::from("a15dccb216d38a6e"),
+            ));
+            res
+        });
+        let consul = mock_consul(&address);
+        let mut opts = QueryOptions::default();
+        opts.WaitHash = Some(String::from("e662e1d5c5c4fe5e"));
+        opts.WaitTime = Some(Duration::from_secs(10));
+        let (config, meta) =
+            block_on(consul.agent_connect_proxy_config_opts("web-proxy", &opts)).unwrap();
+        assert_eq!(config.ContentHash.as_deref(), Some("a15dccb216d38a6e"));
+        assert_eq!(meta.LastContentHash.as_deref(), Some("a15dccb216d38a6e"));
+        assert_eq!(config.Upstreams.unwrap()[0].LocalBindPort, Some(9191));
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests[0].path, "/v1/agent/connect/proxy/web-proxy");
+        assert_eq!(requests[0].query.get("hash").unwrap(), "e662e1d5c5c4fe5e");
+        assert_eq!(requests[0].query.get("wait").unwrap(), "10000ms");
+    }
 
-    /// MaxAge limits how old a cached value will be returned if UseCache is true.
-    /// If there is a cached response that is older than the MaxAge, it is treated
-    /// as a cache miss and a new fetch invoked. If the fetch fails, the error is
-    /// returned. Clients that wish to allow for stale results on error can set
-    /// StaleIfError to a longer duration to change this behavior. It is ignored
-    /// if the endpoint supports background refresh caching. See
-    /// https:///www.consul.io/api/features/caching.html for more details.
-    pub MaxAge: Option<time::Duration>,
+    #[test]
+    fn test_shutdown() {
+        use crate::agent::AgentServiceRegistration;
+        use crate::watch::WatchService;
+        use async_std::task::{block_on, sleep};
+        use std::time::Duration;
+        let (address, requests) = mock_server(|req| {
+            if req.path.starts_with("/v1/health/service/") {
+                thread::sleep(Duration::from_millis(20));
+                return MockResponse::new(200, "[]");
+            }
+            if req.path == "/v1/agent/service/deregister/shutdown-web-0" {
+                return MockResponse::new(500, "deregister failed");
+            }
+            MockResponse::new(200, "")
+        });
+        let mut consul = mock_consul(&address);
+        let mut service = WatchService::default();
+        service.service_name = String::from("shutdown_web");
+        consul.watch_services = Some(vec![service]);
+        let mut registration = AgentServiceRegistration::default();
+        registration.ID = Some(String::from("shutdown-web-1"));
+        registration.Name = Some(String::from("shutdown_web"));
+        let count = |path: &str| {
+            let requests = requests.lock().unwrap();
+            requests
+                .iter()
+                .filter(|req| req.path.starts_with(path))
+                .count()
+        };
+        let mut failing = registration.clone();
+        failing.ID = Some(String::from("shutdown-web-0"));
+        let err = block_on(async {
+            consul.service_register_guarded(&failing).await.unwrap();
+            consul
+                .service_register_guarded(&registration)
+                .await
+                .unwrap();
+            consul.spawn_watch_services().await;
+            consul
+                .spawn_heartbeat("service:shutdown-web-1", Duration::from_millis(10))
+                .await;
+            sleep(Duration::from_millis(100)).await;
+            consul
+                .clone()
+                .shutdown(Duration::from_secs(1))
+                .await
+                .unwrap_err()
+        });
+        assert_eq!(err.status(), 500);
+        assert!(err.to_string().contains("shutdown-web-0"));
+        let watches = count("/v1/health/service/shutdown_web");
+        let heartbeats = count("/v1/agent/check/pass/service:shutdown-web-1");
+        assert!(watches > 0);
+        assert!(heartbeats > 0);
+        assert_eq!(count("/v1/agent/service/deregister/shutdown-web-0"), 1);
+        assert_eq!(count("/v1/agent/service/deregister/shutdown-web-1"), 1);
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(count("/v1/health/service/shutdown_web"), watches);
+        assert_eq!(
+            count("/v1/agent/check/pass/service:shutdown-web-1"),
+            heartbeats
+        );
+    }
 
-    /// StaleIfError specifies how stale the client will accept a cached response
-    /// if the servers are unavailable to fetch a fresh one. Only makes sense when
-    /// UseCache is true and MaxAge is set to a lower, non-zero value. It is
-    /// ignored if the endpoint supports background refresh caching. See
-    /// https:///www.consul.io/api/features/caching.html for more details.
-    pub StaleIfError: Option<time::Duration>,
+    #[test]
+    fn test_agent_service_blocking() {
+        use crate::error::ConsulError;
+        use async_std::task::block_on;
+        use std::time::Duration;
+        let (address, requests) = mock_server(|req| {
+            let (port, hash) = match req.query.get("hash").map(String::as_str) {
+                None => (8080, "h1"),
+                Some("h1") => (8081, "h2"),
+                _ => return MockResponse::new(404, "unknown service ID: web-1"),
+            };
+            let body = format!(r#"{{"ID":"web-1","Service":"web","Port":{}}}"#, port);
+            let mut res = MockResponse::new(200, &body);
+            res.headers
+                .push((String::from("X-Consul-ContentHash"), hash.to_string()));
+            res
+        });
+        let consul = mock_consul(&address);
+        let mut hash = String::new();
+        let mut ports = vec![];
+        let err = block_on(async {
+            loop {
+                match consul
+                    .agent_service_blocking("web-1", &hash, Duration::from_secs(5))
+                    .await
+                {
+                    Ok((service, next)) => {
+                        ports.push(service.Port.unwrap());
+                        hash = next;
+                    }
+                    Err(err) => return err,
+                }
+            }
+        });
+        assert_eq!(ports, vec![8080, 8081]);
+        assert!(matches!(
+            err.downcast_ref::<ConsulError>(),
+            Some(ConsulError::ServiceNotFound(_))
+        ));
+        let requests = requests.lock().unwrap();
+        assert!(requests[0].query.get("wait").is_none());
+        assert_eq!(requests[1].query.get("wait").unwrap(), "5000ms");
+    }
 
-    /// WaitIndex is used to enable a blocking query. Waits
-    /// until the timeout or the next index is reached
-    pub WaitIndex: Option<usize>,
+    #[test]
+    fn test_catalog_service_with_health() {
+        use async_std::task::block_on;
+        let (address, requests) = mock_server(|req| match req.path.as_str() {
+            "/v1/catalog/service/web" => MockResponse::new(
+                200,
+                r#"[{"Node":"host1","ServiceID":"web-1","ServiceName":"web","ServicePort":80},{"Node":"host2","ServiceID":"web-2","ServiceName":"web","ServicePort":80}]"#,
+            ),
+            "/v1/health/checks/web" => MockResponse::new(
+                200,
+                r#"[{"Node":"host1","CheckID":"service:web-1","ServiceID":"web-1","Status":"passing"},{"Node":"host2","CheckID":"service:web-2","ServiceID":"web-2","Status":"critical","Output":"connection refused"}]"#,
+            ),
+            _ => MockResponse::new(404, ""),
+        });
+        let consul = mock_consul(&address);
+        let instances =
+            block_on(consul.catalog_service_with_health("web", None, Some("dc2"))).unwrap();
+        assert_eq!(instances.len(), 2);
+        assert_eq!(instances[0].AggregatedStatus, "passing");
+        assert_eq!(instances[1].Service.Node.as_deref(), Some("host2"));
+        assert_eq!(instances[1].AggregatedStatus, "critical");
+        let requests = requests.lock().unwrap();
+        assert!(requests
+            .iter()
+            .all(|req| req.query.get("dc").unwrap() == "dc2"));
+    }
 
-    /// WaitHash is used by some endpoints instead of WaitIndex to perform blocking
-    /// on state based on a hash of the response rather than a monotonic index.
-    /// This is required when the state being blocked on is not stored in Raft, for
-    /// example agent-local proxy configuration.
-    pub WaitHash: Option<String>,
+    #[test]
+    fn test_extra_headers() {
+        use crate::api::with_headers;
+        use async_std::task::block_on;
+        let (address, requests) = mock_server(|_| MockResponse::new(200, ""));
+        let mut consul = mock_consul(&address);
+        let mut extra_headers = HashMap::new();
+        extra_headers.insert(String::from("X-Vault-Namespace"), String::from("team-a"));
+        extra_headers.insert(String::from("X-Org-ID"), String::from("42"));
+        consul.config.as_mut().unwrap().extra_headers = Some(extra_headers);
+        block_on(consul.check_pass("web", "")).unwrap();
+        let mut headers = HashMap::new();
+        headers.insert(String::from("X-Vault-Namespace"), String::from("team-b"));
+        block_on(with_headers(headers, consul.check_pass("web", ""))).unwrap();
+        let requests = requests.lock().unwrap();
+        assert_eq!(
+            requests[0].headers.get("x-vault-namespace").unwrap(),
+            "team-a"
+        );
+        assert_eq!(requests[0].headers.get("x-org-id").unwrap(), "42");
+        assert_eq!(
+            requests[1].headers.get("x-vault-namespace").unwrap(),
+            "team-b"
+        );
+        assert_eq!(requests[1].headers.get("x-org-id").unwrap(), "42");
+    }
 
-    /// WaitTime is used to bound the duration of a wait.
-    /// Defaults to that of the Config, but can be overridden.
-    pub WaitTime: Option<time::Duration>,
+    #[test]
+    fn test_scoped_task_locals() {
+        use crate::api::{with_deadline, with_headers, DEADLINE, EXTRA_HEADERS};
+        use async_std::future::{pending, timeout};
+        use async_std::task::block_on;
+        use std::future::Future;
+        use std::task::{Context, Poll, Waker};
+        use std::time::{Duration, Instant};
+        let mut headers = HashMap::new();
+        headers.insert(String::from("X-Org-ID"), String::from("42"));
+        let cleared = block_on(async {
+            let cancelled = with_headers(headers.clone(), pending::<()>());
+            assert!(timeout(Duration::from_millis(10), cancelled).await.is_err());
+            let deadline = Instant::now() + Duration::from_secs(60);
+            let cancelled = with_deadline(deadline, pending::<()>());
+            assert!(timeout(Duration::from_millis(10), cancelled).await.is_err());
+            let headers = EXTRA_HEADERS.with(|extra| extra.borrow().is_empty());
+            headers && DEADLINE.with(|deadline| deadline.borrow().is_none())
+        });
+        assert!(cleared);
 
-    /// Token is used to provide a per-request ACL token
-    /// which overrides the agent's default token.
-    pub Token: Option<String>,
+        // outside of an async-std task the future runs without them.
+        let mut fut = Box::pin(with_headers(headers, async { 42 }));
+        let mut cx = Context::from_waker(Waker::noop());
+        assert_eq!(fut.as_mut().poll(&mut cx), Poll::Ready(42));
+    }
 
-    /// Near is used to provide a node name that will sort the results
-    /// in ascending order based on the estimated round trip time from
-    /// that node. Setting this to "_agent" will use the agent's node
-    /// for the sort.
-    pub Near: Option<String>,
+    #[test]
+    fn test_startup_jitter() {
+        use crate::agent::AgentServiceRegistration;
+        use crate::api::STARTUP_JITTER_UNTIL;
+        use async_std::task::{block_on, spawn};
+        use std::time::{Duration, Instant};
+        let arrivals = Arc::new(Mutex::new(vec![]));
+        let received = arrivals.clone();
+        let (address, requests) = mock_server(move |_| {
+            received.lock().unwrap().push(Instant::now());
+            MockResponse::new(200, "")
+        });
+        let mut consul = mock_consul(&address);
+        consul.config.as_mut().unwrap().startup_jitter = Some(300);
+        let mut service = AgentServiceRegistration::default();
+        service.ID = Some(String::from("jitter-1"));
+        let start = Instant::now();
+        let handles: Vec<_> = (0..2)
+            .map(|_| {
+                let consul = consul.clone();
+                let service = service.clone();
+                spawn(async move { consul.service_register(&service).await.unwrap() })
+            })
+            .collect();
+        for handle in handles {
+            block_on(handle);
+        }
+        assert!(start.elapsed() < Duration::from_millis(300 + 200));
+        assert_eq!(requests.lock().unwrap().len(), 2);
+        // the caller arriving during the delay waited for it too.
+        let until = block_on(STARTUP_JITTER_UNTIL.lock()).unwrap();
+        for arrival in arrivals.lock().unwrap().iter() {
+            assert!(*arrival >= until);
+        }
+        let start = Instant::now();
+        for _ in 0..5 {
+            block_on(consul.service_register(&service)).unwrap();
+        }
+        assert!(start.elapsed() < Duration::from_millis(300));
+    }
 
-    /// NodeMeta is used to filter results by nodes with the given
-    /// metadata key/value pairs. Currently, only one key/value pair can
-    /// be provided for filtering.
-    pub NodeMeta: Option<HashMap<String, String>>,
+    #[test]
+    fn test_resolve_circuit_breaker() {
+        use crate::circuit_breaker::{CircuitBreakerConfig, CircuitState};
+        use crate::error::ConsulError;
+        use crate::watch::WatchService;
+        use async_std::task::block_on;
+        let (address, requests) = mock_server(|_| MockResponse::new(200, "[]"));
+        let mut consul = mock_consul(&address);
+        consul.config.as_mut().unwrap().circuit_breaker = Some(CircuitBreakerConfig {
+            failure_threshold: 2,
+            cooldown: 60,
+        });
+        let mut service = WatchService::default();
+        service.service_name = String::from("breaker_web");
+        service.passing_only = Some(true);
+        block_on(consul.resolve(&service)).unwrap();
+        block_on(consul.resolve(&service)).unwrap();
+        let states = block_on(consul.circuit_states());
+        assert_eq!(states.get("breaker_web"), Some(&CircuitState::Open));
+        let err = block_on(consul.resolve(&service)).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<ConsulError>(),
+            Some(ConsulError::CircuitOpen(_))
+        ));
+        assert_eq!(requests.lock().unwrap().len(), 2);
+    }
 
-    /// RelayFactor is used in keyring operations to cause responses to be
-    /// relayed back to the sender through N other random nodes. Must be
-    /// a value from 0 to 5 (inclusive).
-    pub RelayFactor: Option<u8>,
+    #[test]
+    fn test_service_register_bulk() {
+        use crate::agent::AgentServiceRegistration;
+        use async_std::task::block_on;
+        use surf::StatusCode;
+        let (address, requests) = mock_server(|req| {
+            if req.body.contains(r#""ID":"bulk-bad""#) {
+                return MockResponse::new(400, "Invalid service address");
+            }
+            MockResponse::new(200, "")
+        });
+        let consul = mock_consul(&address);
+        let services: Vec<AgentServiceRegistration> = vec!["bulk-1", "bulk-bad", "bulk-2"]
+            .into_iter()
+            .map(|id| {
+                let mut service = AgentServiceRegistration::default();
+                service.ID = Some(id.to_string());
+                service.Name = Some(String::from("bulk"));
+                service
+            })
+            .collect();
+        let out = block_on(consul.service_register_bulk(&services)).unwrap();
+        assert_eq!(
+            out,
+            vec![
+                (String::from("bulk-1"), StatusCode::Ok),
+                (String::from("bulk-bad"), StatusCode::BadRequest),
+                (String::from("bulk-2"), StatusCode::Ok),
+            ]
+        );
+        let opts = Default::default();
+        block_on(consul.service_register_bulk_opts(&services, &opts, true)).unwrap();
+        let requests = requests.lock().unwrap();
+        let mut deregistered: Vec<&str> = requests
+            .iter()
+            .filter_map(|req| req.path.strip_prefix("/v1/agent/service/deregister/"))
+            .collect();
+        deregistered.sort();
+        assert_eq!(deregistered, vec!["bulk-1", "bulk-2"]);
+    }
 
-    /// LocalOnly is used in keyring list operation to force the keyring
-    /// query to only hit local servers (no WAN traffic).
-    pub LocalOnly: Option<bool>,
+    #[test]
+    fn test_service_register_bulk_rollback_failure() {
+        use crate::agent::AgentServiceRegistration;
+        use async_std::task::block_on;
+        let (address, requests) = mock_server(|req| {
+            if req.body.contains(r#""ID":"bulk-bad""#) {
+                return MockResponse::new(400, "Invalid service address");
+            }
+            if req.path == "/v1/agent/service/deregister/bulk-1" {
+                return MockResponse::new(500, "Unknown service ID");
+            }
+            MockResponse::new(200, "")
+        });
+        let consul = mock_consul(&address);
+        let services: Vec<AgentServiceRegistration> = vec!["bulk-1", "bulk-bad", "bulk-2", ""]
+            .into_iter()
+            .map(|id| AgentServiceRegistration {
+                ID: Some(id.to_string()),
+                Name: Some(String::from("bulk")),
+                ..AgentServiceRegistration::default()
+            })
+            .collect();
+        let opts = Default::default();
+        let err = block_on(consul.service_register_bulk_opts(&services, &opts, true)).unwrap_err();
+        assert_eq!(err.status(), 500);
+        assert!(err.to_string().contains("bulk-1"));
+        let requests = requests.lock().unwrap();
+        let mut deregistered: Vec<&str> = requests
+            .iter()
+            .filter_map(|req| req.path.strip_prefix("/v1/agent/service/deregister/"))
+            .collect();
+        deregistered.sort();
+        assert_eq!(deregistered, vec!["bulk-1", "bulk-2"]);
+    }
 
-    /// Connect filters prepared query execution to only include Connect-capable
-    /// services. This currently affects prepared query execution.
-    pub Connect: Option<bool>,
+    #[test]
+    fn test_service_register_bulk_default_id() {
+        use crate::agent::{AgentServiceRegistration, ServiceRegisterOpts};
+        use crate::api::hostname;
+        use async_std::task::block_on;
+        use surf::StatusCode;
+        let (address, requests) = mock_server(|req| {
+            if req.body.contains(r#""Name":"bulk-bad""#) {
+                return MockResponse::new(400, "Invalid service address");
+            }
+            MockResponse::new(200, "")
+        });
+        let consul = mock_consul(&address);
+        let services: Vec<AgentServiceRegistration> = vec!["bulk-web", "bulk-bad"]
+            .into_iter()
+            .map(|name| AgentServiceRegistration {
+                Name: Some(name.to_string()),
+                Port: Some(8080),
+                ..AgentServiceRegistration::default()
+            })
+            .collect();
+        let opts = ServiceRegisterOpts {
+            DefaultID: true,
+            ..ServiceRegisterOpts::default()
+        };
+        let out = block_on(consul.service_register_bulk_opts(&services, &opts, true)).unwrap();
+        let web = format!("bulk-web-{}-8080", hostname());
+        let bad = format!("bulk-bad-{}-8080", hostname());
+        assert_eq!(
+            out,
+            vec![(web.clone(), StatusCode::Ok), (bad, StatusCode::BadRequest)]
+        );
+        let requests = requests.lock().unwrap();
+        let deregistered: Vec<&str> = requests
+            .iter()
+            .filter_map(|req| req.path.strip_prefix("/v1/agent/service/deregister/"))
+            .collect();
+        assert_eq!(deregistered, vec![web.as_str()]);
+    }
 
-    /// Filter requests filtering data prior to it being returned. The string
-    /// is a go-bexpr compatible expression.
-    pub Filter: Option<String>,
-}
+    #[test]
+    fn test_agent_services_filter() {
+        use crate::api::{set_query_options, QueryOptions};
+        use async_std::task::block_on;
+        let mut opts = QueryOptions::default();
+        opts.Filter = Some(String::from(r#""web" in Service.Tags"#));
+        let url = surf::Url::parse("http://127.0.0.1:8500/v1/agent/services").unwrap();
+        let mut req = surf::Request::new(surf::http::Method::Get, url);
+        set_query_options(&mut req, &opts).unwrap();
+        assert_eq!(req.url().query(), Some("filter=%22web%22+in+Service.Tags"));
 
-#[cfg(test)]
-mod tests {
-    use crate::api::{SERVICES_ADDRESS, Config};
+        let (address, requests) = mock_server(|req| match req.path.as_str() {
+            "/v1/agent/services" => MockResponse::new(
+                200,
+                r#"{"web-1":{"ID":"web-1","Service":"web","Tags":["web"],"Port":80}}"#,
+            ),
+            _ => MockResponse::new(
+                200,
+                r#"{"service:web-1":{"CheckID":"service:web-1","Status":"passing","ServiceID":"web-1"}}"#,
+            ),
+        });
+        let consul = mock_consul(&address);
+        let services = block_on(consul.agent_services_opts(&opts)).unwrap();
+        assert_eq!(services["web-1"].Port, Some(80));
+        let checks = block_on(consul.agent_checks_opts(&opts)).unwrap();
+        assert_eq!(checks["service:web-1"].Status.as_deref(), Some("passing"));
+        let requests = requests.lock().unwrap();
+        assert!(requests
+            .iter()
+            .all(|req| req.query.get("filter").unwrap() == r#""web" in Service.Tags"#));
+    }
 
     #[test]
     fn it_works() {
@@ -518,6 +5397,7 @@ mod tests {
     }
 
     pub fn test_watch_services() {
+        use crate::api::QueryOptions;
         use crate::api::CONSUL_CONFIG;
         use crate::watch::WatchService;
         use async_std::task::block_on;
@@ -531,7 +5411,81 @@ mod tests {
         service.service_name = String::from("hyat_rust");
         service.passing_only = Some(true);
         // consul.watch_services = Some(vec![service]);
-        let s = block_on(consul.get_address(&service)).unwrap();
+        let s = block_on(consul.get_address(&service, &QueryOptions::default())).unwrap();
         println!("{:?}", s);
     }
+
+    #[test]
+    fn test_new_request_query_and_token() {
+        use async_std::task::block_on;
+        use surf::http::Method;
+        let (address, requests) = mock_server(|_| MockResponse::new(200, "[]"));
+        let mut consul = mock_consul(&address);
+        if let Some(config) = consul.config.as_mut() {
+            config.datacenter = Some(String::from("dc1"));
+            config.namespace = Some(String::from("team"));
+            config.token = Some(String::from("secret"));
+        }
+        let mut req = block_on(consul.new_request(Method::Get, "/v1/agent/self")).unwrap();
+        let query: HashMap<String, String> = req.url().query_pairs().into_owned().collect();
+        assert_eq!(query.get("dc").unwrap(), "dc1");
+        assert_eq!(query.get("ns").unwrap(), "team");
+        assert!(!query.contains_key("wait"));
+        assert_eq!(req.header("X-Consul-Token").unwrap().as_str(), "secret");
+        assert_eq!(block_on(req.take_body().into_string()).unwrap(), "");
+
+        block_on(consul.resolve_federated("web", None, &["dc2"])).unwrap();
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests[0].query.get("dc").unwrap(), "dc2");
+        assert_eq!(requests[0].query.get("ns").unwrap(), "team");
+        assert_eq!(requests[0].headers.get("x-consul-token").unwrap(), "secret");
+        assert!(requests[0].body.is_empty());
+    }
+
+    #[test]
+    fn test_resolve_federated() {
+        use async_std::task::block_on;
+        let (address, requests) = mock_server(|req| {
+            match req.query.get("dc").map(String::as_str) {
+                Some("dc2") => MockResponse::new(
+                    200,
+                    r#"[{"Service":{"Address":"10.0.2.1","Port":8080}},{"Service":{"Address":"10.0.2.2","Port":8080}}]"#,
+                ),
+                _ => MockResponse::new(200, "[]"),
+            }
+        });
+        let consul = mock_consul(&address);
+        let addresses = block_on(consul.resolve_federated("web", None, &["dc1", "dc2"])).unwrap();
+        assert_eq!(addresses, vec!["10.0.2.1:8080", "10.0.2.2:8080"]);
+        {
+            let requests = requests.lock().unwrap();
+            assert_eq!(requests.len(), 2);
+            assert_eq!(requests[0].method, "GET");
+            assert_eq!(requests[0].path, "/v1/health/service/web");
+            assert_eq!(requests[0].query.get("dc").unwrap(), "dc1");
+            assert_eq!(requests[1].query.get("passing").unwrap(), "1");
+        }
+
+        let addresses = block_on(consul.resolve_federated("web", None, &["dc1"])).unwrap();
+        assert!(addresses.is_empty());
+    }
+
+    #[test]
+    fn test_resolve_federated_unreachable() {
+        use async_std::task::block_on;
+        let (address, requests) =
+            mock_server(|req| match req.query.get("dc").map(String::as_str) {
+                Some("dc2") => {
+                    MockResponse::new(200, r#"[{"Service":{"Address":"10.0.2.1","Port":8080}}]"#)
+                }
+                _ => MockResponse::new(500, "No path to datacenter"),
+            });
+        let consul = mock_consul(&address);
+        let addresses = block_on(consul.resolve_federated("web", None, &["dc1", "dc2"])).unwrap();
+        assert_eq!(addresses, vec!["10.0.2.1:8080"]);
+        assert_eq!(requests.lock().unwrap().len(), 2);
+
+        let err = block_on(consul.resolve_federated("web", None, &["dc1", "dc3"])).unwrap_err();
+        assert_eq!(err.status(), 500);
+    }
 }
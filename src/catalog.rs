@@ -1,3 +1,5 @@
+use super::agent;
+use super::health;
 use serde_derive::{Deserialize, Serialize};
 use std::collections::HashMap;
 
@@ -20,3 +22,99 @@ pub struct ServiceAddress {
     pub Address: Option<String>,
     pub Port: Option<usize>,
 }
+
+/// CatalogService is a service instance as registered in the catalog.
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[allow(non_snake_case)]
+pub struct CatalogService {
+    pub ID: Option<String>,
+    pub Node: Option<String>,
+    pub Address: Option<String>,
+    pub Datacenter: Option<String>,
+    pub TaggedAddresses: Option<HashMap<String, String>>,
+    pub NodeMeta: Option<HashMap<String, String>>,
+    pub ServiceID: Option<String>,
+    pub ServiceName: Option<String>,
+    pub ServiceAddress: Option<String>,
+    pub ServiceTaggedAddresses: Option<HashMap<String, ServiceAddress>>,
+    pub ServiceTags: Option<Vec<String>>,
+    pub ServiceMeta: Option<HashMap<String, String>>,
+    pub ServicePort: Option<usize>,
+    pub ServiceWeights: Option<agent::AgentWeights>,
+    pub ServiceEnableTagOverride: Option<bool>,
+    pub ServiceProxy: Option<agent::AgentServiceConnectProxyConfig>,
+    pub CreateIndex: Option<u64>,
+    pub ModifyIndex: Option<u64>,
+    pub Namespace: Option<String>,
+}
+
+/// CatalogRegistration registers a node, service or check directly in the
+/// catalog, e.g. for an external service that has no agent. SkipNodeUpdate
+/// registers the service without overwriting the existing node entry.
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[allow(non_snake_case)]
+pub struct CatalogRegistration {
+    pub ID: Option<String>,
+    pub Node: Option<String>,
+    pub Address: Option<String>,
+    pub TaggedAddresses: Option<HashMap<String, String>>,
+    pub NodeMeta: Option<HashMap<String, String>>,
+    pub Datacenter: Option<String>,
+    pub Service: Option<agent::AgentService>,
+    pub Check: Option<agent::AgentCheck>,
+    pub Checks: Option<health::HealthChecks>,
+    pub SkipNodeUpdate: Option<bool>,
+    pub Namespace: Option<String>,
+}
+
+/// UiService is the summary of a service returned by the UI endpoint, with
+/// the check counts of all its instances.
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[allow(non_snake_case)]
+pub struct UiService {
+    pub Name: Option<String>,
+    pub Datacenter: Option<String>,
+    pub Kind: Option<agent::ServiceKind>,
+    pub Tags: Option<Vec<String>>,
+    /// Nodes is the names of the nodes running an instance.
+    pub Nodes: Option<Vec<String>>,
+    pub InstanceCount: Option<usize>,
+    pub ChecksPassing: Option<usize>,
+    pub ChecksWarning: Option<usize>,
+    pub ChecksCritical: Option<usize>,
+    pub ExternalSources: Option<Vec<String>>,
+    pub ConnectedWithProxy: Option<bool>,
+    pub ConnectedWithGateway: Option<bool>,
+    pub Namespace: Option<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::agent::{AgentCheck, AgentService};
+    use crate::catalog::CatalogRegistration;
+
+    #[test]
+    fn test_catalog_registration_skip_node_update() {
+        let registration = CatalogRegistration {
+            Node: Some(String::from("external")),
+            Address: Some(String::from("10.0.8.1")),
+            Service: Some(AgentService {
+                ID: Some(String::from("search-1")),
+                Service: Some(String::from("search")),
+                Port: Some(443),
+                ..AgentService::default()
+            }),
+            Check: Some(AgentCheck {
+                CheckID: Some(String::from("service:search-1")),
+                Status: Some(String::from("passing")),
+                ..AgentCheck::default()
+            }),
+            SkipNodeUpdate: Some(true),
+            ..CatalogRegistration::default()
+        };
+        let json = serde_json::to_value(&registration).unwrap();
+        assert_eq!(json["SkipNodeUpdate"], true);
+        assert_eq!(json["Service"]["Service"], "search");
+        assert_eq!(json["Check"]["Status"], "passing");
+    }
+}
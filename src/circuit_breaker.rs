@@ -0,0 +1,138 @@
+use serde_derive::{Deserialize, Serialize};
+use std::time::{Duration, Instant};
+
+/// DEFAULT_FAILURE_THRESHOLD is the failure_threshold of a default
+/// CircuitBreakerConfig.
+pub const DEFAULT_FAILURE_THRESHOLD: u32 = 5;
+/// DEFAULT_COOLDOWN is the cooldown, in seconds, of a default
+/// CircuitBreakerConfig.
+pub const DEFAULT_COOLDOWN: u64 = 30;
+
+/// CircuitBreakerConfig configures the circuit breaker around `resolve`.
+/// Fields left out of a config file take their default.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CircuitBreakerConfig {
+    /// FailureThreshold is the number of consecutive failed resolves, or
+    /// resolves with no passing instance, that opens the circuit. 0 opens it
+    /// on the first failure. Defaults to DEFAULT_FAILURE_THRESHOLD.
+    pub failure_threshold: u32,
+
+    /// Cooldown is how long, in seconds, the circuit stays open before a
+    /// single probe is let through. Defaults to DEFAULT_COOLDOWN.
+    pub cooldown: u64,
+}
+
+impl Default for CircuitBreakerConfig {
+    fn default() -> Self {
+        CircuitBreakerConfig {
+            failure_threshold: DEFAULT_FAILURE_THRESHOLD,
+            cooldown: DEFAULT_COOLDOWN,
+        }
+    }
+}
+
+/// CircuitState is the state of a service's circuit breaker.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CircuitState {
+    /// Closed lets every resolve through.
+    #[default]
+    Closed,
+    /// Open fails every resolve fast until the cooldown has passed.
+    Open,
+    /// HalfOpen lets a single probe through to decide whether to close again,
+    /// and another one each cooldown until a probe reports back.
+    HalfOpen,
+}
+
+/// CircuitBreaker tracks the consecutive resolve failures of a service.
+#[derive(Default, Debug, Clone)]
+pub struct CircuitBreaker {
+    pub state: CircuitState,
+    pub consecutive_failures: u32,
+    pub opened_at: Option<Instant>,
+    pub probe_at: Option<Instant>,
+}
+
+impl CircuitBreaker {
+    /// allow reports whether a resolve may go through, moving an open circuit
+    /// to half-open once the cooldown has passed. A half-open circuit lets
+    /// another probe through when the last one hasn't reported back within
+    /// the cooldown, e.g. because it was cancelled.
+    pub fn allow(&mut self, cooldown: Duration) -> bool {
+        let since = match self.state {
+            CircuitState::Closed => return true,
+            CircuitState::HalfOpen => self.probe_at,
+            CircuitState::Open => self.opened_at,
+        };
+        let cooled = since.is_none_or(|since| since.elapsed() >= cooldown);
+        if cooled {
+            self.state = CircuitState::HalfOpen;
+            self.probe_at = Some(Instant::now());
+        }
+        cooled
+    }
+
+    pub fn record_success(&mut self) {
+        self.state = CircuitState::Closed;
+        self.consecutive_failures = 0;
+        self.opened_at = None;
+        self.probe_at = None;
+    }
+
+    pub fn record_failure(&mut self, failure_threshold: u32) {
+        self.consecutive_failures += 1;
+        if self.state == CircuitState::HalfOpen || self.consecutive_failures >= failure_threshold {
+            self.state = CircuitState::Open;
+            self.opened_at = Some(Instant::now());
+            self.probe_at = None;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::circuit_breaker::{CircuitBreaker, CircuitBreakerConfig, CircuitState};
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn test_circuit_breaker() {
+        let cooldown = Duration::from_millis(50);
+        let mut breaker = CircuitBreaker::default();
+        assert!(breaker.allow(cooldown));
+        breaker.record_failure(2);
+        assert_eq!(breaker.state, CircuitState::Closed);
+        breaker.record_failure(2);
+        assert_eq!(breaker.state, CircuitState::Open);
+        assert!(!breaker.allow(cooldown));
+
+        thread::sleep(cooldown);
+        assert!(breaker.allow(cooldown));
+        assert_eq!(breaker.state, CircuitState::HalfOpen);
+        assert!(!breaker.allow(cooldown));
+        // the probe never reported back, so another one is let through.
+        thread::sleep(cooldown);
+        assert!(breaker.allow(cooldown));
+        assert_eq!(breaker.state, CircuitState::HalfOpen);
+        assert!(!breaker.allow(cooldown));
+        breaker.record_failure(2);
+        assert_eq!(breaker.state, CircuitState::Open);
+
+        thread::sleep(cooldown);
+        assert!(breaker.allow(cooldown));
+        breaker.record_success();
+        assert_eq!(breaker.state, CircuitState::Closed);
+        assert_eq!(breaker.consecutive_failures, 0);
+    }
+
+    #[test]
+    fn test_circuit_breaker_config_default() {
+        let config: CircuitBreakerConfig = serde_json::from_str(r#"{"cooldown":10}"#).unwrap();
+        assert_eq!(config.failure_threshold, 5);
+        assert_eq!(config.cooldown, 10);
+        let config = CircuitBreakerConfig::default();
+        assert_eq!(config.failure_threshold, 5);
+        assert_eq!(config.cooldown, 30);
+    }
+}
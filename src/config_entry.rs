@@ -1,56 +1,62 @@
-use lazy_static::lazy_static;
+use crate::duration::GoDuration;
 use serde_derive::{Deserialize, Serialize};
-pub type ProxyMode = String;
-
-lazy_static!(
-    /// ProxyModeDefault represents no specific mode and should
-    /// be used to indicate that a different layer of the configuration
-    /// chain should take precedence
-    pub static ref PROXY_MODE_DEFAULT: ProxyMode = {
-        String::new()
-    };
+use serde_json::Value;
+use std::collections::HashMap;
 
-    /// ProxyModeTransparent represents that inbound and outbound application
-    /// traffic is being captured and redirected through the proxy.
-    pub static ref PROXY_MODE_TRANSPARENT: ProxyMode = {
-        String::from("transparent")
-    };
+pub const SERVICE_DEFAULTS: &str = "service-defaults";
+pub const PROXY_DEFAULTS: &str = "proxy-defaults";
+pub const EXPORTED_SERVICES: &str = "exported-services";
 
-    /// ProxyModeDirect represents that the proxy's listeners must be dialed directly
-    /// by the local application and other proxies.
-    pub static ref PROXY_MODE_DIRECT: ProxyMode = {
-        String::from("direct")
-    };
-);
+/// PROXY_CONFIG_GLOBAL is the only name a `proxy-defaults` entry may have.
+pub const PROXY_CONFIG_GLOBAL: &str = "global";
 
-pub type MeshGatewayMode = String;
+/// EXPORTED_SERVICES_DEFAULT_PARTITION is the name of an `exported-services`
+/// entry on Consul OSS, where the only partition is `default`.
+pub const EXPORTED_SERVICES_DEFAULT_PARTITION: &str = "default";
 
-lazy_static!(
-    /// MeshGatewayModeDefault represents no specific mode and should
-    /// be used to indicate that a different layer of the configuration
-    /// chain should take precedence
-    pub static ref MESH_GATEWAY_MODE_DEFAULT: MeshGatewayMode = {
-        String::new()
-    };
+string_enum! {
+    /// ProxyMode is the mode a Connect proxy runs in.
+    ProxyMode, default = Default {
+        /// ProxyModeDefault represents no specific mode and should
+        /// be used to indicate that a different layer of the configuration
+        /// chain should take precedence
+        Default => "",
+        /// ProxyModeTransparent represents that inbound and outbound application
+        /// traffic is being captured and redirected through the proxy.
+        Transparent => "transparent",
+        /// ProxyModeDirect represents that the proxy's listeners must be dialed directly
+        /// by the local application and other proxies.
+        Direct => "direct",
+    }
+}
 
-    /// MeshGatewayModeNone represents that the Upstream Connect connections
-    /// should be direct and not flow through a mesh gateway.
-    pub static ref MESH_GATEWAY_MODE_NONE: MeshGatewayMode = {
-        String::from("none")
-    };
+pub const PROXY_MODE_DEFAULT: ProxyMode = ProxyMode::Default;
+pub const PROXY_MODE_TRANSPARENT: ProxyMode = ProxyMode::Transparent;
+pub const PROXY_MODE_DIRECT: ProxyMode = ProxyMode::Direct;
 
-    /// MeshGatewayModeLocal represents that the Upstream Connect connections
-    /// should be made to a mesh gateway in the local datacenter.
-    pub static ref MESH_GATEWAY_MODE_LOCAL: MeshGatewayMode = {
-        String::from("local")
-    };
+string_enum! {
+    /// MeshGatewayMode is the mode used for upstream connections through mesh gateways.
+    MeshGatewayMode, default = Default {
+        /// MeshGatewayModeDefault represents no specific mode and should
+        /// be used to indicate that a different layer of the configuration
+        /// chain should take precedence
+        Default => "",
+        /// MeshGatewayModeNone represents that the Upstream Connect connections
+        /// should be direct and not flow through a mesh gateway.
+        None => "none",
+        /// MeshGatewayModeLocal represents that the Upstream Connect connections
+        /// should be made to a mesh gateway in the local datacenter.
+        Local => "local",
+        /// MeshGatewayModeRemote represents that the Upstream Connect connections
+        /// should be made to a mesh gateway in a remote datacenter.
+        Remote => "remote",
+    }
+}
 
-    /// MeshGatewayModeRemote represents that the Upstream Connect connections
-    /// should be made to a mesh gateway in a remote datacenter.
-    pub static ref MESH_GATEWAY_MODE_REMOTE: MeshGatewayMode = {
-        String::from("remote")
-    };
-);
+pub const MESH_GATEWAY_MODE_DEFAULT: MeshGatewayMode = MeshGatewayMode::Default;
+pub const MESH_GATEWAY_MODE_NONE: MeshGatewayMode = MeshGatewayMode::None;
+pub const MESH_GATEWAY_MODE_LOCAL: MeshGatewayMode = MeshGatewayMode::Local;
+pub const MESH_GATEWAY_MODE_REMOTE: MeshGatewayMode = MeshGatewayMode::Remote;
 
 /// MeshGatewayConfig controls how Mesh Gateways are used for upstream Connect services
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
@@ -60,6 +66,19 @@ pub struct MeshGatewayConfig {
     pub Mode: Option<MeshGatewayMode>,
 }
 
+impl MeshGatewayConfig {
+    /// validate rejects a mesh gateway mode unknown to Consul.
+    pub fn validate(&self) -> surf::Result<()> {
+        match self.Mode.as_ref() {
+            Some(mode) if !mode.is_valid() => Err(surf::Error::from_str(
+                surf::StatusCode::BadRequest,
+                format!("invalid mesh gateway mode {:?}", mode.as_str()),
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
 /// ExposeConfig describes HTTP paths to expose through Envoy outside of Connect.
 /// Users can expose individual paths and/or all HTTP/GRPC paths for checks.
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
@@ -92,3 +111,341 @@ pub struct ExposePath {
     /// ParsedFromCheck is set if this path was parsed from a registered check
     pub ParsedFromCheck: Option<bool>,
 }
+
+/// TransparentProxyConfig configures the transparent proxy mode of a service.
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[allow(non_snake_case)]
+pub struct TransparentProxyConfig {
+    /// The port of the listener where outbound application traffic is being redirected to.
+    pub OutboundListenerPort: Option<usize>,
+
+    /// DialedDirectly indicates whether transparent proxies can dial this proxy instance directly.
+    /// The discovery chain is not considered when dialing a service instance directly.
+    /// This setting is useful when addressing stateful services, such as a database cluster with a leader node.
+    pub DialedDirectly: Option<bool>,
+}
+
+/// UpstreamLimits describes the connection limits applied to an upstream.
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[allow(non_snake_case)]
+pub struct UpstreamLimits {
+    /// MaxConnections is the maximum number of connections the local proxy can
+    /// make to the upstream service.
+    pub MaxConnections: Option<usize>,
+
+    /// MaxPendingRequests is the maximum number of requests that will be queued
+    /// waiting for an available connection. This is mostly applicable to HTTP/1.1
+    /// clusters since all HTTP/2 requests are streamed over a single
+    /// connection.
+    pub MaxPendingRequests: Option<usize>,
+
+    /// MaxConcurrentRequests is the maximum number of in-flight requests that will be allowed
+    /// to the upstream cluster at a point in time. This is mostly applicable to HTTP/2
+    /// clusters since all HTTP/1.1 requests are limited by MaxConnections.
+    pub MaxConcurrentRequests: Option<usize>,
+}
+
+/// PassiveHealthCheck configures outlier detection for an upstream.
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[allow(non_snake_case)]
+pub struct PassiveHealthCheck {
+    /// Interval between health check analysis sweeps. Each sweep may remove
+    /// hosts or return hosts to the pool.
+    pub Interval: Option<GoDuration>,
+
+    /// MaxFailures is the count of consecutive failures that results in a host
+    /// being removed from the pool.
+    pub MaxFailures: Option<usize>,
+}
+
+/// UpstreamConfig is the proxy configuration applied to an upstream.
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[allow(non_snake_case)]
+pub struct UpstreamConfig {
+    /// Name is only accepted within a service-defaults config entry.
+    pub Name: Option<String>,
+
+    /// Namespace is only accepted within a service-defaults config entry.
+    pub Namespace: Option<String>,
+
+    pub EnvoyListenerJSON: Option<String>,
+
+    pub EnvoyClusterJSON: Option<String>,
+
+    /// Protocol describes the upstream's service protocol. Valid values are "tcp",
+    /// "http" and "grpc". Anything else is treated as tcp. The enables protocol
+    /// aware features like per-request metrics and connection pooling, tracing,
+    /// routing etc.
+    pub Protocol: Option<String>,
+
+    /// ConnectTimeoutMs is the number of milliseconds to timeout making a new
+    /// connection to this upstream. Defaults to 5000 (5 seconds) if not set.
+    pub ConnectTimeoutMs: Option<usize>,
+
+    /// Limits are the set of limits that are applied to the proxy for a specific upstream of a
+    /// service instance.
+    pub Limits: Option<UpstreamLimits>,
+
+    /// PassiveHealthCheck configuration determines how upstream proxy instances will
+    /// be monitored for removal from the load balancing pool.
+    pub PassiveHealthCheck: Option<PassiveHealthCheck>,
+
+    /// MeshGatewayConfig controls how Mesh Gateways are configured and used
+    pub MeshGateway: Option<MeshGatewayConfig>,
+}
+
+/// UpstreamConfiguration holds the per-upstream overrides and the defaults
+/// applied to every upstream of a service.
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[allow(non_snake_case)]
+pub struct UpstreamConfiguration {
+    /// Overrides is a slice of per-service configuration. The name field is
+    /// required.
+    pub Overrides: Option<Vec<UpstreamConfig>>,
+
+    /// Defaults contains default configuration for all upstreams of a given
+    /// service. The name field must be empty.
+    pub Defaults: Option<UpstreamConfig>,
+}
+
+/// ServiceDefaults is the `service-defaults` config entry, which sets the
+/// defaults applied to every instance of a service, such as its protocol.
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[allow(non_snake_case)]
+pub struct ServiceDefaults {
+    pub Kind: Option<String>,
+    pub Name: Option<String>,
+    pub Namespace: Option<String>,
+    pub Protocol: Option<String>,
+    pub Mode: Option<ProxyMode>,
+    pub TransparentProxy: Option<TransparentProxyConfig>,
+    pub MeshGateway: Option<MeshGatewayConfig>,
+    pub Expose: Option<ExposeConfig>,
+    pub ExternalSNI: Option<String>,
+    pub UpstreamConfig: Option<UpstreamConfiguration>,
+    pub Meta: Option<HashMap<String, String>>,
+}
+
+impl ServiceDefaults {
+    /// validate checks the entry's mesh gateway modes before it is sent.
+    pub fn validate(&self) -> surf::Result<()> {
+        if let Some(mesh_gateway) = self.MeshGateway.as_ref() {
+            mesh_gateway.validate()?;
+        }
+        if let Some(upstream_config) = self.UpstreamConfig.as_ref() {
+            let overrides = upstream_config.Overrides.iter().flatten();
+            for upstream in overrides.chain(upstream_config.Defaults.iter()) {
+                if let Some(mesh_gateway) = upstream.MeshGateway.as_ref() {
+                    mesh_gateway.validate()?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// ProxyDefaults is the cluster-wide `proxy-defaults` config entry. Its Kind
+/// and Name are always `proxy-defaults` and `global` when applied.
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[allow(non_snake_case)]
+pub struct ProxyDefaults {
+    pub Kind: Option<String>,
+    pub Name: Option<String>,
+    pub Namespace: Option<String>,
+    pub Config: Option<HashMap<String, Value>>,
+    pub Mode: Option<ProxyMode>,
+    pub TransparentProxy: Option<TransparentProxyConfig>,
+    pub MeshGateway: Option<MeshGatewayConfig>,
+    pub Expose: Option<ExposeConfig>,
+    pub Meta: Option<HashMap<String, String>>,
+}
+
+impl ProxyDefaults {
+    /// validate checks the entry's mesh gateway mode before it is sent.
+    pub fn validate(&self) -> surf::Result<()> {
+        if let Some(mesh_gateway) = self.MeshGateway.as_ref() {
+            mesh_gateway.validate()?;
+        }
+        Ok(())
+    }
+}
+
+/// ExportedServices is the `exported-services` config entry, listing the
+/// services of a partition made available to peers or other partitions.
+/// Its Name is the partition.
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[allow(non_snake_case)]
+pub struct ExportedServices {
+    pub Kind: Option<String>,
+    pub Name: Option<String>,
+    pub Partition: Option<String>,
+    pub Services: Option<Vec<ExportedService>>,
+    pub Meta: Option<HashMap<String, String>>,
+    pub CreateIndex: Option<u64>,
+    pub ModifyIndex: Option<u64>,
+}
+
+/// ExportedService is a service exported to the Consumers.
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[allow(non_snake_case)]
+pub struct ExportedService {
+    /// Name is the name of the service, or `*` for every service.
+    pub Name: Option<String>,
+    pub Namespace: Option<String>,
+    pub Consumers: Option<Vec<ServiceConsumer>>,
+}
+
+/// ServiceConsumer is a peer or a partition a service is exported to. Exactly
+/// one of Peer and Partition is set.
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[allow(non_snake_case)]
+pub struct ServiceConsumer {
+    pub Peer: Option<String>,
+    pub Partition: Option<String>,
+}
+
+impl ExportedServices {
+    /// validate checks that each consumer names exactly one peer or partition.
+    pub fn validate(&self) -> surf::Result<()> {
+        for service in self.Services.iter().flatten() {
+            for consumer in service.Consumers.iter().flatten() {
+                if consumer.Peer.is_some() == consumer.Partition.is_some() {
+                    return Err(surf::Error::from_str(
+                        surf::StatusCode::BadRequest,
+                        format!(
+                            "consumer of exported service {:?} must set exactly one of Peer or Partition",
+                            service.Name.as_deref().unwrap_or_default()
+                        ),
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config_entry::{
+        ExportedServices, MeshGatewayMode, ProxyDefaults, ProxyMode, ServiceDefaults,
+    };
+
+    #[test]
+    fn test_exported_services_serde() {
+        let doc = r#"{
+            "Kind": "exported-services",
+            "Name": "default",
+            "Services": [
+                {"Name": "api", "Consumers": [{"Peer": "cluster-02"}, {"Peer": "cluster-03"}]},
+                {"Name": "*", "Namespace": "frontend", "Consumers": [{"Partition": "west"}]}
+            ]
+        }"#;
+        let entry: ExportedServices = serde_json::from_str(doc).unwrap();
+        assert!(entry.validate().is_ok());
+        let services = entry.Services.as_ref().unwrap();
+        assert_eq!(services[0].Name.as_deref(), Some("api"));
+        let consumers = services[0].Consumers.as_ref().unwrap();
+        assert_eq!(consumers[1].Peer.as_deref(), Some("cluster-03"));
+
+        let value = serde_json::to_value(&entry).unwrap();
+        let back: ExportedServices = serde_json::from_value(value.clone()).unwrap();
+        assert_eq!(serde_json::to_value(&back).unwrap(), value);
+        assert_eq!(value["Kind"], "exported-services");
+        assert_eq!(value["Name"], "default");
+        assert_eq!(value["Services"][0]["Consumers"][0]["Peer"], "cluster-02");
+        assert_eq!(value["Services"][1]["Namespace"], "frontend");
+        assert_eq!(value["Services"][1]["Consumers"][0]["Partition"], "west");
+
+        let entry: ExportedServices = serde_json::from_str(
+            r#"{"Services":[{"Name":"api","Consumers":[{"Peer":"cluster-02","Partition":"west"}]}]}"#,
+        )
+        .unwrap();
+        assert!(entry.validate().is_err());
+    }
+
+    #[test]
+    fn test_service_defaults_serde() {
+        let doc = r#"{
+            "Kind": "service-defaults",
+            "Name": "web",
+            "Protocol": "http",
+            "Mode": "transparent",
+            "TransparentProxy": {"OutboundListenerPort": 15001, "DialedDirectly": true},
+            "MeshGateway": {"Mode": "local"},
+            "Expose": {"Checks": true, "Paths": [{"Path": "/metrics", "LocalPathPort": 8080, "ListenerPort": 21500}]},
+            "UpstreamConfig": {
+                "Overrides": [{"Name": "db", "Protocol": "grpc", "ConnectTimeoutMs": 1000}],
+                "Defaults": {"ConnectTimeoutMs": 5000, "Limits": {"MaxConnections": 512}, "PassiveHealthCheck": {"Interval": "10s", "MaxFailures": 5}}
+            }
+        }"#;
+        let entry: ServiceDefaults = serde_json::from_str(doc).unwrap();
+        assert_eq!(entry.Protocol.as_deref(), Some("http"));
+        assert_eq!(
+            entry
+                .TransparentProxy
+                .as_ref()
+                .unwrap()
+                .OutboundListenerPort,
+            Some(15001)
+        );
+        let upstreams = entry.UpstreamConfig.as_ref().unwrap();
+        assert_eq!(
+            upstreams.Overrides.as_ref().unwrap()[0].Name.as_deref(),
+            Some("db")
+        );
+        let defaults = upstreams.Defaults.as_ref().unwrap();
+        assert_eq!(defaults.Limits.as_ref().unwrap().MaxConnections, Some(512));
+
+        let value = serde_json::to_value(&entry).unwrap();
+        assert_eq!(value["Kind"], "service-defaults");
+        assert_eq!(value["MeshGateway"]["Mode"], "local");
+        assert_eq!(value["Expose"]["Paths"][0]["Path"], "/metrics");
+        assert_eq!(
+            value["UpstreamConfig"]["Defaults"]["PassiveHealthCheck"]["MaxFailures"],
+            5
+        );
+    }
+
+    #[test]
+    fn test_mesh_gateway_mode() {
+        assert_eq!(
+            "local".parse::<MeshGatewayMode>(),
+            Ok(MeshGatewayMode::Local)
+        );
+        assert!("locall".parse::<MeshGatewayMode>().is_err());
+        let entry: ProxyDefaults =
+            serde_json::from_str(r#"{"MeshGateway":{"Mode":"locall"}}"#).unwrap();
+        assert!(entry.validate().is_err());
+        let entry: ProxyDefaults =
+            serde_json::from_str(r#"{"MeshGateway":{"Mode":"remote"}}"#).unwrap();
+        assert!(entry.validate().is_ok());
+        let entry: ServiceDefaults = serde_json::from_str(
+            r#"{"UpstreamConfig":{"Overrides":[{"Name":"db","MeshGateway":{"Mode":"locall"}}]}}"#,
+        )
+        .unwrap();
+        assert!(entry.validate().is_err());
+    }
+
+    #[test]
+    fn test_mode_serde() {
+        let modes = vec![
+            (ProxyMode::Default, "\"\""),
+            (ProxyMode::Transparent, "\"transparent\""),
+            (ProxyMode::Direct, "\"direct\""),
+        ];
+        for (mode, json) in modes {
+            assert_eq!(serde_json::to_string(&mode).unwrap(), json);
+            assert_eq!(serde_json::from_str::<ProxyMode>(json).unwrap(), mode);
+        }
+        let modes = vec![
+            (MeshGatewayMode::Default, "\"\""),
+            (MeshGatewayMode::None, "\"none\""),
+            (MeshGatewayMode::Local, "\"local\""),
+            (MeshGatewayMode::Remote, "\"remote\""),
+        ];
+        for (mode, json) in modes {
+            assert_eq!(serde_json::to_string(&mode).unwrap(), json);
+            assert_eq!(serde_json::from_str::<MeshGatewayMode>(json).unwrap(), mode);
+        }
+    }
+}
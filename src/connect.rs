@@ -0,0 +1,64 @@
+use serde_derive::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+
+/// CA_PROVIDER_CONSUL is the built-in Connect CA provider.
+pub const CA_PROVIDER_CONSUL: &str = "consul";
+/// CA_PROVIDER_VAULT signs certificates with Vault's PKI secrets engine.
+pub const CA_PROVIDER_VAULT: &str = "vault";
+/// CA_PROVIDER_AWS_PCA signs certificates with AWS Certificate Manager
+/// Private CA.
+pub const CA_PROVIDER_AWS_PCA: &str = "aws-pca";
+
+/// CAConfig is the configuration for the current Connect CA provider.
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[allow(non_snake_case)]
+pub struct CAConfig {
+    /// Provider is the CA provider, e.g. CA_PROVIDER_VAULT.
+    pub Provider: Option<String>,
+
+    /// Config is the provider specific configuration, e.g. `Address` and
+    /// `RootPKIPath` for Vault.
+    pub Config: Option<HashMap<String, Value>>,
+
+    /// State is read-only data the provider uses to store internal data.
+    pub State: Option<HashMap<String, String>>,
+
+    /// ForceWithoutCrossSigning reconfigures the CA even if the old provider
+    /// can't cross-sign the new root, which breaks the existing connections
+    /// until their certificates are renewed.
+    pub ForceWithoutCrossSigning: Option<bool>,
+
+    pub CreateIndex: Option<u64>,
+    pub ModifyIndex: Option<u64>,
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::connect::{CAConfig, CA_PROVIDER_VAULT};
+
+    #[test]
+    fn test_ca_config_serde() {
+        let json = r#"{
+            "Provider": "vault",
+            "Config": {
+                "Address": "https://vault.service.consul:8200",
+                "Token": "vault-token",
+                "RootPKIPath": "connect-root",
+                "IntermediatePKIPath": "connect-intermediate",
+                "LeafCertTTL": "72h",
+                "PrivateKeyBits": 2048
+            },
+            "ForceWithoutCrossSigning": false,
+            "CreateIndex": 5,
+            "ModifyIndex": 9
+        }"#;
+        let config: CAConfig = serde_json::from_str(json).unwrap();
+        assert_eq!(config.Provider.as_deref(), Some(CA_PROVIDER_VAULT));
+        let provider = config.Config.as_ref().unwrap();
+        assert_eq!(provider["RootPKIPath"], "connect-root");
+        assert_eq!(provider["PrivateKeyBits"], 2048);
+        let out: CAConfig = serde_json::from_value(serde_json::to_value(&config).unwrap()).unwrap();
+        assert_eq!(out, config);
+    }
+}
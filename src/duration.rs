@@ -0,0 +1,219 @@
+use serde::de::{self, Deserialize, Deserializer, Visitor};
+use serde::ser::{Serialize, Serializer};
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// GoDuration is a duration written as a Go duration string, e.g. `10s` or
+/// `1h30m`, as Consul expects in checks, config entries and the Config. It is
+/// formatted with format_go_duration and parsed with parse_go_duration, and
+/// also deserializes from a number of nanoseconds.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct GoDuration(pub Duration);
+
+impl GoDuration {
+    pub fn as_duration(&self) -> Duration {
+        self.0
+    }
+}
+
+impl From<Duration> for GoDuration {
+    fn from(duration: Duration) -> Self {
+        GoDuration(duration)
+    }
+}
+
+impl From<GoDuration> for Duration {
+    fn from(duration: GoDuration) -> Self {
+        duration.0
+    }
+}
+
+impl FromStr for GoDuration {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        parse_go_duration(s).map(GoDuration)
+    }
+}
+
+impl fmt::Display for GoDuration {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&format_go_duration(self.0))
+    }
+}
+
+impl Serialize for GoDuration {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+impl<'de> Deserialize<'de> for GoDuration {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct GoDurationVisitor;
+
+        impl<'de> Visitor<'de> for GoDurationVisitor {
+            type Value = GoDuration;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a Go duration string or a number of nanoseconds")
+            }
+
+            fn visit_str<E: de::Error>(self, value: &str) -> Result<GoDuration, E> {
+                value.parse().map_err(E::custom)
+            }
+
+            fn visit_u64<E: de::Error>(self, value: u64) -> Result<GoDuration, E> {
+                Ok(GoDuration(Duration::from_nanos(value)))
+            }
+
+            fn visit_i64<E: de::Error>(self, value: i64) -> Result<GoDuration, E> {
+                if value < 0 {
+                    return Err(E::custom(format!("negative duration {}", value)));
+                }
+                self.visit_u64(value as u64)
+            }
+        }
+
+        deserializer.deserialize_any(GoDurationVisitor)
+    }
+}
+
+/// parse_go_duration parses a Go duration string such as `90s`, `1m30s` or
+/// `1.5h`, as accepted by Consul. A unit is required, except for `0`.
+pub fn parse_go_duration(s: &str) -> Result<Duration, String> {
+    let invalid = || format!("invalid duration {:?}", s);
+    let mut rest = s.strip_prefix('+').unwrap_or(s);
+    if rest == "0" {
+        return Ok(Duration::from_secs(0));
+    }
+    if rest.is_empty() {
+        return Err(invalid());
+    }
+    let mut nanos: f64 = 0.0;
+    while !rest.is_empty() {
+        let number_len = rest
+            .find(|c: char| !c.is_ascii_digit() && c != '.')
+            .ok_or_else(invalid)?;
+        let number: f64 = rest[..number_len].parse().map_err(|_| invalid())?;
+        rest = &rest[number_len..];
+        let unit_len = rest
+            .find(|c: char| c.is_ascii_digit() || c == '.')
+            .unwrap_or(rest.len());
+        let unit = match &rest[..unit_len] {
+            "ns" => 1.0,
+            "us" | "µs" | "μs" => 1e3,
+            "ms" => 1e6,
+            "s" => 1e9,
+            "m" => 60e9,
+            "h" => 3600e9,
+            _ => return Err(invalid()),
+        };
+        rest = &rest[unit_len..];
+        nanos += number * unit;
+    }
+    Ok(Duration::from_nanos(nanos.round() as u64))
+}
+
+/// format_go_duration formats a duration the way Go's `time.Duration.String`
+/// does, e.g. `1h30m0s`, `2m3.5s` or `1.5ms`.
+pub fn format_go_duration(duration: Duration) -> String {
+    let nanos = duration.as_nanos();
+    if nanos == 0 {
+        return String::from("0s");
+    }
+    if nanos < 1_000 {
+        return format!("{}ns", nanos);
+    }
+    if nanos < 1_000_000 {
+        return format!("{}µs", format_fraction(nanos, 1_000));
+    }
+    if nanos < 1_000_000_000 {
+        return format!("{}ms", format_fraction(nanos, 1_000_000));
+    }
+    let mut out = String::new();
+    let hours = nanos / 3_600_000_000_000;
+    let minutes = nanos / 60_000_000_000 % 60;
+    if hours > 0 {
+        out.push_str(&format!("{}h", hours));
+    }
+    if hours > 0 || minutes > 0 {
+        out.push_str(&format!("{}m", minutes));
+    }
+    let seconds = format_fraction(nanos % 60_000_000_000, 1_000_000_000);
+    out.push_str(&format!("{}s", seconds));
+    out
+}
+
+/// format_fraction formats `value / scale` as a decimal without trailing
+/// zeros, where `scale` is a power of ten.
+fn format_fraction(value: u128, scale: u128) -> String {
+    let fraction = value % scale;
+    if fraction == 0 {
+        return (value / scale).to_string();
+    }
+    let width = scale.to_string().len() - 1;
+    let fraction = format!("{:0width$}", fraction, width = width);
+    format!("{}.{}", value / scale, fraction.trim_end_matches('0'))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::duration::{format_go_duration, parse_go_duration, GoDuration};
+    use std::time::Duration;
+
+    #[test]
+    fn test_go_duration() {
+        assert_eq!(parse_go_duration("90s"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_go_duration("1m30s"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_go_duration("1.5h"), Ok(Duration::from_secs(5400)));
+        assert_eq!(parse_go_duration("250ms"), Ok(Duration::from_millis(250)));
+        assert_eq!(parse_go_duration("0"), Ok(Duration::from_secs(0)));
+        assert!(parse_go_duration("90").is_err());
+        assert!(parse_go_duration("").is_err());
+        assert!(parse_go_duration("-5s").is_err());
+        assert!(parse_go_duration("5 s").is_err());
+        assert!(parse_go_duration("5d").is_err());
+        assert_eq!(format_go_duration(Duration::from_secs(90)), "1m30s");
+        assert_eq!(format_go_duration(Duration::from_secs(45)), "45s");
+        assert_eq!(format_go_duration(Duration::from_millis(1500)), "1.5s");
+        assert_eq!(format_go_duration(Duration::from_millis(250)), "250ms");
+        assert_eq!(format_go_duration(Duration::from_nanos(10)), "10ns");
+        assert_eq!(
+            format_go_duration(Duration::from_nanos(3_723_000_000_001)),
+            "1h2m3.000000001s"
+        );
+    }
+
+    #[test]
+    fn test_go_duration_compound() {
+        let cases = vec![
+            ("1h30m", 5_400_000_000_000, "1h30m0s"),
+            ("1h0m0s", 3_600_000_000_000, "1h0m0s"),
+            ("2m3.5s", 123_500_000_000, "2m3.5s"),
+            ("1s500ms", 1_500_000_000, "1.5s"),
+            ("1ms250us", 1_250_000, "1.25ms"),
+            ("1.5us", 1_500, "1.5µs"),
+            ("3µs7ns", 3_007, "3.007µs"),
+            ("+10s", 10_000_000_000, "10s"),
+            ("0", 0, "0s"),
+        ];
+        for (s, nanos, formatted) in cases.into_iter() {
+            let duration: GoDuration = s.parse().unwrap();
+            assert_eq!(duration.as_duration(), Duration::from_nanos(nanos), "{}", s);
+            assert_eq!(duration.to_string(), formatted, "{}", s);
+            assert_eq!(formatted.parse::<GoDuration>().unwrap(), duration);
+        }
+        assert!("1h30".parse::<GoDuration>().is_err());
+        assert!("h".parse::<GoDuration>().is_err());
+        assert!("1..5s".parse::<GoDuration>().is_err());
+
+        let duration: GoDuration = serde_json::from_str(r#""1m30s""#).unwrap();
+        assert_eq!(serde_json::to_string(&duration).unwrap(), r#""1m30s""#);
+        let duration: GoDuration = serde_json::from_str("10000000000").unwrap();
+        assert_eq!(duration, GoDuration(Duration::from_secs(10)));
+        assert!(serde_json::from_str::<GoDuration>(r#""90""#).is_err());
+        assert!(serde_json::from_str::<GoDuration>("-1").is_err());
+    }
+}
@@ -0,0 +1,159 @@
+use std::fmt;
+use surf::StatusCode;
+
+/// ConsulError is the error carried by a non-2xx response from Consul.
+/// It is wrapped in the returned `surf::Error` and can be recovered with
+/// `err.downcast_ref::<ConsulError>()`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConsulError {
+    /// PermissionDenied is returned when the ACL token lacks the privileges
+    /// for the request, or is unknown to the cluster.
+    PermissionDenied(String),
+
+    /// ServiceNotFound is returned when the requested service isn't known.
+    ServiceNotFound(String),
+
+    /// NoLeader is returned when the cluster has no elected leader.
+    NoLeader,
+
+    /// CircuitOpen is returned by `resolve` without querying Consul while the
+    /// service's circuit breaker is open.
+    CircuitOpen(String),
+
+    /// RateLimited is returned when Consul keeps answering 429 after the
+    /// retries, or asks for a delay too long to wait out. RetryAfter is the
+    /// delay in seconds it asked for, if any.
+    RateLimited { retry_after: Option<u64> },
+
+    /// Unreachable is returned by a registration with VerifyBeforeRegister
+    /// when the service couldn't be reached at the given address.
+    Unreachable(String),
+
+    /// Timeout is returned when the deadline set with `with_deadline` passes
+    /// before the request completes.
+    Timeout,
+
+    /// ResponseTooLarge is returned when a response body exceeds the
+    /// configured `max_response_size`, in bytes.
+    ResponseTooLarge { limit: u64 },
+
+    /// Conflict is returned by a registration with RejectNameConflict when the
+    /// service name is already registered under the given, different ID.
+    Conflict(String),
+
+    /// ImportFailed is returned by `kv_import` with the keys it couldn't write.
+    ImportFailed(Vec<String>),
+
+    /// InvalidResponse is returned when a successful response doesn't carry
+    /// the expected JSON, e.g. the HTML page of a misconfigured proxy. Body is
+    /// the start of the response body.
+    InvalidResponse {
+        status: u16,
+        content_type: Option<String>,
+        body: String,
+        message: String,
+    },
+
+    /// Unexpected is any other error response.
+    Unexpected { status: u16, message: String },
+}
+
+impl ConsulError {
+    /// from_response maps a Consul error response body to a ConsulError.
+    pub fn from_response(status: StatusCode, body: &str) -> Self {
+        let message = body.trim().to_string();
+        let lower = message.to_lowercase();
+        if lower.contains("permission denied") || lower.contains("acl not found") {
+            ConsulError::PermissionDenied(message)
+        } else if lower.contains("no cluster leader") {
+            ConsulError::NoLeader
+        } else if lower.contains("unknown service") || lower.contains("service not found") {
+            ConsulError::ServiceNotFound(message)
+        } else {
+            ConsulError::Unexpected {
+                status: status as u16,
+                message,
+            }
+        }
+    }
+}
+
+impl fmt::Display for ConsulError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConsulError::PermissionDenied(message) => write!(f, "permission denied: {}", message),
+            ConsulError::ServiceNotFound(message) => write!(f, "service not found: {}", message),
+            ConsulError::NoLeader => write!(f, "no cluster leader"),
+            ConsulError::CircuitOpen(service) => write!(f, "circuit open for {}", service),
+            ConsulError::RateLimited {
+                retry_after: Some(retry_after),
+            } => write!(f, "rate limited, retry after {}s", retry_after),
+            ConsulError::RateLimited { retry_after: None } => write!(f, "rate limited"),
+            ConsulError::Unreachable(target) => write!(f, "service unreachable at {}", target),
+            ConsulError::Timeout => write!(f, "deadline exceeded"),
+            ConsulError::ResponseTooLarge { limit } => {
+                write!(f, "response body larger than {} bytes", limit)
+            }
+            ConsulError::Conflict(id) => {
+                write!(f, "service name already registered with ID {}", id)
+            }
+            ConsulError::ImportFailed(keys) => {
+                write!(f, "kv import failed for {}", keys.join(", "))
+            }
+            ConsulError::InvalidResponse {
+                status,
+                content_type,
+                body,
+                message,
+            } => {
+                write!(
+                    f,
+                    "invalid JSON response {} (content-type {}): {}",
+                    status,
+                    content_type.as_deref().unwrap_or("none"),
+                    message
+                )?;
+                if body.is_empty() {
+                    write!(f, ", empty body")
+                } else {
+                    write!(f, ", body: {}", body)
+                }
+            }
+            ConsulError::Unexpected { status, message } => {
+                write!(f, "unexpected response {}: {}", status, message)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ConsulError {}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::ConsulError;
+    use surf::StatusCode;
+
+    #[test]
+    fn test_from_response() {
+        let err = ConsulError::from_response(StatusCode::Forbidden, "rpc error: Permission denied");
+        assert_eq!(
+            err,
+            ConsulError::PermissionDenied(String::from("rpc error: Permission denied"))
+        );
+        let err = ConsulError::from_response(StatusCode::Forbidden, "ACL not found");
+        assert!(matches!(err, ConsulError::PermissionDenied(_)));
+        let err = ConsulError::from_response(StatusCode::NotFound, "Unknown service ID \"web\"");
+        assert!(matches!(err, ConsulError::ServiceNotFound(_)));
+        let err = ConsulError::from_response(StatusCode::InternalServerError, "No cluster leader");
+        assert_eq!(err, ConsulError::NoLeader);
+        let err =
+            ConsulError::from_response(StatusCode::BadRequest, "Invalid check: TTL must be > 0\n");
+        assert_eq!(
+            err,
+            ConsulError::Unexpected {
+                status: 400,
+                message: String::from("Invalid check: TTL must be > 0"),
+            }
+        );
+    }
+}
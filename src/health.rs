@@ -1,11 +1,12 @@
 #[allow(dead_code)]
 use super::agent;
 use super::catalog;
+use super::duration::GoDuration;
 use async_std::sync::Arc;
 use lazy_static::lazy_static;
 use serde_derive::{Deserialize, Serialize};
 use std::collections::{HashMap, LinkedList};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 lazy_static!(
     /// HealthAny is special, and is used as a wild card, not as a specific state.
@@ -31,6 +32,12 @@ lazy_static!(
     };
 );
 
+/// NODE_MAINT is the special key set by a node in maintenance mode.
+pub const NODE_MAINT: &str = "_node_maintenance";
+
+/// SERVICE_MAINT_PREFIX is the prefix for a service in maintenance mode.
+pub const SERVICE_MAINT_PREFIX: &str = "_service_maintenance:";
+
 lazy_static!(
     #[derive(Debug)]
     static ref SERVICE_HEALTH:String = {
@@ -67,7 +74,7 @@ pub struct HealthCheck {
     pub ModifyIndex: Option<usize>,
 }
 
-type ReadableDuration = Duration;
+type ReadableDuration = GoDuration;
 
 /// HealthCheckDefinition is used to store the details about a health check's execution.
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
@@ -93,7 +100,62 @@ pub struct HealthCheckDefinition {
 /// HealthChecks is a collection of HealthCheck structs.
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
 #[allow(non_snake_case)]
-pub struct HealthChecks(Vec<HealthCheck>);
+pub struct HealthChecks(pub Vec<HealthCheck>);
+
+impl HealthChecks {
+    /// failing returns the checks that aren't passing.
+    pub fn failing(&self) -> Vec<&HealthCheck> {
+        self.0
+            .iter()
+            .filter(|check| check.Status.as_deref() != Some(HEALTH_PASSING.as_str()))
+            .collect()
+    }
+
+    /// aggregated_status returns the "best" status for the list of health checks.
+    /// Because a given entry may have many service and node-level health checks
+    /// attached, this function determines the best representative of the status
+    /// as single string using the following heuristic:
+    ///
+    ///  maintenance > critical > warning > passing
+    pub fn aggregated_status(&self) -> String {
+        let (mut passing, mut warning, mut critical, mut maintenance) =
+            (false, false, false, false);
+        for check in self.0.iter() {
+            let id = check.CheckID.as_deref().unwrap_or_default();
+            if id == NODE_MAINT || id.starts_with(SERVICE_MAINT_PREFIX) {
+                maintenance = true;
+                continue;
+            }
+            match check.Status.as_deref() {
+                Some("passing") => passing = true,
+                Some("warning") => warning = true,
+                Some("critical") => critical = true,
+                _ => return String::new(),
+            }
+        }
+        if maintenance {
+            HEALTH_MAINT.to_string()
+        } else if critical {
+            HEALTH_CRITICAL.to_string()
+        } else if warning {
+            HEALTH_WARNING.to_string()
+        } else if passing || self.0.is_empty() {
+            HEALTH_PASSING.to_string()
+        } else {
+            String::new()
+        }
+    }
+}
+
+/// CatalogServiceChecksInfo is a catalog service instance with its checks and
+/// their aggregated status.
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[allow(non_snake_case)]
+pub struct CatalogServiceChecksInfo {
+    pub AggregatedStatus: String,
+    pub Service: catalog::CatalogService,
+    pub Checks: HealthChecks,
+}
 
 /// ServiceEntry is used for the health service endpoint
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
@@ -104,9 +166,167 @@ pub struct ServiceEntry {
     pub Checks: Option<HealthChecks>,
 }
 
+impl ServiceEntry {
+    /// passing_or_warning reports whether the checks of the entry are all
+    /// passing or warning, with none critical or in maintenance.
+    pub fn passing_or_warning(&self) -> bool {
+        let status = self
+            .Checks
+            .as_ref()
+            .map(HealthChecks::aggregated_status)
+            .unwrap_or_else(|| HEALTH_PASSING.to_string());
+        status == HEALTH_PASSING.as_str() || status == HEALTH_WARNING.as_str()
+    }
+}
+
 #[derive(Default, Debug, Serialize, Deserialize, Clone)]
 pub struct ServiceAddress {
     pub index: u64,
     pub address: Vec<String>,
     pub address_link: LinkedList<String>,
+    /// LastUpdate is when the addresses were last fetched from Consul.
+    #[serde(skip)]
+    pub last_update: Option<Instant>,
+}
+
+/// ResolvedAddress is the result of resolving a service. Stale is set when
+/// Consul couldn't be reached and the addresses come from the cache.
+#[derive(Default, Debug, Serialize, Deserialize, Clone)]
+pub struct ResolvedAddress {
+    pub address: Vec<String>,
+    pub stale: bool,
+}
+
+/// SrvRecord is a healthy instance of a service in the shape of a DNS SRV
+/// record. Like Consul DNS, every record has priority 1 and the weight of a
+/// passing instance.
+#[derive(Default, Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct SrvRecord {
+    pub target: String,
+    pub port: usize,
+    pub weight: usize,
+    pub priority: usize,
+}
+
+impl SrvRecord {
+    /// from_entry builds the record of an instance, targeting the service
+    /// address or else the node address. None is returned without a port.
+    pub fn from_entry(entry: &ServiceEntry) -> Option<SrvRecord> {
+        let service = entry.Service.as_ref()?;
+        let port = service.Port?;
+        let target = service
+            .Address
+            .clone()
+            .filter(|address| !address.is_empty())
+            .or_else(|| entry.Node.as_ref().and_then(|node| node.Address.clone()))?;
+        let weight = service
+            .Weights
+            .as_ref()
+            .and_then(|weights| weights.Passing)
+            .unwrap_or(1);
+        Some(SrvRecord {
+            target,
+            port,
+            weight,
+            priority: 1,
+        })
+    }
+}
+
+string_enum! {
+    /// HealthStatus is the aggregated health of a service in a datacenter.
+    HealthStatus, default = Critical {
+        Passing => "passing",
+        Warning => "warning",
+        Critical => "critical",
+        Maintenance => "maintenance",
+        /// Unreachable is the status of a datacenter that couldn't be queried.
+        Unreachable => "unreachable",
+    }
+}
+
+impl HealthStatus {
+    /// from_entries aggregates the checks of every instance, the worst one
+    /// winning. A service without instances is critical, and a check status
+    /// that isn't known is returned in HealthStatus::Unknown.
+    pub fn from_entries(entries: &[ServiceEntry]) -> HealthStatus {
+        if entries.is_empty() {
+            return HealthStatus::Critical;
+        }
+        let checks = entries
+            .iter()
+            .flat_map(|entry| entry.Checks.iter().flat_map(|checks| checks.0.iter()))
+            .cloned()
+            .collect();
+        let checks = HealthChecks(checks);
+        let status = checks.aggregated_status();
+        if status.is_empty() {
+            // aggregated_status gives up on the first status it doesn't know.
+            let known = [
+                HEALTH_PASSING.as_str(),
+                HEALTH_WARNING.as_str(),
+                HEALTH_CRITICAL.as_str(),
+            ];
+            let unknown = checks
+                .0
+                .iter()
+                .filter_map(|check| check.Status.as_deref())
+                .find(|status| !known.contains(status))
+                .unwrap_or_default();
+            return HealthStatus::Unknown(unknown.to_string());
+        }
+        status.parse().unwrap_or(HealthStatus::Unknown(status))
+    }
+}
+
+/// InstanceHealth is the health of an instance of a service, with the output
+/// of each of its failing checks.
+#[derive(Default, Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct InstanceHealth {
+    pub node: Option<String>,
+    /// Address is the `host:port` of the instance.
+    pub address: String,
+    /// Status is the aggregated status of the checks.
+    pub status: String,
+    pub failing: Vec<FailingCheck>,
+}
+
+/// FailingCheck is a check that isn't passing, with its last output.
+#[derive(Default, Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct FailingCheck {
+    pub name: String,
+    pub status: String,
+    pub output: String,
+}
+
+impl InstanceHealth {
+    pub fn from_entry(entry: &ServiceEntry) -> InstanceHealth {
+        let service = entry.Service.as_ref();
+        let node = entry.Node.as_ref();
+        let host = service
+            .and_then(|service| service.Address.clone())
+            .filter(|address| !address.is_empty())
+            .or_else(|| node.and_then(|node| node.Address.clone()))
+            .unwrap_or_default();
+        let address = match service.and_then(|service| service.Port) {
+            Some(port) => format!("{}:{}", host, port),
+            None => host,
+        };
+        let checks = entry.Checks.clone().unwrap_or_default();
+        let failing = checks
+            .failing()
+            .into_iter()
+            .map(|check| FailingCheck {
+                name: check.Name.clone().unwrap_or_default(),
+                status: check.Status.clone().unwrap_or_default(),
+                output: check.Output.clone().unwrap_or_default(),
+            })
+            .collect();
+        InstanceHealth {
+            node: node.and_then(|node| node.Node.clone()),
+            address,
+            status: checks.aggregated_status(),
+            failing,
+        }
+    }
 }
@@ -0,0 +1,80 @@
+use serde_derive::{Deserialize, Serialize};
+
+/// KVPair is used to represent a single K/V entry
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[allow(non_snake_case)]
+pub struct KVPair {
+    /// Key is the name of the key. It is also part of the URL path when accessed
+    /// via the API.
+    pub Key: String,
+
+    /// CreateIndex holds the index corresponding the creation of this KVPair.
+    pub CreateIndex: Option<u64>,
+
+    /// ModifyIndex is used for the Check-And-Set operations and can also be fed
+    /// back into the WaitIndex of the QueryOptions in order to perform blocking
+    /// queries.
+    pub ModifyIndex: Option<u64>,
+
+    /// LockIndex holds the index corresponding to a lock on this key, if any.
+    pub LockIndex: Option<u64>,
+
+    /// Flags are any user-defined flags on the key.
+    pub Flags: Option<u64>,
+
+    /// Value is the base64 encoded value stored at the key.
+    pub Value: Option<String>,
+
+    /// Session is a string representing the ID of the session.
+    pub Session: Option<String>,
+
+    /// Namespace is the namespace the KVPair is associated with.
+    pub Namespace: Option<String>,
+}
+
+/// KVExportEntry is a K/V entry in the format of `consul kv export` and
+/// `consul kv import`.
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct KVExportEntry {
+    pub key: String,
+    pub flags: u64,
+    /// Value is base64 encoded.
+    pub value: String,
+}
+
+impl From<&KVPair> for KVExportEntry {
+    fn from(pair: &KVPair) -> Self {
+        KVExportEntry {
+            key: pair.Key.to_string(),
+            flags: pair.Flags.unwrap_or(0),
+            value: pair.Value.clone().unwrap_or_default(),
+        }
+    }
+}
+
+impl From<&KVExportEntry> for KVPair {
+    fn from(entry: &KVExportEntry) -> Self {
+        KVPair {
+            Key: entry.key.to_string(),
+            Flags: Some(entry.flags),
+            Value: Some(entry.value.to_string()),
+            ..KVPair::default()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::kv::{KVExportEntry, KVPair};
+
+    #[test]
+    fn test_export_entry_serde() {
+        let cli = r#"[{"key":"app/db","flags":42,"value":"aG9zdA=="}]"#;
+        let entries: Vec<KVExportEntry> = serde_json::from_str(cli).unwrap();
+        let pairs: Vec<KVPair> = entries.iter().map(KVPair::from).collect();
+        assert_eq!(pairs[0].Key, "app/db");
+        assert_eq!(pairs[0].Flags, Some(42));
+        let entries: Vec<KVExportEntry> = pairs.iter().map(KVExportEntry::from).collect();
+        assert_eq!(serde_json::to_string(&entries).unwrap(), cli);
+    }
+}
@@ -7,9 +7,88 @@ mod tests {
     }
 }
 
+/// string_enum defines an enum that serializes to the given Consul strings.
+/// Unknown strings deserialize into the `Unknown` variant so values from newer
+/// Consul versions don't break decoding, while `FromStr` rejects them.
+macro_rules! string_enum {
+    (
+        $(#[$meta:meta])*
+        $name:ident, default = $default:ident {
+            $($(#[$variant_meta:meta])* $variant:ident => $value:literal,)+
+        }
+    ) => {
+        $(#[$meta])*
+        #[derive(Debug, Clone, PartialEq, Eq, Hash)]
+        pub enum $name {
+            $($(#[$variant_meta])* $variant,)+
+            /// Unknown is a value not known to this crate.
+            Unknown(String),
+        }
+
+        impl $name {
+            pub fn as_str(&self) -> &str {
+                match self {
+                    $($name::$variant => $value,)+
+                    $name::Unknown(value) => value,
+                }
+            }
+
+            /// is_valid reports whether the value is one known to this crate.
+            pub fn is_valid(&self) -> bool {
+                !matches!(self, $name::Unknown(_))
+            }
+        }
+
+        impl Default for $name {
+            fn default() -> Self {
+                $name::$default
+            }
+        }
+
+        impl std::fmt::Display for $name {
+            fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                f.write_str(self.as_str())
+            }
+        }
+
+        impl std::str::FromStr for $name {
+            type Err = String;
+
+            fn from_str(value: &str) -> Result<Self, Self::Err> {
+                match value {
+                    $($value => Ok($name::$variant),)+
+                    _ => Err(format!("invalid {} {:?}", stringify!($name), value)),
+                }
+            }
+        }
+
+        impl serde::Serialize for $name {
+            fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                serializer.serialize_str(self.as_str())
+            }
+        }
+
+        impl<'de> serde::Deserialize<'de> for $name {
+            fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+                let value = <String as serde::Deserialize>::deserialize(deserializer)?;
+                Ok(value.parse().unwrap_or($name::Unknown(value)))
+            }
+        }
+    };
+}
+
 pub mod agent;
 pub mod api;
 pub mod catalog;
+pub mod circuit_breaker;
 pub mod config_entry;
+pub mod connect;
+pub mod duration;
+pub mod error;
 pub mod health;
+pub mod kv;
+pub mod operator;
+pub mod peering;
+pub mod prepared_query;
+pub mod session;
 pub mod watch;
@@ -0,0 +1,60 @@
+use serde_derive::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// KeyringRequest is used for performing Keyring operations
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[allow(non_snake_case)]
+pub struct KeyringRequest {
+    pub Key: String,
+}
+
+/// KeyringResponse is returned when listing the gossip encryption keys
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[allow(non_snake_case)]
+pub struct KeyringResponse {
+    /// Whether this response is for a WAN ring
+    pub WAN: Option<bool>,
+
+    /// The datacenter name this request corresponds to
+    pub Datacenter: Option<String>,
+
+    /// Segment has the network segment this request corresponds to.
+    pub Segment: Option<String>,
+
+    /// Messages has information or errors from serf
+    pub Messages: Option<HashMap<String, String>>,
+
+    /// A map of the encryption keys to the number of nodes they're installed on
+    pub Keys: Option<HashMap<String, usize>>,
+
+    /// A map of the primary encryption keys to the number of nodes they're
+    /// the primary key on
+    pub PrimaryKeys: Option<HashMap<String, usize>>,
+
+    /// The total number of nodes in this ring
+    pub NumNodes: Option<usize>,
+}
+
+/// Usage is the cluster-wide resource usage returned by the operator usage
+/// endpoint.
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[allow(non_snake_case)]
+pub struct Usage {
+    /// Usage is the usage of each datacenter, keyed by its name.
+    pub Usage: Option<HashMap<String, ServiceUsage>>,
+}
+
+/// ServiceUsage is the service and node counts of a datacenter.
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[allow(non_snake_case)]
+pub struct ServiceUsage {
+    pub Services: Option<usize>,
+    pub ServiceInstances: Option<usize>,
+    /// ConnectServiceInstances counts the Connect instances by kind,
+    /// e.g. `connect-proxy`.
+    pub ConnectServiceInstances: Option<HashMap<String, usize>>,
+    /// BillableServiceInstances is the instances that count toward
+    /// Enterprise licensing, which excludes Connect proxies.
+    pub BillableServiceInstances: Option<usize>,
+    pub Nodes: Option<usize>,
+}
@@ -0,0 +1,94 @@
+use serde_derive::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+string_enum! {
+    /// PeeringState is the state of a cluster peering.
+    PeeringState, default = Undefined {
+        /// PEERING_STATE_UNDEFINED is the zero value of the state.
+        Undefined => "UNDEFINED",
+        /// PEERING_STATE_PENDING means the peering was created by generating a
+        /// token, and the other cluster hasn't established it yet.
+        Pending => "PENDING",
+        /// PEERING_STATE_ESTABLISHING means the peering was created with a token
+        /// and is connecting to the other cluster.
+        Establishing => "ESTABLISHING",
+        /// PEERING_STATE_ACTIVE means the peering is connected and replicating.
+        Active => "ACTIVE",
+        /// PEERING_STATE_FAILING means the peering connection is failing.
+        Failing => "FAILING",
+        /// PEERING_STATE_DELETING means the peering is being deleted.
+        Deleting => "DELETING",
+        /// PEERING_STATE_TERMINATED means the other cluster deleted the peering.
+        Terminated => "TERMINATED",
+    }
+}
+
+pub const PEERING_STATE_UNDEFINED: PeeringState = PeeringState::Undefined;
+pub const PEERING_STATE_PENDING: PeeringState = PeeringState::Pending;
+pub const PEERING_STATE_ESTABLISHING: PeeringState = PeeringState::Establishing;
+pub const PEERING_STATE_ACTIVE: PeeringState = PeeringState::Active;
+pub const PEERING_STATE_FAILING: PeeringState = PeeringState::Failing;
+pub const PEERING_STATE_DELETING: PeeringState = PeeringState::Deleting;
+pub const PEERING_STATE_TERMINATED: PeeringState = PeeringState::Terminated;
+
+/// Peering is a peering between the local cluster and another one.
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[allow(non_snake_case)]
+pub struct Peering {
+    /// ID is a datacenter-scoped UUID for the peering.
+    pub ID: Option<String>,
+    /// Name is the local alias for the peering relationship.
+    pub Name: Option<String>,
+    /// Partition is the local partition connecting to the peer.
+    pub Partition: Option<String>,
+    /// Meta is a mapping of some string value to any other string value.
+    pub Meta: Option<HashMap<String, String>>,
+    /// State is one of the valid PeeringState values to represent the status
+    /// of the peering relationship.
+    pub State: Option<PeeringState>,
+    /// PeerID is the ID that our peer assigned to this peering.
+    pub PeerID: Option<String>,
+    /// PeerCAPems contains all the CA certificates for the remote peer.
+    pub PeerCAPems: Option<Vec<String>>,
+    /// PeerServerName is the name of the remote server as it relates to TLS.
+    pub PeerServerName: Option<String>,
+    /// PeerServerAddresses contains all the connection addresses for the
+    /// remote peer.
+    pub PeerServerAddresses: Option<Vec<String>>,
+    pub CreateIndex: Option<u64>,
+    pub ModifyIndex: Option<u64>,
+}
+
+/// PeeringGenerateTokenRequest asks for a token the other cluster uses to
+/// establish the peering.
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[allow(non_snake_case)]
+pub struct PeeringGenerateTokenRequest {
+    /// PeerName is the name of the remote peer.
+    pub PeerName: String,
+    pub Partition: Option<String>,
+    pub Meta: Option<HashMap<String, String>>,
+    /// ServerExternalAddresses overrides the addresses of the local servers
+    /// handed to the peer, e.g. behind a load balancer.
+    pub ServerExternalAddresses: Option<Vec<String>>,
+}
+
+/// PeeringGenerateTokenResponse carries the base64 encoded peering token.
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[allow(non_snake_case)]
+pub struct PeeringGenerateTokenResponse {
+    pub PeeringToken: String,
+}
+
+/// PeeringEstablishRequest establishes a peering with the token generated
+/// by the other cluster.
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[allow(non_snake_case)]
+pub struct PeeringEstablishRequest {
+    /// PeerName is the local name of the peering.
+    pub PeerName: String,
+    /// PeeringToken is the token generated by the other cluster.
+    pub PeeringToken: String,
+    pub Partition: Option<String>,
+    pub Meta: Option<HashMap<String, String>>,
+}
@@ -0,0 +1,35 @@
+use super::duration::GoDuration;
+use super::health::ServiceEntry;
+use serde_derive::{Deserialize, Serialize};
+
+/// QueryDNSOptions controls settings when query results are served over DNS.
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[allow(non_snake_case)]
+pub struct QueryDNSOptions {
+    /// TTL is the time to live for the served DNS results.
+    pub TTL: Option<GoDuration>,
+}
+
+/// PreparedQueryExecuteResponse has the results of executing a query.
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[allow(non_snake_case)]
+pub struct PreparedQueryExecuteResponse {
+    /// Service is the service that was queried.
+    pub Service: Option<String>,
+
+    /// Namespace of the service that was queried
+    pub Namespace: Option<String>,
+
+    /// Nodes has the nodes that were output by the query.
+    pub Nodes: Option<Vec<ServiceEntry>>,
+
+    /// DNS has the options for serving these results over DNS.
+    pub DNS: Option<QueryDNSOptions>,
+
+    /// Datacenter is the datacenter that these results came from.
+    pub Datacenter: Option<String>,
+
+    /// Failovers is a count of how many times we had to query a remote
+    /// datacenter.
+    pub Failovers: Option<usize>,
+}
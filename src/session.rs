@@ -0,0 +1,50 @@
+use super::duration::GoDuration;
+use serde_derive::{Deserialize, Serialize};
+
+/// SESSION_BEHAVIOR_RELEASE releases the locks of the session when it is
+/// invalidated.
+pub const SESSION_BEHAVIOR_RELEASE: &str = "release";
+/// SESSION_BEHAVIOR_DELETE deletes the keys locked by the session when it is
+/// invalidated.
+pub const SESSION_BEHAVIOR_DELETE: &str = "delete";
+
+/// SessionEntry represents a session in consul
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[allow(non_snake_case)]
+pub struct SessionEntry {
+    pub CreateIndex: Option<u64>,
+    pub ID: Option<String>,
+    pub Name: Option<String>,
+
+    /// Node is the node the session is anchored to, the agent's node if unset.
+    pub Node: Option<String>,
+
+    /// LockDelay is how long a lock released by the invalidation of the
+    /// session can't be acquired again.
+    pub LockDelay: Option<GoDuration>,
+
+    /// Behavior is SESSION_BEHAVIOR_RELEASE or SESSION_BEHAVIOR_DELETE.
+    pub Behavior: Option<String>,
+    pub TTL: Option<GoDuration>,
+    pub Namespace: Option<String>,
+
+    /// NodeChecks and ServiceChecks are the checks whose failure
+    /// invalidates the session.
+    pub NodeChecks: Option<Vec<String>>,
+    pub ServiceChecks: Option<Vec<ServiceCheck>>,
+}
+
+/// ServiceCheck is a service check a session is bound to.
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[allow(non_snake_case)]
+pub struct ServiceCheck {
+    pub ID: String,
+    pub Namespace: Option<String>,
+}
+
+/// SessionCreateResponse is the response of a session creation.
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[allow(non_snake_case)]
+pub struct SessionCreateResponse {
+    pub ID: String,
+}
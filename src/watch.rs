@@ -1,4 +1,5 @@
 use serde_derive::{Deserialize, Serialize};
+use std::time::SystemTime;
 
 pub const ROUND_ROBIN: &'static str = "round_robin";
 pub const RANDOM: &'static str = "random";
@@ -8,4 +9,23 @@ pub struct WatchService {
     pub service_name: String,
     pub tag: Option<String>,
     pub passing_only: Option<bool>,
+    /// WarningOk keeps the instances with warning checks when passing_only
+    /// is set. Consul's passing filter drops them, so they're filtered here.
+    pub warning_ok: Option<bool>,
+    /// Datacenter overrides the datacenter from the config for this service.
+    pub datacenter: Option<String>,
+    /// Node scopes the instances to the ones on this Consul node.
+    pub node: Option<String>,
+}
+
+/// WatchStatus is the cached state of a watched service.
+#[derive(Default, Debug, Serialize, Deserialize, Clone)]
+pub struct WatchStatus {
+    pub service_name: String,
+    pub tag: Option<String>,
+    /// Index is the Consul index of the cached addresses.
+    pub index: u64,
+    /// LastPoll is when Consul was last polled successfully for the service.
+    pub last_poll: Option<SystemTime>,
+    pub address: Vec<String>,
 }